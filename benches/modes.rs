@@ -0,0 +1,101 @@
+//! Compares hello_nest as a table with hello_nest as a view over parquet.
+//!
+//! Builds both from the same source, then reports the build time, the size of
+//! the database file and the median time of a few typical tool queries:
+//!
+//! ```sh
+//! cargo bench --bench modes
+//! NEST_MCP_BENCH_SOURCE='data/country=*/*.parquet' cargo bench --bench modes
+//! ```
+
+use anyhow::Result;
+use duck::AccessMode;
+use nest_mcp::{
+    duckdb::{DuckDB, DuckDbConfig},
+    source::{self, Source},
+};
+use std::{
+    env, fs,
+    time::{Duration, Instant},
+};
+
+/// Source to build from instead of `hello_nest.parquet`
+const SOURCE_ENV: &str = "NEST_MCP_BENCH_SOURCE";
+const RUNS: usize = 10;
+
+const QUERIES: [(&str, &str); 4] = [
+    ("count", "SELECT COUNT(*) FROM hello_nest"),
+    (
+        "lookup",
+        "SELECT * FROM hello_nest WHERE company_id = (SELECT MIN(company_id) FROM hello_nest)",
+    ),
+    (
+        "search",
+        "SELECT company_name, latest_financials FROM hello_nest \
+         WHERE company_name ILIKE '%ab%' AND county IS NOT NULL \
+         ORDER BY company_name LIMIT 50",
+    ),
+    (
+        "aggregate",
+        "SELECT county, COUNT(*), median(revenue_cagr_3y) FROM hello_nest \
+         GROUP BY county ORDER BY county",
+    ),
+];
+
+fn median(mut times: Vec<Duration>) -> Duration {
+    times.sort();
+    times[times.len() / 2]
+}
+
+async fn bench(mode: &str, source: &Source) -> Result<()> {
+    let dir = env::temp_dir().join("nest_mcp_bench");
+    fs::create_dir_all(&dir)?;
+    let config = DuckDbConfig {
+        db_filename: format!("{}.db", mode),
+        temp_directory: dir,
+        access_mode: AccessMode::ReadWrite,
+        source: source.absolute(),
+        ..Default::default()
+    };
+    let db_path = config.db_path();
+    let _ = fs::remove_file(&db_path);
+    let db = DuckDB::new(config).await?;
+
+    let started = Instant::now();
+    match mode {
+        "view" => db.create_hello_nest_view()?,
+        _ => db.create_hello_nest_table()?,
+    }
+    db.execute("CHECKPOINT")?;
+    println!(
+        "{:<6} build {:>10.2?}  file {:>8} KiB",
+        mode,
+        started.elapsed(),
+        fs::metadata(&db_path)?.len() / 1024
+    );
+
+    for (name, sql) in QUERIES {
+        let times = (0..RUNS)
+            .map(|_| {
+                let started = Instant::now();
+                db.query_all_json(sql).map(|_| started.elapsed())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        println!("{:<6} {:<10} {:>10.2?}", mode, name, median(times));
+    }
+
+    drop(db);
+    fs::remove_file(&db_path)?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let source =
+        Source::parse(&env::var(SOURCE_ENV).unwrap_or_else(|_| source::DEFAULT_SOURCE.into()));
+    println!("Source: {} (median of {} runs)", source.uri(), RUNS);
+    for mode in ["table", "view"] {
+        bench(mode, &source).await?;
+    }
+    Ok(())
+}
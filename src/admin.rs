@@ -0,0 +1,103 @@
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use serde_json::json;
+use std::{sync::Arc, time::UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+use crate::snapshot::Snapshots;
+
+/// Mount `POST /admin/reload`, which swaps in a fresh snapshot of the
+/// database, when an admin token (`auth.admin_token`) is configured.
+///
+/// Usage:
+///   curl -X POST -H "Authorization: Bearer $NEST_MCP_AUTH_ADMIN_TOKEN" http://localhost:8000/admin/reload
+pub fn attach_to_router(
+    router: Router,
+    snapshots: Arc<Snapshots>,
+    token: Option<String>,
+) -> Router {
+    let Some(token) = token.filter(|token| !token.is_empty()) else {
+        return router;
+    };
+    let admin = Router::new()
+        .route("/admin/reload", post(reload))
+        .with_state(AdminState {
+            token: Arc::from(token),
+            snapshots,
+        });
+    router.merge(admin)
+}
+
+#[derive(Clone)]
+struct AdminState {
+    token: Arc<str>,
+    snapshots: Arc<Snapshots>,
+}
+
+/// Whether the request carries `token` as its bearer token. The comparison
+/// takes the same time wherever the given token first differs.
+fn is_authorized(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given.as_bytes().ct_eq(token.as_bytes()).into())
+}
+
+async fn reload(State(state): State<AdminState>, headers: HeaderMap) -> Response {
+    if !is_authorized(&headers, &state.token) {
+        warn!("Admin request blocked: missing or wrong token");
+        let body = Json(json!({ "error": "unauthorized" }));
+        return (StatusCode::UNAUTHORIZED, body).into_response();
+    }
+
+    match state.snapshots.reload().await {
+        Ok(loaded_at) => {
+            let loaded_at = loaded_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            (
+                StatusCode::OK,
+                Json(json!({ "reloaded": true, "loaded_at": loaded_at })),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            let body = Json(json!({ "reloaded": false, "error": format!("{:#}", e) }));
+            (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_authorized_requires_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_authorized(&headers, "secret"));
+
+        headers.insert(header::AUTHORIZATION, "secret".parse().unwrap());
+        assert!(!is_authorized(&headers, "secret"));
+
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(!is_authorized(&headers, "secret"));
+
+        headers.insert(header::AUTHORIZATION, "Bearer secre".parse().unwrap());
+        assert!(!is_authorized(&headers, "secret"));
+
+        headers.insert(header::AUTHORIZATION, "Bearer secrets".parse().unwrap());
+        assert!(!is_authorized(&headers, "secret"));
+
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(is_authorized(&headers, "secret"));
+    }
+}
@@ -16,19 +16,22 @@ use tracing::{info, warn};
 /// - Allow if the `User-Agent` contains "Claude" or "claude.ai".
 /// - Allow if the `Origin` or `Referer` header contains "claude.ai".
 ///
-/// Everything else receives 403 Forbidden.
+/// Everything else receives 403 Forbidden. With `claude_only` off
+/// (`auth.claude_only = false`) every request is allowed.
 ///
 /// Usage:
 ///   let app = Router::new();
-///   let app = auth::attach_to_router(app);
-pub fn attach_to_router(router: Router) -> Router {
+///   let app = auth::attach_to_router(app, true);
+pub fn attach_to_router(router: Router, claude_only: bool) -> Router {
     let well_known = Router::new().route(
         "/.well-known/oauth-protected-resource",
         get(oauth_protected_resource),
     );
-    router
-        .merge(well_known)
-        .layer(middleware::from_fn(claude_only_middleware))
+    let router = router.merge(well_known);
+    if !claude_only {
+        return router;
+    }
+    router.layer(middleware::from_fn(claude_only_middleware))
 }
 
 async fn claude_only_middleware(req: Request, next: Next) -> Response {
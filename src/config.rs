@@ -0,0 +1,552 @@
+//! Layered configuration.
+//!
+//! Settings are read, lowest precedence first, from the defaults below, a TOML
+//! file, `NEST_MCP_*` environment variables and the command line. Every key
+//! has the same name in all three: `server.port` in the file is
+//! `NEST_MCP_SERVER_PORT` in the environment and `--set server.port=8080` on
+//! the command line, where common ones also have their own flag (`--db`,
+//! `serve --port`). `PORT` is honoured as `server.port` for Cloud Run.
+//!
+//! ```toml
+//! [database]
+//! path = "/data/nest_mcp.db"
+//! threads = 4
+//! memory_limit = "2GB"
+//! mode = "view"
+//!
+//! [source]
+//! uri = "s3://nest-data/hello_nest.parquet"
+//! s3_region = "eu-north-1"
+//!
+//! [query]
+//! timeout_secs = 30
+//!
+//! [server]
+//! port = 8080
+//! ```
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    duckdb::DuckDbConfig,
+    source::{self, S3Config, Source},
+};
+
+/// Config file to read when `--config` is not given
+pub const CONFIG_FILE_ENV: &str = "NEST_MCP_CONFIG";
+/// Read from the working directory when neither `--config` nor
+/// [`CONFIG_FILE_ENV`] is given and the file exists
+pub const DEFAULT_CONFIG_FILE: &str = "nest_mcp.toml";
+const ENV_PREFIX: &str = "NEST_MCP_";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub source: SourceConfig,
+    pub query: QueryConfig,
+    pub server: ServerConfig,
+    pub auth: AuthConfig,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessMode {
+    Automatic,
+    #[default]
+    ReadOnly,
+    ReadWrite,
+}
+
+impl From<AccessMode> for duck::AccessMode {
+    fn from(mode: AccessMode) -> Self {
+        match mode {
+            AccessMode::Automatic => duck::AccessMode::Automatic,
+            AccessMode::ReadOnly => duck::AccessMode::ReadOnly,
+            AccessMode::ReadWrite => duck::AccessMode::ReadWrite,
+        }
+    }
+}
+
+/// How `create-db` builds hello_nest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildMode {
+    /// Copy the source into a table of the database file
+    #[default]
+    Table,
+    /// A view reading the source parquet at query time, see
+    /// [`crate::duckdb::DuckDB::create_hello_nest_view`]
+    View,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Database file, relative to `temp_directory`
+    pub path: PathBuf,
+    /// `table` or `view`
+    pub mode: BuildMode,
+    /// `read_only`, `read_write` or `automatic`. Commands that write
+    /// (`create-db`, `update`) always open the file read-write.
+    pub access_mode: AccessMode,
+    /// DuckDB worker threads; DuckDB's default (all cores) when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threads: Option<u32>,
+    /// e.g. "2GB"; DuckDB's default (80% of RAM) when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_limit: Option<String>,
+    /// Directory for the database file and spills; the working directory
+    /// when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temp_directory: Option<PathBuf>,
+    pub max_temp_directory_size: String,
+    pub http_timeout_secs: u64,
+    pub http_keep_alive: bool,
+    pub http_retries: u32,
+    pub s3_uploader_thread_limit: u32,
+    /// Connections the server runs queries on at once; further tool calls wait
+    pub pool_size: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        let duckdb = DuckDbConfig::default();
+        Self {
+            path: PathBuf::from(duckdb.db_filename),
+            mode: BuildMode::Table,
+            access_mode: AccessMode::ReadOnly,
+            threads: None,
+            memory_limit: None,
+            temp_directory: None,
+            max_temp_directory_size: duckdb.max_temp_directory_size,
+            http_timeout_secs: duckdb.http_timeout.as_secs(),
+            http_keep_alive: duckdb.http_keep_alive,
+            http_retries: duckdb.http_retries,
+            s3_uploader_thread_limit: duckdb.s3_uploader_thread_limit,
+            pool_size: 16,
+        }
+    }
+}
+
+/// Where `create-db` reads the companies from. S3 credentials are only used,
+/// and the aws extension only loaded, for an `s3://` uri or delta.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceConfig {
+    /// Local path, `s3://bucket/key` or `http(s)://` URL of the parquet file
+    pub uri: String,
+    /// e.g. "localhost:9000" for MinIO; AWS when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s3_endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s3_region: Option<String>,
+    /// "path" for MinIO, "vhost" (the default) for AWS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s3_url_style: Option<String>,
+    pub s3_use_ssl: bool,
+    /// Static credentials; the AWS credential chain is used without them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s3_key_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s3_secret: Option<String>,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        Self {
+            uri: source::DEFAULT_SOURCE.to_string(),
+            s3_endpoint: None,
+            s3_region: None,
+            s3_url_style: None,
+            s3_use_ssl: true,
+            s3_key_id: None,
+            s3_secret: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryConfig {
+    /// Tool calls running longer are interrupted; 0 disables the timeout
+    pub timeout_secs: u64,
+    /// Rows company-sql returns at most; 0 disables the limit
+    pub max_rows: u64,
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 60,
+            max_rows: 10_000,
+        }
+    }
+}
+
+impl QueryConfig {
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_secs > 0).then(|| Duration::from_secs(self.timeout_secs))
+    }
+}
+
+/// How `serve` talks to MCP clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// SSE over HTTP on `host:port`, with the admin endpoint
+    #[default]
+    Sse,
+    /// One client on stdin/stdout, for clients that spawn the server
+    Stdio,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `sse` or `stdio`
+    pub transport: Transport,
+    pub host: String,
+    pub port: u16,
+    pub sse_path: String,
+    pub post_path: String,
+    /// Seconds between SSE keep-alive pings; rmcp's default when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sse_keep_alive_secs: Option<u64>,
+    /// Seconds between checks of the database file for a newer snapshot;
+    /// 0 disables watching
+    pub reload_interval_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            transport: Transport::Sse,
+            host: "0.0.0.0".to_string(),
+            port: 8000,
+            sse_path: "/sse".to_string(),
+            post_path: "/message".to_string(),
+            sse_keep_alive_secs: None,
+            reload_interval_secs: 30,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn reload_interval(&self) -> Option<Duration> {
+        (self.reload_interval_secs > 0).then(|| Duration::from_secs(self.reload_interval_secs))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Only serve requests that appear to come from Claude
+    pub claude_only: bool,
+    /// Bearer token for `POST /admin/reload`, which is not mounted without one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            claude_only: true,
+            admin_token: None,
+        }
+    }
+}
+
+/// Every optional setting filled in, so its keys and value types can be listed
+fn sample() -> Config {
+    let mut config = Config::default();
+    config.database.threads = Some(1);
+    config.database.memory_limit = Some(String::new());
+    config.database.temp_directory = Some(PathBuf::new());
+    config.server.sse_keep_alive_secs = Some(1);
+    config.source.s3_endpoint = Some(String::new());
+    config.source.s3_region = Some(String::new());
+    config.source.s3_url_style = Some(String::new());
+    config.source.s3_key_id = Some(String::new());
+    config.source.s3_secret = Some(String::new());
+    config.auth.admin_token = Some(String::new());
+    config
+}
+
+fn flatten(prefix: &str, table: &toml::Table, keys: &mut Vec<(String, toml::Value)>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(table) => flatten(&key, table, keys),
+            value => keys.push((key, value.clone())),
+        }
+    }
+}
+
+/// Every setting as `(section.key, sample value)`
+fn settings() -> Vec<(String, toml::Value)> {
+    let mut keys = Vec::new();
+    if let Ok(toml::Value::Table(table)) = toml::Value::try_from(sample()) {
+        flatten("", &table, &mut keys);
+    }
+    keys
+}
+
+/// Environment variable for a `section.key` setting
+pub fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+/// `raw` as a value of the same type as `sample`
+fn parse_value(raw: &str, sample: &toml::Value) -> Option<toml::Value> {
+    let raw = raw.trim();
+    match sample {
+        toml::Value::Integer(_) => raw.parse().ok().map(toml::Value::Integer),
+        toml::Value::Float(_) => raw.parse().ok().map(toml::Value::Float),
+        toml::Value::Boolean(_) => raw.parse().ok().map(toml::Value::Boolean),
+        _ => Some(toml::Value::String(raw.to_string())),
+    }
+}
+
+fn set(table: &mut toml::Table, key: &str, value: toml::Value) {
+    let mut table = table;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            table.insert(part.to_string(), value);
+            return;
+        }
+        let entry = table
+            .entry(part.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        if !entry.is_table() {
+            *entry = toml::Value::Table(toml::Table::new());
+        }
+        table = entry.as_table_mut().expect("just made a table");
+    }
+}
+
+/// Merge `file`, the environment and `flags` (`section.key`, value) over the
+/// defaults
+fn layered(
+    file: toml::Table,
+    env: impl Fn(&str) -> Option<String>,
+    flags: &[(String, String)],
+) -> Result<Config> {
+    let settings = settings();
+    let mut table = file;
+
+    let mut overrides: Vec<(String, String, String)> = Vec::new();
+    if let Some(port) = env("PORT") {
+        overrides.push(("server.port".to_string(), port, "PORT".to_string()));
+    }
+    for (key, _) in &settings {
+        let name = env_name(key);
+        if let Some(value) = env(&name) {
+            overrides.push((key.clone(), value, name));
+        }
+    }
+    for (key, value) in flags {
+        overrides.push((key.clone(), value.clone(), format!("--set {}", key)));
+    }
+
+    for (key, raw, origin) in overrides {
+        let Some((_, sample)) = settings.iter().find(|(known, _)| *known == key) else {
+            bail!("Unknown setting {}", origin);
+        };
+        let Some(value) = parse_value(&raw, sample) else {
+            bail!(
+                "Invalid {} {:?}: expected a {}",
+                origin,
+                raw,
+                sample.type_str()
+            );
+        };
+        set(&mut table, &key, value);
+    }
+
+    let config: Config = toml::Value::Table(table)
+        .try_into()
+        .context("Invalid configuration")?;
+    config.validate()?;
+    Ok(config)
+}
+
+impl Config {
+    fn validate(&self) -> Result<()> {
+        if self.database.pool_size == 0 {
+            bail!("database.pool_size must be at least 1");
+        }
+        if self.source.s3_key_id.is_some() != self.source.s3_secret.is_some() {
+            bail!("source.s3_key_id and source.s3_secret must be set together");
+        }
+        for (key, path) in [
+            ("server.sse_path", &self.server.sse_path),
+            ("server.post_path", &self.server.post_path),
+        ] {
+            if !path.starts_with('/') {
+                bail!("{} must start with '/', got {:?}", key, path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Load from `file` (or [`CONFIG_FILE_ENV`], or [`DEFAULT_CONFIG_FILE`]
+    /// if it exists), then the environment, then `flags`
+    pub fn load(file: Option<&Path>, flags: &[(String, String)]) -> Result<Self> {
+        let file = file
+            .map(Path::to_path_buf)
+            .or_else(|| env::var_os(CONFIG_FILE_ENV).map(PathBuf::from))
+            .or_else(|| {
+                let default = PathBuf::from(DEFAULT_CONFIG_FILE);
+                default.exists().then_some(default)
+            });
+        let table = match &file {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                text.parse::<toml::Table>()
+                    .with_context(|| format!("Failed to parse {}", path.display()))?
+            }
+            None => toml::Table::new(),
+        };
+        layered(table, |name| env::var(name).ok(), flags)
+    }
+
+    pub fn duckdb_config(&self) -> DuckDbConfig {
+        let database = &self.database;
+        let defaults = DuckDbConfig::default();
+        DuckDbConfig {
+            db_filename: database.path.to_string_lossy().into_owned(),
+            http_timeout: Duration::from_secs(database.http_timeout_secs),
+            http_keep_alive: database.http_keep_alive,
+            http_retries: database.http_retries,
+            s3_uploader_thread_limit: database.s3_uploader_thread_limit,
+            temp_directory: database
+                .temp_directory
+                .clone()
+                .unwrap_or(defaults.temp_directory),
+            max_temp_directory_size: database.max_temp_directory_size.clone(),
+            access_mode: database.access_mode.into(),
+            threads: database.threads,
+            memory_limit: database.memory_limit.clone(),
+            source: Source::parse(&self.source.uri),
+            s3: S3Config {
+                endpoint: self.source.s3_endpoint.clone(),
+                region: self.source.s3_region.clone(),
+                url_style: self.source.s3_url_style.clone(),
+                use_ssl: self.source.s3_use_ssl,
+                key_id: self.source.s3_key_id.clone(),
+                secret: self.source.s3_secret.clone(),
+            },
+        }
+    }
+
+    /// The configuration with secrets masked, for printing
+    pub fn masked(&self) -> Config {
+        let mut config = self.clone();
+        if config.auth.admin_token.is_some() {
+            config.auth.admin_token = Some("********".to_string());
+        }
+        if config.source.s3_secret.is_some() {
+            config.source.s3_secret = Some("********".to_string());
+        }
+        config
+    }
+
+    /// The effective configuration as TOML, with secrets masked
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(&self.masked())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_have_env_names() {
+        let keys: Vec<String> = settings().into_iter().map(|(key, _)| key).collect();
+        assert!(keys.contains(&"database.memory_limit".to_string()));
+        assert!(keys.contains(&"auth.admin_token".to_string()));
+        assert_eq!(
+            env_name("server.reload_interval_secs"),
+            "NEST_MCP_SERVER_RELOAD_INTERVAL_SECS"
+        );
+    }
+
+    #[test]
+    fn test_layers_override_in_order() -> Result<()> {
+        let file: toml::Table = r#"
+            [database]
+            path = "/data/nest.db"
+            threads = 2
+
+            [server]
+            port = 9000
+            sse_path = "/events"
+        "#
+        .parse()?;
+        let env = |name: &str| match name {
+            "PORT" => Some("8080".to_string()),
+            "NEST_MCP_DATABASE_THREADS" => Some("4".to_string()),
+            "NEST_MCP_AUTH_CLAUDE_ONLY" => Some("false".to_string()),
+            _ => None,
+        };
+        let flags = vec![
+            ("database.threads".to_string(), "8".to_string()),
+            ("database.mode".to_string(), "view".to_string()),
+            ("server.transport".to_string(), "stdio".to_string()),
+        ];
+
+        let config = layered(file, env, &flags)?;
+        assert_eq!(config.database.path, PathBuf::from("/data/nest.db"));
+        assert_eq!(config.database.threads, Some(8));
+        assert_eq!(config.database.mode, BuildMode::View);
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.transport, Transport::Stdio);
+        assert_eq!(config.server.sse_path, "/events");
+        assert_eq!(config.server.post_path, "/message");
+        assert!(!config.auth.claude_only);
+        assert_eq!(config.query, QueryConfig::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        let no_env = |_: &str| None;
+        let flag = |key: &str, value: &str| vec![(key.to_string(), value.to_string())];
+
+        assert!(layered(toml::Table::new(), no_env, &flag("server.port", "http")).is_err());
+        assert!(layered(toml::Table::new(), no_env, &flag("server.colour", "red")).is_err());
+        assert!(layered(toml::Table::new(), no_env, &flag("server.sse_path", "sse")).is_err());
+        assert!(layered("[query]\ntimeout = 5".parse().unwrap(), no_env, &[]).is_err());
+    }
+
+    #[test]
+    fn test_printed_config_masks_secrets() -> Result<()> {
+        let mut config = Config::default();
+        config.auth.admin_token = Some("hunter2".to_string());
+        config.source.s3_secret = Some("hunter2".to_string());
+        let printed = config.to_toml()?;
+        assert!(!printed.contains("hunter2"));
+
+        let reparsed: Config = toml::from_str(&printed)?;
+        assert_eq!(reparsed.server, config.server);
+        Ok(())
+    }
+}
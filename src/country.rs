@@ -0,0 +1,138 @@
+//! Countries of the companies in the dataset: the `country` column, the
+//! currency of their financial values and the `country` search filter.
+//!
+//! There is no per-country account code mapping. The converter maps every code
+//! of `raw/codes.csv` to its English description whatever the country, and
+//! ingest reads `financial_data` under the Swedish metric names of
+//! [`crate::metrics::FINANCIAL_METRICS`] only, so a Norwegian or Danish source
+//! must already use those names.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Country {
+    Sweden,
+    Norway,
+    Denmark,
+}
+
+impl Country {
+    pub const ALL: [Country; 3] = [Country::Sweden, Country::Norway, Country::Denmark];
+
+    /// ISO 3166-1 alpha-2 code, as stored in `hello_nest.country`
+    pub fn code(self) -> &'static str {
+        match self {
+            Country::Sweden => "SE",
+            Country::Norway => "NO",
+            Country::Denmark => "DK",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Country::Sweden => "Sweden",
+            Country::Norway => "Norway",
+            Country::Denmark => "Denmark",
+        }
+    }
+
+    /// ISO 4217 currency that financial values are reported in
+    pub fn currency(self) -> &'static str {
+        match self {
+            Country::Sweden => "SEK",
+            Country::Norway => "NOK",
+            Country::Denmark => "DKK",
+        }
+    }
+
+    /// Lowercase ISO codes and English and native names of the country
+    fn aliases(self) -> &'static [&'static str] {
+        match self {
+            Country::Sweden => &["se", "swe", "sweden", "sverige"],
+            Country::Norway => &["no", "nor", "norway", "norge", "noreg"],
+            Country::Denmark => &["dk", "dnk", "denmark", "danmark"],
+        }
+    }
+
+    /// Parse an ISO code or an English or native country name, e.g. "SE",
+    /// "Sweden", "Sverige", "norge" or "Danmark"
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.trim().to_lowercase();
+        Country::ALL
+            .into_iter()
+            .find(|country| country.aliases().contains(&name.as_str()))
+    }
+}
+
+/// `source` with a `country` column, which is NULL when the source has
+/// neither a `country` column nor `country=` partition directories
+pub fn with_country_column_sql(source: &str) -> String {
+    format!(
+        "(SELECT * FROM {source} UNION ALL BY NAME SELECT CAST(NULL AS VARCHAR) AS country WHERE false)"
+    )
+}
+
+/// `country` of a company, from the `country` column of a source wrapped in
+/// [`with_country_column_sql`]. The column may hold any code or name that
+/// [`Country::parse`] accepts. Companies without one are Swedish, like all of
+/// `hello_nest.parquet`; an unknown country fails the ingest.
+pub fn country_sql() -> String {
+    let name = "lower(trim(CAST(country AS VARCHAR)))";
+    let cases: Vec<String> = Country::ALL
+        .iter()
+        .map(|country| {
+            let aliases: Vec<String> = country
+                .aliases()
+                .iter()
+                .map(|alias| format!("'{}'", alias))
+                .collect();
+            format!(
+                "WHEN {} IN ({}) THEN '{}'",
+                name,
+                aliases.join(", "),
+                country.code()
+            )
+        })
+        .collect();
+    format!(
+        "CASE WHEN country IS NULL THEN '{}' {} ELSE error('Unknown country ' || CAST(country AS VARCHAR)) END",
+        Country::Sweden.code(),
+        cases.join(" ")
+    )
+}
+
+/// `currency` of a company's financial values, from its `country` code
+pub fn currency_sql(country: &str) -> String {
+    let cases: Vec<String> = Country::ALL
+        .iter()
+        .map(|country| format!("WHEN '{}' THEN '{}'", country.code(), country.currency()))
+        .collect();
+    format!("CASE {} {} END", country, cases.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_country() {
+        assert_eq!(Country::parse("SE"), Some(Country::Sweden));
+        assert_eq!(Country::parse(" norge "), Some(Country::Norway));
+        assert_eq!(Country::parse("Danmark"), Some(Country::Denmark));
+        assert_eq!(Country::parse("Finland"), None);
+    }
+
+    #[test]
+    fn test_country_sql_accepts_every_alias() {
+        let sql = country_sql();
+        assert!(sql.starts_with("CASE WHEN country IS NULL THEN 'SE' "));
+        assert!(sql.contains("IN ('no', 'nor', 'norway', 'norge', 'noreg') THEN 'NO'"));
+        assert!(sql.contains("ELSE error("));
+    }
+
+    #[test]
+    fn test_currency_sql() {
+        assert_eq!(
+            currency_sql("country"),
+            "CASE country WHEN 'SE' THEN 'SEK' WHEN 'NO' THEN 'NOK' WHEN 'DK' THEN 'DKK' END"
+        );
+    }
+}
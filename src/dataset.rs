@@ -0,0 +1,293 @@
+//! Which data build the server answers from.
+//!
+//! `create-db` and `update` append a row to `dataset_metadata` with the build
+//! time, the hashes of the files read, row counts and year coverage. The
+//! newest row is the dataset version that tool responses and the
+//! [`RESOURCE_URI`] resource report, so analysts can cite the data vintage.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{duckdb::DuckDB, metrics, source::Source};
+
+pub const RESOURCE_URI: &str = "nest://dataset/metadata";
+
+/// Notebook that converts the source CSV to `hello_nest.parquet`. It has no
+/// version of its own, so its hash stands in for one.
+pub const CONVERTER_NOTEBOOK: &str = "converter.ipynb";
+/// Overrides the converter version, e.g. with the commit the parquet was
+/// built from
+pub const CONVERTER_VERSION_ENV: &str = "NEST_MCP_CONVERTER_VERSION";
+
+/// Sources, row counts and year coverage are JSON text, so the table reads the
+/// same without the json extension.
+pub const METADATA_TABLE_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS dataset_metadata (
+        version VARCHAR,
+        kind VARCHAR,
+        built_at TIMESTAMP,
+        builder_version VARCHAR,
+        converter_version VARCHAR,
+        sources VARCHAR,
+        row_counts VARCHAR,
+        year_coverage VARCHAR
+    );
+"#;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceFile {
+    pub path: String,
+    pub sha256: String,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct YearCoverage {
+    pub year: i64,
+    pub companies: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DatasetMetadata {
+    /// Build time and the first source hash, e.g. `20250825T101500-3f2a9c1e`
+    pub version: String,
+    /// `create` for a full build, `update` for a merged delta
+    pub kind: String,
+    /// UTC, ISO 8601
+    pub built_at: String,
+    /// nest-mcp version that wrote the tables
+    pub builder_version: String,
+    pub converter_version: Option<String>,
+    pub sources: Vec<SourceFile>,
+    pub row_counts: BTreeMap<String, i64>,
+    /// Companies with accounts per financial year
+    pub year_coverage: Vec<YearCoverage>,
+}
+
+/// The short form included in tool responses
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DatasetVersion {
+    pub version: String,
+    pub built_at: String,
+}
+
+impl DatasetMetadata {
+    pub fn summary(&self) -> DatasetVersion {
+        DatasetVersion {
+            version: self.version.clone(),
+            built_at: self.built_at.clone(),
+        }
+    }
+}
+
+fn hash_file(db: &DuckDB, path: &str) -> Result<SourceFile> {
+    let (sha256, bytes) = db
+        .query_one(
+            &format!(
+                "SELECT sha256(content), size FROM read_blob('{}')",
+                path.replace('\'', "''")
+            ),
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+        )?
+        .with_context(|| format!("Failed to read {}", path))?;
+    Ok(SourceFile {
+        path: path.to_string(),
+        sha256,
+        bytes,
+    })
+}
+
+fn converter_version(db: &DuckDB) -> Result<Option<String>> {
+    if let Some(version) = std::env::var(CONVERTER_VERSION_ENV)
+        .ok()
+        .filter(|version| !version.is_empty())
+    {
+        return Ok(Some(version));
+    }
+    if !Path::new(CONVERTER_NOTEBOOK).exists() {
+        return Ok(None);
+    }
+    let notebook = hash_file(db, CONVERTER_NOTEBOOK)?;
+    Ok(Some(format!("sha256:{}", &notebook.sha256[..12])))
+}
+
+fn row_counts(db: &DuckDB) -> Result<BTreeMap<String, i64>> {
+    let tables = db.query_all(
+        "SELECT table_name FROM information_schema.tables \
+         WHERE table_catalog = current_database() AND table_schema = 'main' \
+         AND table_type IN ('BASE TABLE', 'VIEW') AND table_name <> 'dataset_metadata' \
+         ORDER BY table_name",
+        |row| Ok(row.get::<_, String>(0)?),
+    )?;
+    tables
+        .into_iter()
+        .map(|table| {
+            let rows = db
+                .query_one(&format!("SELECT count(*) FROM \"{}\"", table), |row| {
+                    Ok(row.get::<_, i64>(0)?)
+                })?
+                .unwrap_or(0);
+            Ok((table, rows))
+        })
+        .collect()
+}
+
+fn year_coverage_sql() -> String {
+    let selects: Vec<String> = metrics::FINANCIAL_YEARS
+        .iter()
+        .map(|year| {
+            format!(
+                "SELECT {year} AS year, count(*) FILTER (WHERE {}) FROM hello_nest",
+                metrics::has_accounts_sql(*year)
+            )
+        })
+        .collect();
+    format!("{} ORDER BY year", selects.join(" UNION ALL "))
+}
+
+/// Append the metadata of a `kind` build that read `sources` to
+/// `dataset_metadata`. Local sources that do not exist are left out; remote
+/// ones are read again to hash them.
+pub fn record(db: &DuckDB, kind: &str, sources: &[&str]) -> Result<DatasetMetadata> {
+    db.execute(METADATA_TABLE_SQL)?;
+
+    let sources = sources
+        .iter()
+        .filter(|path| Source::parse(path).is_remote() || Path::new(path).exists())
+        .map(|path| hash_file(db, path))
+        .collect::<Result<Vec<_>>>()?;
+    let year_coverage = db.query_all(&year_coverage_sql(), |row| {
+        Ok(YearCoverage {
+            year: row.get(0)?,
+            companies: row.get(1)?,
+        })
+    })?;
+    let row_counts = row_counts(db)?;
+    let converter_version = converter_version(db)?;
+    let hash = sources
+        .first()
+        .map(|source| format!("-{}", &source.sha256[..8]))
+        .unwrap_or_default();
+
+    db.execute(&format!(
+        "INSERT INTO dataset_metadata \
+         SELECT strftime(built_at, '%Y%m%dT%H%M%S') || '{hash}', '{kind}', built_at, '{builder}', {converter}, '{sources}', '{row_counts}', '{year_coverage}' \
+         FROM (SELECT now()::TIMESTAMP AS built_at)",
+        kind = kind.replace('\'', "''"),
+        builder = env!("CARGO_PKG_VERSION"),
+        converter = converter_version.as_deref().map_or("NULL".to_string(), |version| {
+            format!("'{}'", version.replace('\'', "''"))
+        }),
+        sources = serde_json::to_string(&sources)?.replace('\'', "''"),
+        row_counts = serde_json::to_string(&row_counts)?.replace('\'', "''"),
+        year_coverage = serde_json::to_string(&year_coverage)?.replace('\'', "''"),
+    ))?;
+
+    current(db)?.context("Failed to read back dataset metadata")
+}
+
+/// The newest build, or `None` for a database built before metadata was
+/// recorded
+pub fn current(db: &DuckDB) -> Result<Option<DatasetMetadata>> {
+    let exists = db
+        .query_one(
+            "SELECT count(*) FROM information_schema.tables \
+             WHERE table_catalog = current_database() AND table_name = 'dataset_metadata'",
+            |row| Ok(row.get::<_, i64>(0)?),
+        )?
+        .unwrap_or(0);
+    if exists == 0 {
+        return Ok(None);
+    }
+
+    let row = db.query_one(
+        "SELECT version, kind, strftime(built_at, '%Y-%m-%dT%H:%M:%SZ'), builder_version, \
+         converter_version, sources, row_counts, year_coverage \
+         FROM dataset_metadata ORDER BY built_at DESC LIMIT 1",
+        |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+                row.get::<_, String>(7)?,
+            ))
+        },
+    )?;
+    let Some((version, kind, built_at, builder_version, converter_version, sources, rows, years)) =
+        row
+    else {
+        return Ok(None);
+    };
+    Ok(Some(DatasetMetadata {
+        version,
+        kind,
+        built_at,
+        builder_version,
+        converter_version,
+        sources: serde_json::from_str(&sources).context("Invalid dataset sources")?,
+        row_counts: serde_json::from_str(&rows).context("Invalid dataset row counts")?,
+        year_coverage: serde_json::from_str(&years).context("Invalid dataset year coverage")?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duckdb::test_support::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_record_dataset_metadata() -> Result<()> {
+        let db = create_test_db("dataset")?;
+        let revenue = metrics::REVENUE_METRIC;
+        db.execute(&format!(
+            "CREATE TABLE hello_nest AS {}",
+            companies_sql(&[
+                (
+                    1,
+                    "Alpha AB",
+                    &[(2023, revenue, 100.0), (2024, revenue, 120.0)]
+                ),
+                (2, "Beta AB", &[(2024, revenue, 50.0)]),
+                (3, "Gamma AB", &[(2023, EMPTY_YEAR, 0.0)]),
+            ])
+        ))?;
+        assert_eq!(current(&db)?, None);
+
+        let source = "/tmp/test_duck_dataset_source.parquet";
+        fs::write(source, "nest")?;
+        let metadata = record(&db, "create", &[source, "/tmp/missing.parquet"])?;
+        fs::remove_file(source)?;
+
+        assert_eq!(
+            metadata.sources,
+            vec![SourceFile {
+                path: source.to_string(),
+                sha256: "75ef2e329bdb50d26a4ce73c2aaa567364e4ac5b5f79f7cf6d64b4cd547a863c"
+                    .to_string(),
+                bytes: 4,
+            }]
+        );
+        assert!(metadata.version.ends_with("-75ef2e32"));
+        assert_eq!(metadata.kind, "create");
+        assert_eq!(metadata.row_counts.get("hello_nest"), Some(&3));
+        assert!(!metadata.row_counts.contains_key("dataset_metadata"));
+        let coverage: Vec<(i64, i64)> = metadata
+            .year_coverage
+            .iter()
+            .filter(|year| year.companies > 0)
+            .map(|year| (year.year, year.companies))
+            .collect();
+        // Gamma's 2023 struct has no accounts
+        assert_eq!(coverage, vec![(2023, 1), (2024, 2)]);
+        assert_eq!(current(&db)?, Some(metadata));
+
+        cleanup_test_db("dataset");
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+//! Metrics derived from the base amounts in `financial_data`.
+//!
+//! Ingest adds [`DERIVED_METRICS`] to every year's struct, so they can be
+//! filtered, sorted and converted like reported metrics. The reported ratios
+//! in [`RECOMPUTED_RATIOS`] are recomputed from their base amounts and the
+//! disagreements are flagged, since the source often stores them as
+//! percentages or with the wrong numerator.
+
+use crate::metrics;
+
+/// A metric computed from other fields of one year's financial struct
+#[derive(Debug, Clone, Copy)]
+pub struct Formula {
+    pub metric: &'static str,
+    pub definition: &'static str,
+    sql: fn(&str) -> String,
+}
+
+impl Formula {
+    /// The metric computed from `year_struct`, an expression of one year's
+    /// financial struct, e.g. `financial_data['2024']`
+    pub fn sql(&self, year_struct: &str) -> String {
+        (self.sql)(year_struct)
+    }
+}
+
+pub const EBITDA_METRIC: &str = "EBITDA";
+pub const NET_DEBT_METRIC: &str = "Net debt";
+pub const WORKING_CAPITAL_METRIC: &str = "Working capital";
+pub const EQUITY_RATIO_METRIC: &str = "Equity ratio";
+
+/// Metrics stored alongside the reported ones in each year's struct
+pub const DERIVED_METRICS: [Formula; 4] = [
+    Formula {
+        metric: EBITDA_METRIC,
+        definition: "Result before depreciation (operating result before depreciation and amortisation), as reported",
+        sql: |f| format!("({f}['Result before depreciation'])"),
+    },
+    Formula {
+        metric: NET_DEBT_METRIC,
+        definition: "Total long-term liabilities + Total short-term liabilities - Bank deposits cash etc.",
+        sql: |f| {
+            format!(
+                "({f}['Total long-term liabilities'] + {f}['Total short-term liabilities'] - {f}['Bank deposits cash etc.'])"
+            )
+        },
+    },
+    Formula {
+        metric: WORKING_CAPITAL_METRIC,
+        definition: "Total current assets - Total short-term liabilities",
+        sql: |f| format!("({f}['Total current assets'] - {f}['Total short-term liabilities'])"),
+    },
+    Formula {
+        metric: EQUITY_RATIO_METRIC,
+        definition: "Total equity / Total assets, as a fraction",
+        sql: |f| format!("({f}['Total equity'] / nullif({f}['Total assets'], 0))"),
+    },
+];
+
+/// Reported ratios and how to recompute them from base amounts
+pub const RECOMPUTED_RATIOS: [Formula; 3] = [
+    Formula {
+        metric: "Operating margin",
+        definition: "Operating result / Total operating revenues",
+        sql: |f| format!("({f}['Operating result'] / nullif({f}['Total operating revenues'], 0))"),
+    },
+    Formula {
+        metric: "Equity-to-asset ratio / solvency ratio",
+        definition: "Total equity / Total assets",
+        sql: |f| format!("({f}['Total equity'] / nullif({f}['Total assets'], 0))"),
+    },
+    Formula {
+        metric: "Debt ratio",
+        definition: "(Total short-term liabilities + Total long-term liabilities) / Total equity",
+        sql: |f| {
+            format!(
+                "(({f}['Total short-term liabilities'] + {f}['Total long-term liabilities']) / nullif({f}['Total equity'], 0))"
+            )
+        },
+    },
+];
+
+/// A reported ratio matches its recomputed value within 10% of it, or within
+/// 0.01 for ratios near zero. Swedish solvency adds 79.4% of untaxed
+/// reserves to equity, which the base amounts lack, so exact matches are rare.
+pub const MISMATCH_RELATIVE_TOLERANCE: f64 = 0.1;
+pub const MISMATCH_ABSOLUTE_TOLERANCE: f64 = 0.01;
+
+pub fn is_derived_metric(metric: &str) -> bool {
+    DERIVED_METRICS
+        .iter()
+        .any(|formula| formula.metric == metric)
+}
+
+/// `column` (the raw `financial_data` struct) with [`DERIVED_METRICS`]
+/// added to every reported year
+pub fn financial_data_with_derived_sql(column: &str) -> String {
+    let years: Vec<String> = metrics::FINANCIAL_YEARS
+        .iter()
+        .map(|year| {
+            let year_struct = format!("{}['{}']", column, year);
+            let fields: Vec<String> = DERIVED_METRICS
+                .iter()
+                .map(|formula| {
+                    format!(
+                        "\"{}\" := CAST({} AS DOUBLE)",
+                        formula.metric,
+                        formula.sql(&year_struct)
+                    )
+                })
+                .collect();
+            format!(
+                "\"{year}\" := CASE WHEN {year_struct} IS NOT NULL THEN struct_insert({year_struct}, {}) END",
+                fields.join(", ")
+            )
+        })
+        .collect();
+    format!("STRUCT_PACK({})", years.join(", "))
+}
+
+/// Reported ratios that disagree with their recomputed value: one row per
+/// company, year and ratio with `company_id`, `company_name`, `year`,
+/// `metric`, `value` (reported), `computed` and `scale` (reported / computed,
+/// about 100 for a percentage stored as a fraction).
+pub fn ratio_mismatches_sql() -> String {
+    let selects: Vec<String> = RECOMPUTED_RATIOS
+        .iter()
+        .flat_map(|formula| {
+            metrics::FINANCIAL_YEARS.iter().map(move |year| {
+                let reported = metrics::metric_sql(formula.metric, *year);
+                let computed = formula.sql(&format!("financial_data['{}']", year));
+                format!(
+                    "SELECT company_id, company_name, {year} AS year, '{metric}' AS metric, \
+                     {reported} AS value, {computed} AS computed, \
+                     round({reported} / nullif({computed}, 0), 2) AS scale \
+                     FROM hello_nest \
+                     WHERE abs({reported} - {computed}) > greatest({MISMATCH_ABSOLUTE_TOLERANCE}, {MISMATCH_RELATIVE_TOLERANCE} * abs({computed}))",
+                    metric = formula.metric
+                )
+            })
+        })
+        .collect();
+    selects.join(" UNION ALL ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duckdb::test_support::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_recomputed_ratios_are_reported_metrics() {
+        for formula in RECOMPUTED_RATIOS {
+            assert!(
+                metrics::is_known_metric(formula.metric),
+                "{}",
+                formula.metric
+            );
+            assert!(!is_derived_metric(formula.metric));
+        }
+        for formula in DERIVED_METRICS {
+            assert!(!metrics::FINANCIAL_METRICS.contains(&formula.metric));
+        }
+    }
+
+    #[test]
+    fn test_financial_data_with_derived_keeps_missing_years_null() {
+        let sql = financial_data_with_derived_sql("fd");
+        assert!(sql.starts_with(
+            "STRUCT_PACK(\"2016\" := CASE WHEN fd['2016'] IS NOT NULL THEN struct_insert(fd['2016'], \"EBITDA\" := CAST((fd['2016']['Result before depreciation']) AS DOUBLE), "
+        ));
+        assert_eq!(
+            sql.matches("struct_insert(").count(),
+            metrics::FINANCIAL_YEARS.len()
+        );
+    }
+
+    #[test]
+    fn test_ratio_mismatches_use_tolerance() {
+        let sql = ratio_mismatches_sql();
+        assert!(sql.contains(
+            "abs(financial_data['2024']['Operating margin'] - (financial_data['2024']['Operating result'] / nullif(financial_data['2024']['Total operating revenues'], 0))) > greatest(0.01, 0.1 * abs("
+        ));
+        assert_eq!(
+            sql.matches("SELECT ").count(),
+            RECOMPUTED_RATIOS.len() * metrics::FINANCIAL_YEARS.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_derived_metrics_and_ratio_mismatches() -> Result<()> {
+        let db = create_test_db("derived")?;
+
+        let financial_data = financial_data_sql(&[
+            (2024, "Total operating revenues", 1000.0),
+            (2024, "Total operating expenses", -900.0),
+            (2024, "Operating result", 100.0),
+            (2024, "Result before depreciation", 150.0),
+            (2024, "Operating margin", 10.0),
+            (2024, "Total assets", 1000.0),
+            (2024, "Total equity", 500.0),
+            (2024, "Equity-to-asset ratio / solvency ratio", 0.5),
+            (2024, "Total current assets", 800.0),
+            (2024, "Total short-term liabilities", 300.0),
+            (2024, "Total long-term liabilities", 200.0),
+            (2024, "Bank deposits cash etc.", 150.0),
+            (2024, "Debt ratio", 1.05),
+        ]);
+        db.execute(&format!(
+            "CREATE TABLE hello_nest AS SELECT 1 AS company_id, 'Derived AB' AS company_name, {} AS financial_data",
+            financial_data_with_derived_sql(&financial_data)
+        ))?;
+
+        let derived_2024 = db.query_one(
+            "SELECT f['EBITDA'], f['Net debt'], f['Working capital'], f['Equity ratio'] \
+             FROM (SELECT financial_data['2024'] AS f FROM hello_nest)",
+            |row| {
+                Ok((
+                    row.get::<_, f64>(0)?,
+                    row.get::<_, f64>(1)?,
+                    row.get::<_, f64>(2)?,
+                    row.get::<_, f64>(3)?,
+                ))
+            },
+        )?;
+        assert_eq!(derived_2024, Some((150.0, 350.0, 500.0, 0.5)));
+        let missing_year = db.query_one(
+            "SELECT financial_data['2023'] IS NULL FROM hello_nest",
+            |row| Ok(row.get::<_, bool>(0)?),
+        )?;
+        assert_eq!(missing_year, Some(true));
+
+        // Only the operating margin, stored as a percentage, is off
+        db.create_ratio_mismatches_table()?;
+        let mismatches = db.query_all(
+            "SELECT year, metric, reported, computed, scale FROM ratio_mismatches",
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, f64>(2)?,
+                    row.get::<_, f64>(3)?,
+                    row.get::<_, f64>(4)?,
+                ))
+            },
+        )?;
+        assert_eq!(
+            mismatches,
+            vec![(2024, "Operating margin".to_string(), 10.0, 0.1, 100.0)]
+        );
+
+        cleanup_test_db("derived");
+        Ok(())
+    }
+}
@@ -1,7 +1,17 @@
+use crate::{
+    country, dataset, derived, fx, gazetteer, geo, history, metrics, migrations,
+    source::{self, RemoteSettings, S3Config, Source},
+    views,
+};
 use anyhow::{Context, Result};
 use duck::{AccessMode, Config, Connection};
 use serde_json::Value;
-use std::{env, path::PathBuf, time::Duration};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 #[derive(Debug)]
 pub struct DuckDbConfig {
@@ -13,6 +23,11 @@ pub struct DuckDbConfig {
     pub temp_directory: PathBuf,
     pub max_temp_directory_size: String,
     pub access_mode: AccessMode,
+    pub threads: Option<u32>,
+    pub memory_limit: Option<String>,
+    /// Where `create-db` reads the companies from
+    pub source: Source,
+    pub s3: S3Config,
 }
 
 impl Default for DuckDbConfig {
@@ -26,121 +41,710 @@ impl Default for DuckDbConfig {
             temp_directory: std::env::current_dir().unwrap_or_else(|_| env::temp_dir()),
             max_temp_directory_size: "10 GB".into(),
             access_mode: AccessMode::ReadOnly,
+            threads: None,
+            memory_limit: None,
+            source: Source::default(),
+            s3: S3Config::default(),
         }
     }
 }
 
+impl DuckDbConfig {
+    pub fn db_path(&self) -> PathBuf {
+        self.temp_directory.join(&self.db_filename)
+    }
+}
+
 pub struct DuckDB {
     conn: Connection,
+    source: Source,
+    remote: RemoteSettings,
 }
 
 impl DuckDB {
     pub async fn new(config: DuckDbConfig) -> Result<Self> {
-        let db_path = config.temp_directory.join(&config.db_filename);
+        let db_path = config.db_path();
         let duck_config = Config::default().access_mode(config.access_mode)?;
         let conn = Connection::open_with_flags(db_path, duck_config)?;
 
-        conn.pragma_update(
-            None,
-            "http_timeout",
-            &(config.http_timeout.as_millis() as i64).to_string(),
-        )?;
-        conn.pragma_update(None, "http_keep_alive", &config.http_keep_alive.to_string())?;
-        conn.pragma_update(None, "http_retries", &config.http_retries.to_string())?;
-        conn.pragma_update(
-            None,
-            "s3_uploader_thread_limit",
-            &config.s3_uploader_thread_limit.to_string(),
-        )?;
         conn.pragma_update(
             None,
             "max_temp_directory_size",
             &config.max_temp_directory_size,
         )?;
+        if let Some(threads) = config.threads {
+            conn.pragma_update(None, "threads", &threads.to_string())?;
+        }
+        if let Some(memory_limit) = &config.memory_limit {
+            conn.pragma_update(None, "memory_limit", memory_limit)?;
+        }
 
-        conn.execute(
-            r#"
-            CREATE SECRET (
-                TYPE s3,
-                PROVIDER credential_chain,
-                REFRESH auto
-            );
-            "#,
-            [],
-        )
-        .context("Failed to create s3 credentials")?;
+        let db = Self {
+            conn,
+            source: config.source,
+            remote: RemoteSettings {
+                http_timeout: config.http_timeout,
+                http_keep_alive: config.http_keep_alive,
+                http_retries: config.http_retries,
+                s3_uploader_thread_limit: config.s3_uploader_thread_limit,
+                s3: config.s3,
+            },
+        };
+        db.register_macros();
+        source::prepare(&db, &db.source, &db.remote)?;
+
+        Ok(db)
+    }
+
+    /// Per-connection SQL macros used by the search tools. `distance_km` uses
+    /// the spatial extension when it is installed and haversine otherwise;
+    /// `fx_rate` converts between currencies with the bundled yearly rates.
+    pub(crate) fn register_macros(&self) {
+        let distance = match self.conn.execute_batch("LOAD spatial") {
+            Ok(()) => geo::SPATIAL_DISTANCE_MACRO.to_string(),
+            Err(e) => {
+                tracing::debug!(error = %e, "spatial extension unavailable, using haversine");
+                geo::haversine_distance_macro()
+            }
+        };
+        let sql = format!(
+            "CREATE OR REPLACE TEMP MACRO distance_km(lat1, lon1, lat2, lon2) AS {}",
+            distance
+        );
+        if let Err(e) = self.conn.execute_batch(&sql) {
+            tracing::warn!(error = %e, "Failed to register distance_km macro");
+        }
+
+        let sql = format!(
+            "CREATE OR REPLACE TEMP MACRO fx_rate(rate_year, from_currency, to_currency) AS {}",
+            fx::fx_rate_macro()
+        );
+        if let Err(e) = self.conn.execute_batch(&sql) {
+            tracing::warn!(error = %e, "Failed to register fx_rate macro");
+        }
+    }
 
-        Ok(Self { conn })
+    /// Another connection to the same database, with the macros registered.
+    /// The database stays open while any of its connections are alive.
+    pub fn try_clone(&self) -> Result<Self> {
+        let db = Self {
+            conn: self.conn.try_clone()?,
+            source: self.source.clone(),
+            remote: self.remote.clone(),
+        };
+        db.register_macros();
+        Ok(db)
+    }
+
+    /// Handle that interrupts the query running on this connection
+    pub fn interrupt_handle(&self) -> Arc<duck::InterruptHandle> {
+        self.conn.interrupt_handle()
     }
 
     pub async fn new_default() -> Result<Self> {
-        let mut config = DuckDbConfig::default();
-        config.access_mode = AccessMode::ReadOnly;
-        return Self::new(config).await;
+        let config = DuckDbConfig {
+            access_mode: AccessMode::ReadOnly,
+            ..Default::default()
+        };
+        Self::new(config).await
+    }
+
+    /// The configured company source
+    pub fn source(&self) -> &Source {
+        &self.source
     }
 
     /// Inspect the parquet file schema
     pub fn inspect_parquet_schema(&self) -> Result<String> {
-        let schema_sql = "DESCRIBE SELECT * FROM 'hello_nest.parquet' LIMIT 1";
-        self.query_all_json(schema_sql)
+        let schema_sql = format!(
+            "DESCRIBE SELECT * FROM {} LIMIT 1",
+            self.source.sql_literal()
+        );
+        self.query_all_json(&schema_sql)
     }
 
-    /// Create the hello_nest table from the parquet file with proper schema
+    /// Create the hello_nest table from the configured source parquet with
+    /// proper schema
     pub fn create_hello_nest_table(&self) -> Result<()> {
-        // Drop existing table if it exists
-        self.conn.execute("DROP TABLE IF EXISTS hello_nest", [])?;
+        // Drop existing table (or view) if it exists
+        self.drop_relation("hello_nest")?;
 
-        // First, let's inspect the parquet file structure
-        match self.inspect_parquet_schema() {
-            Ok(_) => {}
-            Err(_) => {}
+        self.create_companies_table("hello_nest", &self.source.read_parquet_sql())
+            .with_context(|| format!("Failed to read companies from {}", self.source.uri()))?;
+
+        self.create_reference_tables()?;
+
+        let versions = history::sync(self).context("Failed to record company history")?;
+        tracing::info!(
+            opened = versions.opened,
+            closed = versions.closed,
+            "Recorded company history"
+        );
+
+        migrations::record_current(self).context("Failed to record schema version")?;
+
+        self.record_dataset("create")
+    }
+
+    /// Create hello_nest as a view over the configured source parquet, which
+    /// may be a glob over hive-partitioned files, with the same cleaning as
+    /// [`Self::create_hello_nest_table`]. Only the coordinates and gazetteer
+    /// locations are stored (`company_coordinates`, `company_places`), so the
+    /// database stays small and company columns are read from the files at
+    /// query time.
+    ///
+    /// Files added to the source show up at once; rerun this to give them
+    /// coordinates, locations and ratio mismatches. Views keep no company
+    /// history and cannot be `update`d.
+    pub fn create_hello_nest_view(&self) -> Result<()> {
+        self.drop_relation("hello_nest")?;
+
+        let source = self.source.absolute();
+        let companies = format!("({})", companies_select_sql(&source.read_parquet_sql()));
+        self.create_company_coordinates(&companies)
+            .context("Failed to convert company coordinates")?;
+        self.create_company_places(&companies)
+            .context("Failed to normalise company locations")?;
+        self.conn
+            .execute_batch(&format!(
+                r#"
+                CREATE VIEW hello_nest AS
+                SELECT
+                    companies.*,
+                    c.lat,
+                    c.lon,
+                    p.county_code,
+                    p.county,
+                    p.municipality_code,
+                    p.municipality
+                FROM {companies} companies
+                LEFT JOIN company_coordinates c ON c.company_id = companies.company_id
+                LEFT JOIN company_places p ON p.company_id = companies.company_id;
+                "#
+            ))
+            .with_context(|| format!("Failed to create hello_nest view over {}", source.uri()))?;
+
+        self.create_reference_tables()?;
+
+        if self.relation_type("company_history")?.is_some() {
+            tracing::warn!("Dropping company_history: views keep no company history");
+            self.conn.execute_batch("DROP TABLE company_history")?;
         }
 
-        // Create table with basic structure first - debug JSON fields later
-        let create_sql = r#"
-        CREATE TABLE hello_nest AS
-        SELECT
-            company_id,
-            name AS company_name,
-            organization_number,
-            company_type,
-            company_purpose,
-            CASE
-                WHEN established_date IS NULL OR established_date = '' THEN NULL
-                ELSE TRY_CAST(established_date AS DATE)
-            END AS established_date,
-            foundation_year,
-            registered_for_payroll_tax,
-            homepage,
-            postal_address,
-            visitor_address,
-            CASE
-                WHEN nace_categories IS NULL OR nace_categories = '' OR nace_categories = '[]' OR nace_categories = 'null' THEN NULL
-                ELSE nace_categories
-            END AS nace_categories,
-            CASE
-                WHEN location IS NULL OR location = '' OR location = '{}' THEN NULL
-                ELSE STRUCT_PACK(
-                    county := json_extract_string(location, '$.county'),
-                    countryPart := json_extract_string(location, '$.countryPart'),
-                    municipality := json_extract_string(location, '$.municipality'),
-                    coordinates := CASE
-                        WHEN json_extract(location, '$.coordinates') IS NULL THEN NULL
-                        ELSE STRUCT_PACK(
-                            XCoordinate := CAST(json_extract(location, '$.coordinates[0].XCoordinate') AS DOUBLE),
-                            YCoordinate := CAST(json_extract(location, '$.coordinates[0].YCoordinate') AS DOUBLE),
-                            coordinateSystem := json_extract_string(location, '$.coordinates[0].coordinateSystem')
-                        )
-                    END
-                )
-            END AS location,
-            "financiaL_data" AS financial_data
-        FROM 'hello_nest.parquet'
-        "#;
+        migrations::record_current(self).context("Failed to record schema version")?;
+
+        self.record_dataset("create")
+    }
+
+    /// `BASE TABLE`, `VIEW` or `LOCAL TEMPORARY` for a relation of the main
+    /// database, `None` if there is none called `name`
+    fn relation_type(&self, name: &str) -> Result<Option<String>> {
+        self.query_one(
+            &format!(
+                "SELECT table_type FROM information_schema.tables \
+                 WHERE table_catalog = current_database() AND table_schema = 'main' \
+                 AND table_name = '{}'",
+                name.replace('\'', "''")
+            ),
+            |row| Ok(row.get::<_, String>(0)?),
+        )
+    }
+
+    /// Whether hello_nest is a view over parquet (see
+    /// [`Self::create_hello_nest_view`]) rather than a table
+    pub fn is_view_mode(&self) -> Result<bool> {
+        Ok(self.relation_type("hello_nest")?.as_deref() == Some("VIEW"))
+    }
+
+    fn drop_relation(&self, name: &str) -> Result<()> {
+        match self.relation_type(name)?.as_deref() {
+            Some("VIEW") => self.conn.execute_batch(&format!("DROP VIEW {}", name))?,
+            Some(_) => self.conn.execute_batch(&format!("DROP TABLE {}", name))?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// The gazetteer, exchange rate, ratio mismatch and change
+    /// log tables and the [`views`] that sit next to hello_nest
+    pub(crate) fn create_reference_tables(&self) -> Result<()> {
+        self.create_gazetteer_tables()
+            .context("Failed to create gazetteer tables")?;
+
+        self.create_fx_rates_table()
+            .context("Failed to create fx_rates table")?;
+
+        self.create_ratio_mismatches_table()
+            .context("Failed to create ratio_mismatches table")?;
 
         self.conn
-            .execute(create_sql, [])
-            .context("Failed to create hello_nest table")?;
+            .execute_batch(CHANGE_LOG_TABLE_SQL)
+            .context("Failed to create change_log table")?;
+
+        views::create(self).context("Failed to create derived views")?;
+
+        Ok(())
+    }
+
+    /// Record a `kind` build of every file the source matches
+    fn record_dataset(&self, kind: &str) -> Result<()> {
+        let files = match &self.source {
+            Source::Http(uri) => vec![uri.clone()],
+            source => self.query_all(
+                &format!(
+                    "SELECT file FROM glob({}) ORDER BY file",
+                    source.sql_literal()
+                ),
+                |row| Ok(row.get::<_, String>(0)?),
+            )?,
+        };
+        let files: Vec<&str> = files.iter().map(String::as_str).collect();
+        let metadata =
+            dataset::record(self, kind, &files).context("Failed to record dataset metadata")?;
+        tracing::info!(version = %metadata.version, "Built dataset");
+
+        Ok(())
+    }
+
+    /// Create `table` with the cleaned company columns of `source`, plus WGS84
+    /// coordinates and gazetteer locations
+    fn create_companies_table(&self, table: &str, source: &str) -> Result<()> {
+        self.conn
+            .execute(
+                &format!("CREATE TABLE {} AS {}", table, companies_select_sql(source)),
+                [],
+            )
+            .with_context(|| format!("Failed to create {} table", table))?;
+
+        self.add_wgs84_coordinates(table)
+            .context("Failed to convert company coordinates")?;
+
+        self.add_normalized_locations(table)
+            .context("Failed to normalise company locations")?;
+
+        Ok(())
+    }
+
+    /// Merge a parquet or CSV delta with the columns of `hello_nest.parquet`
+    /// into hello_nest by `company_id`. New companies are inserted; known ones
+    /// take the delta's columns, keep the financial years the delta does not
+    /// report and get the years it adds. Unchanged companies are left alone.
+    /// Every insert and update is recorded in `change_log`.
+    ///
+    /// The merge runs in one short transaction instead of a rebuild. A serving
+    /// process holds the file open, so update a copy and rename it over the
+    /// served file; the server then swaps in the new snapshot (see
+    /// [`crate::snapshot`]).
+    pub fn update_hello_nest_table(&self, path: &str) -> Result<UpdateSummary> {
+        migrations::check_compatible(self)?;
+        if self.is_view_mode()? {
+            anyhow::bail!(
+                "hello_nest is a view over parquet files: add the delta's files to its source and rerun create-db instead"
+            );
+        }
+        let delta = Source::parse(path);
+        source::prepare(self, &delta, &self.remote)?;
+        let source = delta_source_sql(delta.uri())?;
+
+        self.conn
+            .execute_batch("DROP TABLE IF EXISTS company_updates")?;
+        self.create_companies_table("company_updates", &source)?;
+
+        let duplicates = self
+            .query_one(
+                "SELECT count(*) - count(DISTINCT company_id) FROM company_updates",
+                |row| Ok(row.get::<_, i64>(0)?),
+            )?
+            .unwrap_or(0);
+        if duplicates > 0 {
+            self.conn.execute_batch("DROP TABLE company_updates")?;
+            anyhow::bail!("{} has {} duplicate company_id rows", path, duplicates);
+        }
+
+        let summary = self.merge_company_updates(path);
+        self.conn
+            .execute_batch("DROP TABLE IF EXISTS company_updates")?;
+        let summary = summary?;
+
+        let metadata = dataset::record(self, "update", &[path])
+            .context("Failed to record dataset metadata")?;
+        tracing::info!(version = %metadata.version, "Updated dataset");
+
+        Ok(summary)
+    }
+
+    /// Upsert the cleaned rows in `company_updates` into hello_nest
+    fn merge_company_updates(&self, source: &str) -> Result<UpdateSummary> {
+        self.conn.execute_batch("BEGIN TRANSACTION")?;
+        match self.apply_company_updates(source) {
+            Ok(summary) => {
+                self.conn.execute_batch("COMMIT")?;
+                if summary.inserted > 0 || summary.updated > 0 {
+                    tracing::info!(
+                        inserted = summary.inserted,
+                        updated = summary.updated,
+                        unchanged = summary.unchanged,
+                        source,
+                        "Merged company updates"
+                    );
+                }
+                Ok(summary)
+            }
+            Err(e) => {
+                self.conn.execute_batch("ROLLBACK")?;
+                Err(e)
+            }
+        }
+    }
+
+    fn apply_company_updates(&self, source: &str) -> Result<UpdateSummary> {
+        self.conn.execute_batch(CHANGE_LOG_TABLE_SQL)?;
+
+        let columns = self.query_all(
+            "SELECT column_name FROM information_schema.columns \
+             WHERE table_name = 'hello_nest' ORDER BY ordinal_position",
+            |row| Ok(row.get::<_, String>(0)?),
+        )?;
+        let received = self
+            .query_one("SELECT count(*) FROM company_updates", |row| {
+                Ok(row.get::<_, i64>(0)?)
+            })?
+            .unwrap_or(0);
+
+        self.conn.execute_batch(&format!(
+            "CREATE OR REPLACE TEMP TABLE company_merge AS {};",
+            merge_select_sql(&columns)
+        ))?;
+        self.conn.execute_batch(&format!(
+            r#"
+            DELETE FROM company_merge WHERE NOT is_new AND len(changed_columns) = 0;
+            UPDATE company_merge SET latest_financial_year = {};
+            UPDATE company_merge SET latest_financials = {};
+            UPDATE company_merge SET
+                revenue_cagr_3y = {},
+                employee_change_3y = {},
+                profitable_years_streak = {};
+            INSERT INTO change_log
+            SELECT
+                now()::TIMESTAMP,
+                company_id,
+                CASE WHEN is_new THEN 'insert' ELSE 'update' END,
+                changed_columns,
+                added_years,
+                '{}'
+            FROM company_merge;
+            DELETE FROM hello_nest WHERE company_id IN (SELECT company_id FROM company_merge);
+            INSERT INTO hello_nest BY NAME
+            SELECT * EXCLUDE (is_new, changed_columns, added_years) FROM company_merge;
+            "#,
+            metrics::latest_financial_year_sql(),
+            metrics::latest_financials_sql(),
+            metrics::revenue_cagr_3y_sql(),
+            metrics::employee_change_3y_sql(),
+            metrics::profitable_years_streak_sql(),
+            source.replace('\'', "''")
+        ))?;
+
+        let (inserted, updated) = self
+            .query_one(
+                "SELECT count(*) FILTER (WHERE is_new), count(*) FILTER (WHERE NOT is_new) FROM company_merge",
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+            )?
+            .unwrap_or_default();
+        self.conn.execute_batch("DROP TABLE company_merge")?;
+
+        history::sync(self)?;
+        self.create_ratio_mismatches_table()?;
+
+        Ok(UpdateSummary {
+            inserted,
+            updated,
+            unchanged: received - inserted - updated,
+        })
+    }
+
+    /// Flag reported ratios that disagree with their value recomputed from
+    /// base amounts, see [`derived::RECOMPUTED_RATIOS`]
+    pub(crate) fn create_ratio_mismatches_table(&self) -> Result<()> {
+        self.conn.execute_batch(&format!(
+            "CREATE OR REPLACE TABLE ratio_mismatches AS \
+             SELECT company_id, year, metric, value AS reported, computed, scale FROM ({}) \
+             ORDER BY company_id, year, metric",
+            derived::ratio_mismatches_sql()
+        ))?;
+
+        let mismatches = self
+            .query_one("SELECT count(*) FROM ratio_mismatches", |row| {
+                Ok(row.get::<_, i64>(0)?)
+            })?
+            .unwrap_or(0);
+        if mismatches > 0 {
+            tracing::warn!(
+                mismatches,
+                "Reported ratios differ from their recomputed values, see ratio_mismatches"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Load the bundled yearly average exchange rates into `fx_rates`
+    fn create_fx_rates_table(&self) -> Result<()> {
+        self.conn.execute_batch(
+            r#"
+            CREATE OR REPLACE TABLE fx_rates (
+                year BIGINT,
+                currency VARCHAR,
+                per_eur DOUBLE,
+                PRIMARY KEY (year, currency)
+            );
+            "#,
+        )?;
+
+        let mut appender = self.conn.appender("fx_rates")?;
+        for rate in fx::fx_rates() {
+            appender.append_row(duck::params![rate.year, rate.currency, rate.per_eur])?;
+        }
+        appender.flush()?;
+
+        Ok(())
+    }
+
+    /// Load the bundled gazetteer into `counties` and `municipalities` tables
+    pub(crate) fn create_gazetteer_tables(&self) -> Result<()> {
+        self.conn.execute_batch(
+            r#"
+            CREATE OR REPLACE TABLE counties (
+                county_code VARCHAR PRIMARY KEY,
+                county_name VARCHAR,
+                official_name VARCHAR,
+                lat DOUBLE,
+                lon DOUBLE
+            );
+            CREATE OR REPLACE TABLE municipalities (
+                municipality_code VARCHAR PRIMARY KEY,
+                municipality_name VARCHAR,
+                county_code VARCHAR,
+                lat DOUBLE,
+                lon DOUBLE
+            );
+            "#,
+        )?;
+
+        let mut appender = self.conn.appender("counties")?;
+        for county in gazetteer::counties() {
+            appender.append_row(duck::params![
+                county.code,
+                county.name,
+                county.official_name,
+                county.position.lat,
+                county.position.lon
+            ])?;
+        }
+        appender.flush()?;
+
+        let mut appender = self.conn.appender("municipalities")?;
+        for municipality in gazetteer::municipalities() {
+            appender.append_row(duck::params![
+                municipality.code,
+                municipality.name,
+                municipality.county_code,
+                municipality.position.lat,
+                municipality.position.lon
+            ])?;
+        }
+        appender.flush()?;
+
+        Ok(())
+    }
+
+    /// Add `county_code`, `county`, `municipality_code` and `municipality`
+    /// columns to `table` with the gazetteer's canonical names, so that
+    /// "Stockholms Län" and "Stockholm" end up as the same county
+    pub(crate) fn add_normalized_locations(&self, table: &str) -> Result<()> {
+        self.create_company_places(table)?;
+
+        self.conn.execute_batch(&format!(
+            r#"
+            ALTER TABLE {table} ADD COLUMN county_code VARCHAR;
+            ALTER TABLE {table} ADD COLUMN county VARCHAR;
+            ALTER TABLE {table} ADD COLUMN municipality_code VARCHAR;
+            ALTER TABLE {table} ADD COLUMN municipality VARCHAR;
+            UPDATE {table}
+            SET county_code = company_places.county_code,
+                county = company_places.county,
+                municipality_code = company_places.municipality_code,
+                municipality = company_places.municipality
+            FROM company_places
+            WHERE {table}.company_id = company_places.company_id;
+            DROP TABLE company_places;
+            "#
+        ))?;
+
+        Ok(())
+    }
+
+    /// `company_places` with the gazetteer's county and municipality of every
+    /// company in `relation`
+    fn create_company_places(&self, relation: &str) -> Result<()> {
+        let locations = self.query_all(
+            &format!(
+                r#"
+            SELECT company_id, location.county, location.municipality
+            FROM {}
+            WHERE location IS NOT NULL
+            "#,
+                relation
+            ),
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            },
+        )?;
+
+        self.conn.execute_batch(
+            r#"
+            DROP TABLE IF EXISTS company_places;
+            CREATE TABLE company_places (
+                company_id BIGINT,
+                county_code VARCHAR,
+                county VARCHAR,
+                municipality_code VARCHAR,
+                municipality VARCHAR
+            );
+            "#,
+        )?;
+
+        let mut unknown_counties = 0;
+        let mut unknown_municipalities = 0;
+        let mut mismatched_counties = 0;
+        {
+            let mut appender = self.conn.appender("company_places")?;
+            for (company_id, raw_county, raw_municipality) in locations {
+                let raw_county = raw_county.as_deref().filter(|name| !name.is_empty());
+                let raw_municipality = raw_municipality.as_deref().filter(|name| !name.is_empty());
+                let (county, municipality) =
+                    gazetteer::normalize_location(raw_county, raw_municipality);
+
+                if raw_municipality.is_some() && municipality.is_none() {
+                    unknown_municipalities += 1;
+                }
+                match (raw_county, county) {
+                    (Some(_), None) => unknown_counties += 1,
+                    (Some(raw), Some(county))
+                        if gazetteer::find_county(raw).is_some_and(|given| given != county) =>
+                    {
+                        mismatched_counties += 1
+                    }
+                    _ => {}
+                }
+
+                if county.is_some() {
+                    appender.append_row(duck::params![
+                        company_id,
+                        county.map(|county| county.code),
+                        county.map(|county| county.name),
+                        municipality.map(|municipality| municipality.code),
+                        municipality.map(|municipality| municipality.name)
+                    ])?;
+                }
+            }
+            appender.flush()?;
+        }
+        if unknown_counties > 0 || unknown_municipalities > 0 || mismatched_counties > 0 {
+            tracing::warn!(
+                unknown_counties,
+                unknown_municipalities,
+                mismatched_counties,
+                "Company locations that do not match the gazetteer"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Add `lat`/`lon` columns to `table`, converted to WGS84 from
+    /// `location.coordinates` in whatever grid it was recorded in
+    pub(crate) fn add_wgs84_coordinates(&self, table: &str) -> Result<()> {
+        self.create_company_coordinates(table)?;
+
+        self.conn.execute_batch(&format!(
+            r#"
+            ALTER TABLE {table} ADD COLUMN lat DOUBLE;
+            ALTER TABLE {table} ADD COLUMN lon DOUBLE;
+            UPDATE {table}
+            SET lat = company_coordinates.lat, lon = company_coordinates.lon
+            FROM company_coordinates
+            WHERE {table}.company_id = company_coordinates.company_id;
+            DROP TABLE company_coordinates;
+            "#
+        ))?;
+
+        Ok(())
+    }
+
+    /// `company_coordinates` with the WGS84 position of every company in
+    /// `relation` that has coordinates
+    fn create_company_coordinates(&self, relation: &str) -> Result<()> {
+        let coordinates = self.query_all(
+            &format!(
+                r#"
+            SELECT
+                company_id,
+                location.coordinates.XCoordinate,
+                location.coordinates.YCoordinate,
+                location.coordinates.coordinateSystem
+            FROM {}
+            WHERE location.coordinates.XCoordinate IS NOT NULL
+              AND location.coordinates.YCoordinate IS NOT NULL
+            "#,
+                relation
+            ),
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, f64>(1)?,
+                    row.get::<_, f64>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            },
+        )?;
+
+        self.conn.execute_batch(
+            r#"
+            DROP TABLE IF EXISTS company_coordinates;
+            CREATE TABLE company_coordinates (company_id BIGINT, lat DOUBLE, lon DOUBLE);
+            "#,
+        )?;
+
+        let mut skipped = 0;
+        {
+            let mut appender = self.conn.appender("company_coordinates")?;
+            for (company_id, x, y, system) in coordinates {
+                let position = system
+                    .as_deref()
+                    .and_then(geo::CoordinateSystem::parse)
+                    .and_then(|system| geo::to_wgs84(system, x, y));
+                match position {
+                    Some(position) => appender.append_row(duck::params![
+                        company_id,
+                        position.lat,
+                        position.lon
+                    ])?,
+                    None => skipped += 1,
+                }
+            }
+            appender.flush()?;
+        }
+        if skipped > 0 {
+            tracing::warn!(
+                skipped,
+                "Skipped coordinates with an unknown coordinate system or out of range"
+            );
+        }
 
         Ok(())
     }
@@ -170,7 +774,7 @@ impl DuckDB {
 
         let mut results = Vec::new();
         while let Some(row) = rows.next()? {
-            results.push(row_mapper(&row)?);
+            results.push(row_mapper(row)?);
         }
         Ok(results)
     }
@@ -186,12 +790,18 @@ impl DuckDB {
         let mut rows = stmt.query([])?;
 
         match rows.next()? {
-            Some(row) => Ok(Some(row_mapper(&row)?)),
+            Some(row) => Ok(Some(row_mapper(row)?)),
             None => Ok(None),
         }
     }
 
     pub fn query_all_json(&self, sql: &str) -> Result<String> {
+        let value = self.query_all_value(sql)?;
+        serde_json::to_string_pretty(&value).context("Failed to format JSON")
+    }
+
+    /// Query all results as a JSON array value, one object per row
+    pub fn query_all_value(&self, sql: &str) -> Result<Value> {
         let json_sql = format!(
             "SELECT COALESCE(json_group_array(to_json(row_data)), '[]') FROM ({}) as row_data",
             sql.trim_end_matches([';', '\n']).trim()
@@ -205,8 +815,7 @@ impl DuckDB {
         let result: String = stmt
             .query_row([], |row| row.get(0))
             .with_context(|| format!("Failed to execute JSON query: {}", json_sql))?;
-        let value: Value = serde_json::from_str(&result).context("Failed to parse JSON result")?;
-        serde_json::to_string_pretty(&value).context("Failed to format JSON")
+        serde_json::from_str(&result).context("Failed to parse JSON result")
     }
 
     /// Query all results as JSON - same as query_all_json since no normalization
@@ -215,23 +824,266 @@ impl DuckDB {
     }
 }
 
+/// Company columns of hello_nest that ingest computes from `financial_data`.
+/// A merge recomputes them instead of comparing them.
+const FINANCIAL_SUMMARY_COLUMNS: [&str; 5] = [
+    "latest_financial_year",
+    "latest_financials",
+    "revenue_cagr_3y",
+    "employee_change_3y",
+    "profitable_years_streak",
+];
+
+const CHANGE_LOG_TABLE_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS change_log (
+        changed_at TIMESTAMP,
+        company_id BIGINT,
+        change VARCHAR,
+        changed_columns VARCHAR[],
+        added_years BIGINT[],
+        source VARCHAR
+    );
+"#;
+
+/// Companies inserted, updated and left unchanged by a delta
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct UpdateSummary {
+    pub inserted: i64,
+    pub updated: i64,
+    pub unchanged: i64,
+}
+
+/// The cleaned hello_nest company columns read from `source`, which has the
+/// columns of `hello_nest.parquet` and optionally a `country` column or
+/// partition (see [`country::country_sql`]). Every year of `financial_data` gets the
+/// derived metrics and the same `FINANCIAL_METRICS_BASE` type.
+fn companies_select_sql(source: &str) -> String {
+    format!(
+        r#"
+        SELECT
+            company_id,
+            name AS company_name,
+            organization_number,
+            {} AS country,
+            {} AS currency,
+            company_type,
+            company_purpose,
+            CASE
+                WHEN established_date IS NULL OR established_date = '' THEN NULL
+                ELSE TRY_CAST(established_date AS DATE)
+            END AS established_date,
+            foundation_year,
+            registered_for_payroll_tax,
+            homepage,
+            postal_address,
+            visitor_address,
+            CASE
+                WHEN nace_categories IS NULL OR nace_categories = '' OR nace_categories = '[]' OR nace_categories = 'null' THEN NULL
+                ELSE nace_categories
+            END AS nace_categories,
+            CASE
+                WHEN location IS NULL OR location = '' OR location = '{{}}' THEN NULL
+                ELSE STRUCT_PACK(
+                    county := json_extract_string(location, '$.county'),
+                    countryPart := json_extract_string(location, '$.countryPart'),
+                    municipality := json_extract_string(location, '$.municipality'),
+                    coordinates := CASE
+                        WHEN json_extract(location, '$.coordinates') IS NULL THEN NULL
+                        ELSE STRUCT_PACK(
+                            XCoordinate := CAST(json_extract(location, '$.coordinates[0].XCoordinate') AS DOUBLE),
+                            YCoordinate := CAST(json_extract(location, '$.coordinates[0].YCoordinate') AS DOUBLE),
+                            coordinateSystem := json_extract_string(location, '$.coordinates[0].coordinateSystem')
+                        )
+                    END
+                )
+            END AS location,
+            "financiaL_data" AS financial_data,
+            {} AS latest_financial_year,
+            {} AS latest_financials,
+            {} AS revenue_cagr_3y,
+            {} AS employee_change_3y,
+            {} AS profitable_years_streak
+        FROM (SELECT * REPLACE ({} AS "financiaL_data") FROM {})
+        "#,
+        country::country_sql(),
+        country::currency_sql(&format!("({})", country::country_sql())),
+        metrics::latest_financial_year_sql(),
+        metrics::latest_financials_sql(),
+        metrics::revenue_cagr_3y_sql(),
+        metrics::employee_change_3y_sql(),
+        metrics::profitable_years_streak_sql(),
+        metrics::canonical_financial_data_sql("\"financiaL_data\""),
+        country::with_country_column_sql(source)
+    )
+}
+
+/// Relation reading a delta file. CSV deltas carry `financiaL_data` as JSON.
+fn delta_source_sql(path: &str) -> Result<String> {
+    let quoted = path.replace('\'', "''");
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+    match extension.as_deref() {
+        Some("parquet") => Ok(format!("read_parquet('{}')", quoted)),
+        Some("csv") => Ok(format!(
+            r#"(SELECT * REPLACE (json_transform("financiaL_data", '{}') AS "financiaL_data") FROM read_csv('{}', header = true))"#,
+            metrics::financial_data_json_structure(),
+            quoted
+        )),
+        _ => anyhow::bail!("Unsupported delta file {}: expected .parquet or .csv", path),
+    }
+}
+
+/// `company_updates` joined to hello_nest: the update's columns with
+/// `financial_data` merged year by year, plus `is_new`, the `changed_columns`
+/// among `columns` and the `added_years`
+fn merge_select_sql(columns: &[String]) -> String {
+    let merged_years: Vec<String> = metrics::FINANCIAL_YEARS
+        .iter()
+        .map(|year| {
+            format!(
+                "\"{year}\" := coalesce(u.financial_data['{year}'], h.financial_data['{year}'])"
+            )
+        })
+        .collect();
+    let merged_financial_data = format!("STRUCT_PACK({})", merged_years.join(", "));
+
+    let changes: Vec<String> = columns
+        .iter()
+        .filter(|column| !FINANCIAL_SUMMARY_COLUMNS.contains(&column.as_str()))
+        .filter(|column| column.as_str() != "company_id")
+        .map(|column| {
+            let new = if column == "financial_data" {
+                merged_financial_data.clone()
+            } else {
+                format!("u.{}", column)
+            };
+            format!(
+                "CASE WHEN {} IS DISTINCT FROM h.{} THEN '{}' END",
+                new, column, column
+            )
+        })
+        .collect();
+    let added_years: Vec<String> = metrics::FINANCIAL_YEARS
+        .iter()
+        .map(|year| {
+            format!(
+                "CASE WHEN h.financial_data['{year}'] IS NULL AND u.financial_data['{year}'] IS NOT NULL THEN {year} END"
+            )
+        })
+        .collect();
+
+    format!(
+        "SELECT u.* REPLACE ({} AS financial_data), \
+         h.company_id IS NULL AS is_new, \
+         CASE WHEN h.company_id IS NULL THEN []::VARCHAR[] ELSE list_filter([{}], c -> c IS NOT NULL) END AS changed_columns, \
+         list_filter([{}], y -> y IS NOT NULL)::BIGINT[] AS added_years \
+         FROM company_updates u LEFT JOIN hello_nest h ON h.company_id = u.company_id",
+        merged_financial_data,
+        changes.join(", "),
+        added_years.join(", ")
+    )
+}
+
+/// Fixtures for the tests of modules that work on a database
 #[cfg(test)]
-mod tests {
+pub(crate) mod test_support {
     use super::*;
     use std::fs;
 
-    fn create_test_db(test_name: &str) -> Result<DuckDB> {
+    /// A new database file `/tmp/test_duck_<test_name>.db`, without the
+    /// settings and macros of [`DuckDB::new`]
+    pub(crate) fn create_test_db(test_name: &str) -> Result<DuckDB> {
         let db_path = format!("/tmp/test_duck_{}.db", test_name);
         let _ = fs::remove_file(&db_path);
         let conn = Connection::open(&db_path)?;
-        Ok(DuckDB { conn })
+        Ok(DuckDB {
+            conn,
+            source: Source::default(),
+            remote: RemoteSettings {
+                http_timeout: Duration::from_secs(60),
+                http_keep_alive: true,
+                http_retries: 3,
+                s3_uploader_thread_limit: 64,
+                s3: S3Config::default(),
+            },
+        })
     }
 
-    fn cleanup_test_db(test_name: &str) {
+    /// Remove the file of [`create_test_db`]
+    pub(crate) fn cleanup_test_db(test_name: &str) {
         let db_path = format!("/tmp/test_duck_{}.db", test_name);
         let _ = fs::remove_file(&db_path);
     }
 
+    /// Stands in for a metric in test values to give a year a struct whose
+    /// metrics are all NULL, as the converter writes some years without accounts
+    pub(crate) const EMPTY_YEAR: &str = "(no accounts)";
+
+    /// Source `financial_data` with every year and reported metric, NULL except
+    /// for `values` (year, metric, value). Years without values are NULL structs.
+    pub(crate) fn financial_data_sql(values: &[(i64, &str, f64)]) -> String {
+        let years: Vec<String> = metrics::FINANCIAL_YEARS
+            .iter()
+            .map(|year| {
+                let fields: Vec<String> = metrics::FINANCIAL_METRICS
+                    .iter()
+                    .map(|metric| {
+                        let value = values
+                            .iter()
+                            .find(|(y, m, _)| y == year && m == metric)
+                            .map_or("NULL".to_string(), |(_, _, value)| value.to_string());
+                        format!("\"{}\" := CAST({} AS DOUBLE)", metric, value)
+                    })
+                    .collect();
+                let year_struct = format!("STRUCT_PACK({})", fields.join(", "));
+                let reported = values.iter().any(|(y, _, _)| y == year);
+                format!(
+                    "\"{}\" := CASE WHEN {} THEN {} END",
+                    year, reported, year_struct
+                )
+            })
+            .collect();
+        format!("STRUCT_PACK({})", years.join(", "))
+    }
+
+    /// `(company_id, company_name, financial values)` of a test company
+    pub(crate) type TestCompany<'a> = (i64, &'a str, &'a [(i64, &'a str, f64)]);
+
+    /// `SELECT` of test companies with the financial summary columns ingest
+    /// computes
+    pub(crate) fn companies_sql(companies: &[TestCompany]) -> String {
+        let rows: Vec<String> = companies
+            .iter()
+            .map(|(company_id, company_name, values)| {
+                format!(
+                    "SELECT {} AS company_id, '{}' AS company_name, {} AS financial_data",
+                    company_id,
+                    company_name,
+                    derived::financial_data_with_derived_sql(&financial_data_sql(values))
+                )
+            })
+            .collect();
+        format!(
+            "SELECT *, {} AS latest_financial_year, {} AS latest_financials, {} AS revenue_cagr_3y, \
+             {} AS employee_change_3y, {} AS profitable_years_streak FROM ({})",
+            metrics::latest_financial_year_sql(),
+            metrics::latest_financials_sql(),
+            metrics::revenue_cagr_3y_sql(),
+            metrics::employee_change_3y_sql(),
+            metrics::profitable_years_streak_sql(),
+            rows.join(" UNION ALL ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::*;
+    use super::*;
+    use std::fs;
+
     #[tokio::test]
     async fn test_duckdb_connection() -> Result<()> {
         let db = create_test_db("connection")?;
@@ -250,6 +1102,25 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_try_clone_outlives_original() -> Result<()> {
+        let db = create_test_db("clone")?;
+        db.execute("CREATE TABLE t AS SELECT 42 AS answer")?;
+
+        let clone = db.try_clone()?;
+        drop(db);
+
+        let answer = clone.query_one("SELECT answer FROM t", |row| Ok(row.get::<_, i64>(0)?))?;
+        assert_eq!(answer, Some(42));
+        let rate = clone.query_one("SELECT fx_rate(2024, 'EUR', 'EUR')", |row| {
+            Ok(row.get::<_, f64>(0)?)
+        })?;
+        assert_eq!(rate, Some(1.0));
+
+        cleanup_test_db("clone");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_query_one() -> Result<()> {
         let db = create_test_db("query_one")?;
@@ -299,10 +1170,248 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_add_wgs84_coordinates() -> Result<()> {
+        let db = create_test_db("wgs84")?;
+
+        db.execute(
+            "CREATE TABLE hello_nest (company_id BIGINT, location STRUCT(coordinates STRUCT(XCoordinate DOUBLE, YCoordinate DOUBLE, coordinateSystem VARCHAR)))",
+        )?;
+        db.execute(
+            r#"INSERT INTO hello_nest VALUES
+                (1, {'coordinates': {'XCoordinate': 18.0686, 'YCoordinate': 59.3293, 'coordinateSystem': 'EPSG:4326'}}),
+                (2, {'coordinates': {'XCoordinate': 6580822, 'YCoordinate': 674032, 'coordinateSystem': 'SWEREF 99 TM'}}),
+                (3, {'coordinates': {'XCoordinate': 1, 'YCoordinate': 2, 'coordinateSystem': 'EPSG:3857'}}),
+                (4, NULL)"#,
+        )?;
+
+        db.add_wgs84_coordinates("hello_nest")?;
+
+        let positions = db.query_all(
+            "SELECT company_id, lat, lon FROM hello_nest ORDER BY company_id",
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<f64>>(1)?,
+                    row.get::<_, Option<f64>>(2)?,
+                ))
+            },
+        )?;
+
+        assert_eq!(positions[0], (1, Some(59.3293), Some(18.0686)));
+        let (_, lat, lon) = positions[1];
+        assert!((lat.unwrap() - 59.3293).abs() < 0.01 && (lon.unwrap() - 18.0686).abs() < 0.01);
+        assert_eq!(positions[2], (3, None, None));
+        assert_eq!(positions[3], (4, None, None));
+
+        cleanup_test_db("wgs84");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_normalized_locations() -> Result<()> {
+        let db = create_test_db("places")?;
+
+        db.execute(
+            "CREATE TABLE hello_nest (company_id BIGINT, location STRUCT(county VARCHAR, municipality VARCHAR))",
+        )?;
+        db.execute(
+            r#"INSERT INTO hello_nest VALUES
+                (1, {'county': 'Stockholms Län', 'municipality': 'Solna'}),
+                (2, {'county': 'Stockholm', 'municipality': ''}),
+                (3, {'county': 'Örebro Län', 'municipality': 'Motala'}),
+                (4, {'county': 'Atlantis', 'municipality': NULL}),
+                (5, NULL)"#,
+        )?;
+
+        db.create_gazetteer_tables()?;
+        db.add_normalized_locations("hello_nest")?;
+
+        let places = db.query_all(
+            "SELECT concat_ws('/', county_code, county, municipality_code, municipality) FROM hello_nest ORDER BY company_id",
+            |row| Ok(row.get::<_, String>(0)?),
+        )?;
+
+        assert_eq!(
+            places,
+            vec![
+                "01/Stockholm/0184/Solna",
+                "01/Stockholm",
+                "05/Östergötland/0583/Motala",
+                "",
+                "",
+            ]
+        );
+
+        let municipality_count = db.query_one("SELECT COUNT(*) FROM municipalities", |row| {
+            Ok(row.get::<_, i64>(0)?)
+        })?;
+        assert_eq!(municipality_count, Some(290));
+
+        cleanup_test_db("places");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_company_updates() -> Result<()> {
+        let db = create_test_db("merge")?;
+        let revenue = metrics::REVENUE_METRIC;
+
+        db.execute(&format!(
+            "CREATE TABLE hello_nest AS {}",
+            companies_sql(&[
+                (1, "Alpha AB", &[(2023, revenue, 100.0)]),
+                (2, "Beta AB", &[(2024, revenue, 50.0)]),
+            ])
+        ))?;
+        // Alpha reports 2024, Beta is unchanged and Gamma is new
+        db.execute(&format!(
+            "CREATE TABLE company_updates AS {}",
+            companies_sql(&[
+                (1, "Alpha AB", &[(2024, revenue, 120.0)]),
+                (2, "Beta AB", &[(2024, revenue, 50.0)]),
+                (3, "Gamma AB", &[(2024, revenue, 10.0)]),
+            ])
+        ))?;
+
+        let summary = db.merge_company_updates("delta.parquet")?;
+        assert_eq!(
+            summary,
+            UpdateSummary {
+                inserted: 1,
+                updated: 1,
+                unchanged: 1
+            }
+        );
+
+        let companies = db.query_all(
+            &format!(
+                "SELECT company_id, {}, {}, latest_financial_year FROM hello_nest ORDER BY company_id",
+                metrics::metric_sql(revenue, 2023),
+                metrics::metric_sql(revenue, 2024)
+            ),
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<f64>>(1)?,
+                    row.get::<_, Option<f64>>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            },
+        )?;
+        assert_eq!(
+            companies,
+            vec![
+                (1, Some(100.0), Some(120.0), 2024),
+                (2, None, Some(50.0), 2024),
+                (3, None, Some(10.0), 2024),
+            ]
+        );
+
+        let changes = db.query_all(
+            "SELECT company_id, change, coalesce(array_to_string(changed_columns, ','), ''), array_to_string(added_years, ','), source \
+             FROM change_log ORDER BY company_id",
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            },
+        )?;
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    1,
+                    "update".to_string(),
+                    "financial_data".to_string(),
+                    "2024".to_string(),
+                    "delta.parquet".to_string()
+                ),
+                (
+                    3,
+                    "insert".to_string(),
+                    String::new(),
+                    "2024".to_string(),
+                    "delta.parquet".to_string()
+                ),
+            ]
+        );
+
+        // Merging the same delta again changes nothing
+        let summary = db.merge_company_updates("delta.parquet")?;
+        assert_eq!(summary.unchanged, 3);
+        let logged = db.query_one("SELECT count(*) FROM change_log", |row| {
+            Ok(row.get::<_, i64>(0)?)
+        })?;
+        assert_eq!(logged, Some(2));
+
+        cleanup_test_db("merge");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fx_rate_macro() -> Result<()> {
+        let db = create_test_db("fx_rate")?;
+        db.register_macros();
+
+        let rates = db.query_all(
+            "SELECT fx_rate(2024, 'NOK', 'SEK'), fx_rate(2024, 'SEK', 'SEK'), fx_rate(2015, 'SEK', 'EUR')",
+            |row| {
+                Ok((
+                    row.get::<_, Option<f64>>(0)?,
+                    row.get::<_, Option<f64>>(1)?,
+                    row.get::<_, Option<f64>>(2)?,
+                ))
+            },
+        )?;
+        let (nok_to_sek, sek_to_sek, missing) = rates[0];
+        assert!((nok_to_sek.unwrap() - fx::rate(2024, "NOK", "SEK").unwrap()).abs() < 1e-12);
+        assert_eq!(sek_to_sek, Some(1.0));
+        assert_eq!(missing, None);
+
+        // Column arguments bind to the caller's table, not the macro's rates
+        let converted = db.query_all(
+            "SELECT amount * fx_rate(year, currency, 'SEK') \
+             FROM (VALUES (2024, 'NOK', 100.0), (2024, 'SEK', 100.0)) AS t(year, currency, amount) \
+             ORDER BY currency",
+            |row| Ok(row.get::<_, f64>(0)?),
+        )?;
+        assert!((converted[0] - 100.0 * nok_to_sek.unwrap()).abs() < 1e-9);
+        assert_eq!(converted[1], 100.0);
+
+        cleanup_test_db("fx_rate");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_distance_macro() -> Result<()> {
+        let db = create_test_db("distance")?;
+        db.register_macros();
+
+        // Stockholm to Gothenburg is just under 400 km as the crow flies
+        let distance = db.query_one(
+            "SELECT distance_km(59.3293, 18.0686, 57.7089, 11.9746)",
+            |row| Ok(row.get::<_, f64>(0)?),
+        )?;
+        let distance = distance.unwrap();
+        assert!((390.0..400.0).contains(&distance), "{}", distance);
+
+        cleanup_test_db("distance");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_access_mode_configuration() -> Result<()> {
-        let mut config = DuckDbConfig::default();
-        config.access_mode = AccessMode::ReadWrite;
+        let config = DuckDbConfig {
+            access_mode: AccessMode::ReadWrite,
+            db_filename: "duck.db".to_string(),
+            temp_directory: std::env::temp_dir(),
+            ..Default::default()
+        };
         let db_rw = DuckDB::new(config).await?;
         db_rw.execute("DROP TABLE IF EXISTS test_access")?;
         db_rw.execute("CREATE TABLE test_access (id INTEGER, name VARCHAR)")?;
@@ -315,9 +1424,12 @@ mod tests {
         assert_eq!(results.len(), 1);
         assert_eq!(results[0], (1, "test".to_string()));
 
-        let mut custom_config = DuckDbConfig::default();
-        custom_config.access_mode = AccessMode::ReadWrite;
-        custom_config.db_filename = "custom_test.db".to_string();
+        let custom_config = DuckDbConfig {
+            access_mode: AccessMode::ReadWrite,
+            db_filename: "custom_test.db".to_string(),
+            temp_directory: std::env::temp_dir(),
+            ..Default::default()
+        };
         let db_custom = DuckDB::new(custom_config).await?;
         db_custom.execute("DROP TABLE IF EXISTS test_custom")?;
         db_custom.execute("CREATE TABLE test_custom (id INTEGER)")?;
@@ -337,4 +1449,141 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_local_source_needs_no_secret() -> Result<()> {
+        let dir = std::env::temp_dir().join("test_duck_local_source");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let parquet = dir.join("companies.parquet");
+
+        let config = DuckDbConfig {
+            access_mode: AccessMode::ReadWrite,
+            temp_directory: dir.clone(),
+            source: Source::parse(&parquet.to_string_lossy()),
+            ..Default::default()
+        };
+        let db = DuckDB::new(config).await?;
+        db.execute(&format!(
+            "COPY (SELECT 1 AS company_id, 'Alpha AB' AS name) TO '{}' (FORMAT parquet)",
+            parquet.display()
+        ))?;
+
+        let secrets = db.query_one("SELECT count(*) FROM duckdb_secrets()", |row| {
+            Ok(row.get::<_, i64>(0)?)
+        })?;
+        assert_eq!(secrets, Some(0));
+        let schema = db.inspect_parquet_schema()?;
+        assert!(schema.contains("company_id"));
+        assert!(schema.contains("name"));
+
+        drop(db);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_view_mode_matches_table() -> Result<()> {
+        let dir = std::env::temp_dir().join("test_duck_view_mode");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let partitions = dir.join("companies");
+
+        let mut table = create_test_db("view_mode_table")?;
+        table.execute(&format!(
+            "COPY (SELECT *, company_id % 3 AS part FROM read_parquet('{}') ORDER BY company_id LIMIT 300) \
+             TO '{}' (FORMAT parquet, PARTITION_BY (part))",
+            source::DEFAULT_SOURCE,
+            partitions.display()
+        ))?;
+        let glob = Source::parse(&partitions.join("*/*.parquet").to_string_lossy());
+        table.source = glob.clone();
+        table.create_hello_nest_table()?;
+        assert!(!table.is_view_mode()?);
+
+        let mut view = create_test_db("view_mode_view")?;
+        view.source = glob;
+        view.create_hello_nest_view()?;
+        assert!(view.is_view_mode()?);
+
+        let companies_sql = "SELECT * EXCLUDE (financial_data, latest_financials) \
+                             FROM hello_nest ORDER BY company_id";
+        assert_eq!(
+            table.query_all_json(companies_sql)?,
+            view.query_all_json(companies_sql)?
+        );
+        // The source has INTEGER and missing fields in early years
+        let years = table.query_one(
+            "SELECT count(*) FROM (SELECT financial_data['2016'] AS f FROM hello_nest \
+             UNION ALL SELECT financial_data['2024'] FROM hello_nest) WHERE f IS NOT NULL",
+            |row| Ok(row.get::<_, i64>(0)?),
+        )?;
+        assert!(years.unwrap_or(0) > 0);
+        let minority_interests = table.query_one(
+            "SELECT typeof(financial_data['2016']['Minority interests']) FROM hello_nest LIMIT 1",
+            |row| Ok(row.get::<_, String>(0)?),
+        )?;
+        assert_eq!(minority_interests.as_deref(), Some("DOUBLE"));
+
+        let metadata = dataset::current(&view)?.unwrap();
+        assert_eq!(metadata.sources.len(), 3);
+        assert_eq!(metadata.row_counts["hello_nest"], 300);
+        assert!(view.update_hello_nest_table("delta.parquet").is_err());
+
+        cleanup_test_db("view_mode_table");
+        cleanup_test_db("view_mode_view");
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_country_from_source_partitions() -> Result<()> {
+        let dir = std::env::temp_dir().join("test_duck_country");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let partitions = dir.join("companies");
+
+        let mut db = create_test_db("country")?;
+        db.execute(&format!(
+            "COPY (SELECT *, ['SE', 'Norge', 'dk'][company_id % 3 + 1] AS country \
+             FROM read_parquet('{}') ORDER BY company_id LIMIT 30) \
+             TO '{}' (FORMAT parquet, PARTITION_BY (country))",
+            source::DEFAULT_SOURCE,
+            partitions.display()
+        ))?;
+        db.source = Source::parse(&partitions.join("*/*.parquet").to_string_lossy());
+        db.create_hello_nest_table()?;
+
+        let countries = db.query_all(
+            "SELECT country, currency, bool_and(company_id % 3 = list_position(['SE', 'NO', 'DK'], country) - 1) \
+             FROM hello_nest GROUP BY ALL ORDER BY country",
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, bool>(2)?,
+                ))
+            },
+        )?;
+        assert_eq!(
+            countries,
+            vec![
+                ("DK".to_string(), "DKK".to_string(), true),
+                ("NO".to_string(), "NOK".to_string(), true),
+                ("SE".to_string(), "SEK".to_string(), true),
+            ]
+        );
+
+        // Without a country the source is the Swedish dataset
+        db.source = Source::parse(source::DEFAULT_SOURCE);
+        db.create_hello_nest_table()?;
+        let countries = db.query_all("SELECT DISTINCT country FROM hello_nest", |row| {
+            Ok(row.get::<_, String>(0)?)
+        })?;
+        assert_eq!(countries, vec!["SE".to_string()]);
+
+        cleanup_test_db("country");
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
 }
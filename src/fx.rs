@@ -0,0 +1,130 @@
+//! Yearly average exchange rates for comparing financial values across the
+//! Nordic currencies.
+//!
+//! Rates are annual averages of the ECB euro reference rates, bundled from
+//! `raw/fx_rates.csv` as units of currency per EUR. A value from a given
+//! financial year is converted with that year's average rate.
+//!
+//! SQL conversions go through the `fx_rate(year, from_currency, to_currency)`
+//! macro that [`crate::duckdb::DuckDB`] registers on every connection. The
+//! macro carries the rates inline, so it works without the `fx_rates` table.
+
+use std::sync::LazyLock;
+
+const FX_RATES_CSV: &str = include_str!("../raw/fx_rates.csv");
+
+/// Currencies that values can be converted between
+pub const CURRENCIES: [&str; 4] = ["SEK", "NOK", "DKK", "EUR"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FxRate {
+    pub year: i64,
+    pub currency: &'static str,
+    /// Units of `currency` per EUR
+    pub per_eur: f64,
+}
+
+static FX_RATES: LazyLock<Vec<FxRate>> = LazyLock::new(|| {
+    FX_RATES_CSV
+        .lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let fields: Vec<&'static str> = line.split(',').map(str::trim).collect();
+            let &[year, currency, per_eur] = fields.as_slice() else {
+                panic!("Malformed row in raw/fx_rates.csv: {:?}", line);
+            };
+            FxRate {
+                year: year.parse().expect("bundled FX year is a number"),
+                currency,
+                per_eur: per_eur.parse().expect("bundled FX rate is a number"),
+            }
+        })
+        .collect()
+});
+
+pub fn fx_rates() -> &'static [FxRate] {
+    &FX_RATES
+}
+
+/// Canonical code of a supported currency, e.g. "eur" -> "EUR"
+pub fn parse_currency(code: &str) -> Option<&'static str> {
+    let code = code.trim().to_uppercase();
+    CURRENCIES
+        .iter()
+        .copied()
+        .find(|currency| *currency == code)
+}
+
+/// Units of `to` per unit of `from`, averaged over `year`
+pub fn rate(year: i64, from: &str, to: &str) -> Option<f64> {
+    let per_eur = |currency: &str| {
+        fx_rates()
+            .iter()
+            .find(|rate| rate.year == year && rate.currency == currency)
+            .map(|rate| rate.per_eur)
+    };
+    Some(per_eur(to)? / per_eur(from)?)
+}
+
+/// Body of the `fx_rate(rate_year, from_currency, to_currency)` macro. NULL
+/// when either currency has no rate for the year.
+///
+/// The arguments are substituted into the subquery as written, so its columns
+/// are prefixed to keep e.g. a `currency` argument bound to the caller's table.
+pub fn fx_rate_macro() -> String {
+    let rows: Vec<String> = fx_rates()
+        .iter()
+        .map(|rate| format!("({}, '{}', {})", rate.year, rate.currency, rate.per_eur))
+        .collect();
+    let values = rows.join(", ");
+    format!(
+        "(SELECT to_rate.fx_per_eur / from_rate.fx_per_eur \
+         FROM (VALUES {values}) AS from_rate(fx_year, fx_currency, fx_per_eur), \
+         (VALUES {values}) AS to_rate(fx_year, fx_currency, fx_per_eur) \
+         WHERE from_rate.fx_year = rate_year AND to_rate.fx_year = rate_year \
+         AND from_rate.fx_currency = from_currency AND to_rate.fx_currency = to_currency)"
+    )
+}
+
+/// `value` (in the company's `currency`, for financial year `year`)
+/// converted to `to`
+pub fn convert_sql(value: &str, year: &str, to: &str) -> String {
+    format!("({} * fx_rate({}, currency, '{}'))", value, year, to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics;
+
+    #[test]
+    fn test_rates_cover_financial_years() {
+        for year in metrics::FINANCIAL_YEARS {
+            for currency in CURRENCIES {
+                assert!(
+                    rate(year, currency, "EUR").is_some(),
+                    "{} {}",
+                    year,
+                    currency
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rate_crosses_through_eur() {
+        assert_eq!(rate(2024, "SEK", "SEK"), Some(1.0));
+        let nok_to_sek = rate(2024, "NOK", "SEK").unwrap();
+        assert!((nok_to_sek - 11.4325 / 11.6290).abs() < 1e-12);
+        assert!((rate(2024, "EUR", "DKK").unwrap() - 7.4589).abs() < 1e-12);
+        assert_eq!(rate(2015, "SEK", "EUR"), None);
+    }
+
+    #[test]
+    fn test_parse_currency() {
+        assert_eq!(parse_currency(" eur "), Some("EUR"));
+        assert_eq!(parse_currency("nok"), Some("NOK"));
+        assert_eq!(parse_currency("USD"), None);
+    }
+}
@@ -0,0 +1,266 @@
+//! Bundled gazetteer of Swedish counties (län) and municipalities (kommuner).
+//!
+//! The data lives in `raw/counties.csv` and `raw/municipalities.csv` and is
+//! compiled into the binary. Positions are the administrative seat of each
+//! county or municipality, which is what people usually mean by "near
+//! Uppsala". Names are matched with [`fold_name`], so "Stockholms Län",
+//! "stockholms lan" and "Stockholms län" all find the same county.
+
+use std::sync::LazyLock;
+
+use crate::geo::LatLon;
+
+const COUNTIES_CSV: &str = include_str!("../raw/counties.csv");
+const MUNICIPALITIES_CSV: &str = include_str!("../raw/municipalities.csv");
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct County {
+    /// Two-digit county code (länskod), e.g. "01"
+    pub code: &'static str,
+    /// Short name, e.g. "Stockholm"
+    pub name: &'static str,
+    /// Official name, e.g. "Stockholms län"
+    pub official_name: &'static str,
+    pub position: LatLon,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Municipality {
+    /// Four-digit municipality code (kommunkod), e.g. "0180"
+    pub code: &'static str,
+    pub name: &'static str,
+    pub county_code: &'static str,
+    pub position: LatLon,
+}
+
+impl Municipality {
+    pub fn county(&self) -> &'static County {
+        county_by_code(self.county_code).expect("bundled municipality has a known county")
+    }
+}
+
+/// A resolved place name
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Place {
+    Municipality(&'static Municipality),
+    County(&'static County),
+}
+
+impl Place {
+    pub fn position(&self) -> LatLon {
+        match self {
+            Place::Municipality(municipality) => municipality.position,
+            Place::County(county) => county.position,
+        }
+    }
+}
+
+static COUNTIES: LazyLock<Vec<County>> = LazyLock::new(|| {
+    csv_rows(COUNTIES_CSV)
+        .map(|fields| match fields.as_slice() {
+            &[code, name, official_name, lat, lon] => County {
+                code,
+                name,
+                official_name,
+                position: parse_position(lat, lon),
+            },
+            _ => panic!("Malformed row in raw/counties.csv: {:?}", fields),
+        })
+        .collect()
+});
+
+static MUNICIPALITIES: LazyLock<Vec<Municipality>> = LazyLock::new(|| {
+    csv_rows(MUNICIPALITIES_CSV)
+        .map(|fields| match fields.as_slice() {
+            &[code, name, county_code, lat, lon] => Municipality {
+                code,
+                name,
+                county_code,
+                position: parse_position(lat, lon),
+            },
+            _ => panic!("Malformed row in raw/municipalities.csv: {:?}", fields),
+        })
+        .collect()
+});
+
+/// Data rows of a bundled CSV file. The files are plain comma separated
+/// values without quoting.
+fn csv_rows(csv: &'static str) -> impl Iterator<Item = Vec<&'static str>> {
+    csv.lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.split(',').map(str::trim).collect())
+}
+
+fn parse_position(lat: &str, lon: &str) -> LatLon {
+    LatLon {
+        lat: lat.parse().expect("bundled latitude is a number"),
+        lon: lon.parse().expect("bundled longitude is a number"),
+    }
+}
+
+pub fn counties() -> &'static [County] {
+    &COUNTIES
+}
+
+pub fn municipalities() -> &'static [Municipality] {
+    &MUNICIPALITIES
+}
+
+pub fn county_by_code(code: &str) -> Option<&'static County> {
+    counties().iter().find(|county| county.code == code)
+}
+
+/// Normalise a place name for comparison: lowercase, without diacritics,
+/// hyphens or repeated whitespace, e.g. "Malung-Sälen" -> "malung salen".
+pub fn fold_name(name: &str) -> String {
+    let folded: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'å' | 'ä' | 'á' | 'à' | 'â' => 'a',
+            'ö' | 'ø' | 'ó' | 'ò' | 'ô' => 'o',
+            'é' | 'è' | 'ê' | 'ë' => 'e',
+            'ü' | 'ú' => 'u',
+            '-' => ' ',
+            c => c,
+        })
+        .collect();
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Find a county by short name, official name or code, e.g. "Skåne",
+/// "Skåne län" or "12".
+pub fn find_county(name: &str) -> Option<&'static County> {
+    let folded = fold_name(name);
+    counties().iter().find(|county| {
+        county.code == folded
+            || fold_name(county.name) == folded
+            || fold_name(county.official_name) == folded
+    })
+}
+
+/// Find a municipality by name or code, e.g. "Göteborg", "Goteborg",
+/// "Göteborgs stad", "Uppsala kommun" or "1480".
+pub fn find_municipality(name: &str) -> Option<&'static Municipality> {
+    let folded = fold_name(name);
+    // "X kommun" / "Xs kommun" / "Xs stad" are all the municipality X
+    let without_suffix = folded
+        .strip_suffix(" kommun")
+        .or_else(|| folded.strip_suffix(" stad"));
+    municipalities().iter().find(|municipality| {
+        let name = fold_name(municipality.name);
+        municipality.code == folded
+            || name == folded
+            || without_suffix
+                .is_some_and(|base| base == name || base.strip_suffix('s') == Some(name.as_str()))
+    })
+}
+
+/// Resolve a free-form place name. Municipalities win over counties with the
+/// same name, so "Uppsala" is the city rather than the whole county.
+pub fn resolve_place(name: &str) -> Option<Place> {
+    find_municipality(name)
+        .map(Place::Municipality)
+        .or_else(|| find_county(name).map(Place::County))
+}
+
+/// Canonical county and municipality for a company's `location.county` and
+/// `location.municipality`. The municipality decides the county when both
+/// are known, since every municipality belongs to exactly one county.
+pub fn normalize_location(
+    county: Option<&str>,
+    municipality: Option<&str>,
+) -> (Option<&'static County>, Option<&'static Municipality>) {
+    let municipality = municipality.and_then(find_municipality);
+    let county = match municipality {
+        Some(municipality) => Some(municipality.county()),
+        None => county.and_then(find_county),
+    };
+    (county, municipality)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled_data_is_complete() {
+        assert_eq!(counties().len(), 21);
+        assert_eq!(municipalities().len(), 290);
+        for municipality in municipalities() {
+            assert!(county_by_code(municipality.county_code).is_some());
+            assert!(municipality.code.starts_with(municipality.county_code));
+        }
+    }
+
+    #[test]
+    fn test_find_county_variants() {
+        for name in [
+            "Stockholm",
+            "Stockholms Län",
+            "stockholms lan",
+            "Stockholms län",
+            "01",
+        ] {
+            assert_eq!(find_county(name).map(|c| c.code), Some("01"), "{}", name);
+        }
+        assert_eq!(
+            find_county("Västra Götalands län").map(|c| c.name),
+            Some("Västra Götaland")
+        );
+        assert!(find_county("Oslo").is_none());
+    }
+
+    #[test]
+    fn test_find_municipality_variants() {
+        for name in ["Göteborg", "goteborg", "Göteborgs stad", "1480"] {
+            assert_eq!(
+                find_municipality(name).map(|m| m.code),
+                Some("1480"),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            find_municipality("malung salen").map(|m| m.name),
+            Some("Malung-Sälen")
+        );
+        assert_eq!(
+            find_municipality("Uppsala kommun").map(|m| m.code),
+            Some("0380")
+        );
+    }
+
+    #[test]
+    fn test_resolve_place_prefers_municipality() {
+        assert!(matches!(
+            resolve_place("Uppsala"),
+            Some(Place::Municipality(m)) if m.code == "0380"
+        ));
+        assert!(matches!(
+            resolve_place("Uppsala län"),
+            Some(Place::County(c)) if c.code == "03"
+        ));
+        assert!(matches!(
+            resolve_place("Norrbotten"),
+            Some(Place::County(c)) if c.code == "25"
+        ));
+        assert!(resolve_place("Atlantis").is_none());
+    }
+
+    #[test]
+    fn test_normalize_location() {
+        let (county, municipality) = normalize_location(Some("Skåne Län"), Some("Malmö"));
+        assert_eq!(county.map(|c| c.name), Some("Skåne"));
+        assert_eq!(municipality.map(|m| m.code), Some("1280"));
+
+        // The municipality wins over a contradicting county
+        let (county, _) = normalize_location(Some("Örebro Län"), Some("Motala"));
+        assert_eq!(county.map(|c| c.name), Some("Östergötland"));
+
+        let (county, municipality) = normalize_location(Some("Dalarnas Län"), Some(""));
+        assert_eq!(county.map(|c| c.code), Some("20"));
+        assert!(municipality.is_none());
+    }
+}
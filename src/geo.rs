@@ -0,0 +1,363 @@
+//! Coordinate conversion from the Swedish grids used in company locations to
+//! WGS84 latitude/longitude.
+//!
+//! Grid coordinates follow the Swedish convention: X is northing and Y is
+//! easting. WGS84 coordinates are stored with X as longitude and Y as latitude.
+//! The Gauss–Krüger formulas and projection parameters are the ones published
+//! by Lantmäteriet; RT 90 is converted with its direct GRS 80 parameters, which
+//! are accurate to about a metre.
+//!
+//! Distances in SQL go through the `distance_km(lat1, lon1, lat2, lon2)` macro
+//! that [`crate::duckdb::DuckDB`] registers on every connection.
+
+use serde_json::{Map, Value, json};
+use std::f64::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoordinateSystem {
+    Wgs84,
+    Sweref99Tm,
+    Rt90,
+}
+
+impl CoordinateSystem {
+    /// Parse the `coordinateSystem` string from the source data, e.g.
+    /// "EPSG:4326", "SWEREF 99 TM" or "RT90 2.5 gon V".
+    pub fn parse(name: &str) -> Option<Self> {
+        let normalized: String = name
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_uppercase();
+
+        match normalized.as_str() {
+            "EPSG:4326" | "WGS84" => Some(CoordinateSystem::Wgs84),
+            "EPSG:3006" | "SWEREF99TM" | "SWEREF99" => Some(CoordinateSystem::Sweref99Tm),
+            "EPSG:3021" | "EPSG:2400" | "RT90" | "RT902.5GONV" => Some(CoordinateSystem::Rt90),
+            _ => None,
+        }
+    }
+
+    fn projection(self) -> Option<Projection> {
+        match self {
+            CoordinateSystem::Wgs84 => None,
+            CoordinateSystem::Sweref99Tm => Some(Projection {
+                central_meridian: 15.0,
+                scale: 0.9996,
+                false_northing: 0.0,
+                false_easting: 500_000.0,
+            }),
+            CoordinateSystem::Rt90 => Some(Projection {
+                central_meridian: 15.0 + 48.0 / 60.0 + 22.624306 / 3600.0,
+                scale: 1.00000561024,
+                false_northing: -667.711,
+                false_easting: 1_500_064.274,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatLon {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// Convert a stored coordinate pair to WGS84. Returns `None` for non-finite
+/// input or results outside the valid latitude/longitude range.
+pub fn to_wgs84(system: CoordinateSystem, x: f64, y: f64) -> Option<LatLon> {
+    if !x.is_finite() || !y.is_finite() {
+        return None;
+    }
+
+    let position = match system.projection() {
+        None => LatLon { lat: y, lon: x },
+        Some(projection) => projection.grid_to_geodetic(x, y),
+    };
+
+    let valid = (-90.0..=90.0).contains(&position.lat) && (-180.0..=180.0).contains(&position.lon);
+    valid.then_some(position)
+}
+
+/// Mean Earth radius (IUGG), used for great-circle distances
+pub const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+/// Kilometres per degree of latitude, used to pre-filter radius searches
+pub const KM_PER_DEGREE_LATITUDE: f64 = 111.195;
+
+/// Body of the `distance_km` macro using the spatial extension. Its sphere
+/// functions expect points in [latitude, longitude] axis order.
+pub const SPATIAL_DISTANCE_MACRO: &str =
+    "ST_Distance_Sphere(ST_Point(lat1, lon1), ST_Point(lat2, lon2)) / 1000";
+
+/// Body of the `distance_km` macro in plain SQL (haversine), for when the
+/// spatial extension can't be loaded.
+pub fn haversine_distance_macro() -> String {
+    format!(
+        "2 * {} * asin(sqrt(pow(sin(radians(lat2 - lat1) / 2), 2) + cos(radians(lat1)) * cos(radians(lat2)) * pow(sin(radians(lon2 - lon1) / 2), 2)))",
+        EARTH_RADIUS_KM
+    )
+}
+
+/// Distance in km from each company's `lat`/`lon` to a fixed point
+pub fn distance_km_sql(position: LatLon) -> String {
+    format!("distance_km(lat, lon, {}, {})", position.lat, position.lon)
+}
+
+pub const GEOJSON_MIME_TYPE: &str = "application/geo+json";
+
+/// Build a GeoJSON FeatureCollection of points from result rows that have
+/// `lat` and `lon` members. All other members become feature properties.
+/// Rows without a usable position are left out and counted in the second
+/// return value.
+pub fn feature_collection(rows: &[Value]) -> (Value, usize) {
+    let mut features = Vec::new();
+    let mut skipped = 0;
+
+    for row in rows {
+        let Some(object) = row.as_object() else {
+            skipped += 1;
+            continue;
+        };
+        let lat = object.get("lat").and_then(Value::as_f64);
+        let lon = object.get("lon").and_then(Value::as_f64);
+        let (Some(lat), Some(lon)) = (lat, lon) else {
+            skipped += 1;
+            continue;
+        };
+
+        let properties: Map<String, Value> = object
+            .iter()
+            .filter(|(key, _)| !matches!(key.as_str(), "lat" | "lon"))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        features.push(json!({
+            "type": "Feature",
+            // GeoJSON positions are [longitude, latitude]
+            "geometry": {"type": "Point", "coordinates": [lon, lat]},
+            "properties": properties,
+        }));
+    }
+
+    let collection = json!({
+        "type": "FeatureCollection",
+        "features": features,
+    });
+    (collection, skipped)
+}
+
+/// Transverse Mercator parameters on the GRS 80 ellipsoid
+#[derive(Debug, Clone, Copy)]
+struct Projection {
+    central_meridian: f64,
+    scale: f64,
+    false_northing: f64,
+    false_easting: f64,
+}
+
+const GRS80_AXIS: f64 = 6_378_137.0;
+const GRS80_FLATTENING: f64 = 1.0 / 298.257_222_101;
+
+impl Projection {
+    fn rectifying_radius(&self) -> f64 {
+        let n = GRS80_FLATTENING / (2.0 - GRS80_FLATTENING);
+        GRS80_AXIS / (1.0 + n) * (1.0 + n.powi(2) / 4.0 + n.powi(4) / 64.0)
+    }
+
+    fn grid_to_geodetic(&self, northing: f64, easting: f64) -> LatLon {
+        let e2 = GRS80_FLATTENING * (2.0 - GRS80_FLATTENING);
+        let n = GRS80_FLATTENING / (2.0 - GRS80_FLATTENING);
+
+        let delta = [
+            n / 2.0 - 2.0 * n.powi(2) / 3.0 + 37.0 * n.powi(3) / 96.0 - n.powi(4) / 360.0,
+            n.powi(2) / 48.0 + n.powi(3) / 15.0 - 437.0 * n.powi(4) / 1440.0,
+            17.0 * n.powi(3) / 480.0 - 37.0 * n.powi(4) / 840.0,
+            4397.0 * n.powi(4) / 161_280.0,
+        ];
+        let a_star = e2 + e2.powi(2) + e2.powi(3) + e2.powi(4);
+        let b_star = -(7.0 * e2.powi(2) + 17.0 * e2.powi(3) + 30.0 * e2.powi(4)) / 6.0;
+        let c_star = (224.0 * e2.powi(3) + 889.0 * e2.powi(4)) / 120.0;
+        let d_star = -(4279.0 * e2.powi(4)) / 1260.0;
+
+        let radius = self.scale * self.rectifying_radius();
+        let xi = (northing - self.false_northing) / radius;
+        let eta = (easting - self.false_easting) / radius;
+
+        let mut xi_prim = xi;
+        let mut eta_prim = eta;
+        for (j, d) in delta.iter().enumerate() {
+            let k = 2.0 * (j as f64 + 1.0);
+            xi_prim -= d * (k * xi).sin() * (k * eta).cosh();
+            eta_prim -= d * (k * xi).cos() * (k * eta).sinh();
+        }
+
+        let phi_star = (xi_prim.sin() / eta_prim.cosh()).asin();
+        let delta_lambda = (eta_prim.sinh() / xi_prim.cos()).atan();
+        let sin2 = phi_star.sin().powi(2);
+        let phi = phi_star
+            + phi_star.sin()
+                * phi_star.cos()
+                * (a_star + b_star * sin2 + c_star * sin2.powi(2) + d_star * sin2.powi(3));
+
+        LatLon {
+            lat: phi.to_degrees(),
+            lon: self.central_meridian + delta_lambda * 180.0 / PI,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Forward projection (geodetic to grid), only used to round-trip test the inverse.
+    fn geodetic_to_grid(projection: &Projection, position: LatLon) -> (f64, f64) {
+        let e2 = GRS80_FLATTENING * (2.0 - GRS80_FLATTENING);
+        let n = GRS80_FLATTENING / (2.0 - GRS80_FLATTENING);
+        let beta = [
+            n / 2.0 - 2.0 * n.powi(2) / 3.0 + 5.0 * n.powi(3) / 16.0 + 41.0 * n.powi(4) / 180.0,
+            13.0 * n.powi(2) / 48.0 - 3.0 * n.powi(3) / 5.0 + 557.0 * n.powi(4) / 1440.0,
+            61.0 * n.powi(3) / 240.0 - 103.0 * n.powi(4) / 140.0,
+            49561.0 * n.powi(4) / 161_280.0,
+        ];
+        let a = e2;
+        let b = (5.0 * e2.powi(2) - e2.powi(3)) / 6.0;
+        let c = (104.0 * e2.powi(3) - 45.0 * e2.powi(4)) / 120.0;
+        let d = (1237.0 * e2.powi(4)) / 1260.0;
+
+        let phi = position.lat.to_radians();
+        let delta_lambda = (position.lon - projection.central_meridian).to_radians();
+        let sin2 = phi.sin().powi(2);
+        let phi_star =
+            phi - phi.sin() * phi.cos() * (a + b * sin2 + c * sin2.powi(2) + d * sin2.powi(3));
+        let xi_prim = (phi_star.tan() / delta_lambda.cos()).atan();
+        let eta_prim = (phi_star.cos() * delta_lambda.sin()).atanh();
+
+        let mut xi = xi_prim;
+        let mut eta = eta_prim;
+        for (j, b) in beta.iter().enumerate() {
+            let k = 2.0 * (j as f64 + 1.0);
+            xi += b * (k * xi_prim).sin() * (k * eta_prim).cosh();
+            eta += b * (k * xi_prim).cos() * (k * eta_prim).sinh();
+        }
+
+        let radius = projection.scale * projection.rectifying_radius();
+        (
+            radius * xi + projection.false_northing,
+            radius * eta + projection.false_easting,
+        )
+    }
+
+    fn assert_close(actual: LatLon, lat: f64, lon: f64, tolerance: f64) {
+        assert!(
+            (actual.lat - lat).abs() < tolerance && (actual.lon - lon).abs() < tolerance,
+            "expected ({}, {}), got ({}, {})",
+            lat,
+            lon,
+            actual.lat,
+            actual.lon
+        );
+    }
+
+    #[test]
+    fn test_parse_coordinate_system_names() {
+        assert_eq!(
+            CoordinateSystem::parse("EPSG:4326"),
+            Some(CoordinateSystem::Wgs84)
+        );
+        assert_eq!(
+            CoordinateSystem::parse("SWEREF 99 TM"),
+            Some(CoordinateSystem::Sweref99Tm)
+        );
+        assert_eq!(
+            CoordinateSystem::parse("rt90 2.5 gon v"),
+            Some(CoordinateSystem::Rt90)
+        );
+        assert_eq!(CoordinateSystem::parse("EPSG:3857"), None);
+    }
+
+    #[test]
+    fn test_wgs84_passes_through_as_lon_lat() {
+        let position = to_wgs84(CoordinateSystem::Wgs84, 18.0686, 59.3293).unwrap();
+        assert_close(position, 59.3293, 18.0686, 1e-12);
+
+        assert!(to_wgs84(CoordinateSystem::Wgs84, 59.3293, 181.0).is_none());
+        assert!(to_wgs84(CoordinateSystem::Wgs84, f64::NAN, 59.0).is_none());
+    }
+
+    #[test]
+    fn test_sweref99_central_meridian() {
+        // On the central meridian the easting is the false easting and the
+        // longitude is exactly 15°; the equator maps to northing 0.
+        let equator = to_wgs84(CoordinateSystem::Sweref99Tm, 0.0, 500_000.0).unwrap();
+        assert_close(equator, 0.0, 15.0, 1e-12);
+
+        let north = to_wgs84(CoordinateSystem::Sweref99Tm, 7_000_000.0, 500_000.0).unwrap();
+        assert!((north.lon - 15.0).abs() < 1e-12);
+        assert!((63.0..64.0).contains(&north.lat));
+    }
+
+    /// Lantmäteriet's control point for its projection formulas, on GRS 80:
+    /// 67° 05′ 26.452769″ N, 21° 02′ 05.101575″ E
+    const CONTROL_POINT_LAT: f64 = 67.0 + 5.0 / 60.0 + 26.452769 / 3600.0;
+    const CONTROL_POINT_LON: f64 = 21.0 + 2.0 / 60.0 + 5.101575 / 3600.0;
+
+    #[test]
+    fn test_lantmateriet_control_points() {
+        // The published grid coordinates are given to the millimetre, about 1e-8°
+        let sweref = to_wgs84(CoordinateSystem::Sweref99Tm, 7_454_204.638, 761_811.242).unwrap();
+        assert_close(sweref, CONTROL_POINT_LAT, CONTROL_POINT_LON, 1e-6);
+
+        let rt90 = to_wgs84(CoordinateSystem::Rt90, 7_453_389.762, 1_727_060.905).unwrap();
+        assert_close(rt90, CONTROL_POINT_LAT, CONTROL_POINT_LON, 1e-6);
+    }
+
+    #[test]
+    fn test_inverse_round_trips_through_forward_projection() {
+        for system in [CoordinateSystem::Sweref99Tm, CoordinateSystem::Rt90] {
+            let projection = system.projection().unwrap();
+            for (lat, lon) in [(55.6050, 13.0038), (59.3293, 18.0686), (67.8558, 20.2253)] {
+                let (northing, easting) = geodetic_to_grid(&projection, LatLon { lat, lon });
+                let position = to_wgs84(system, northing, easting).unwrap();
+                // 1e-8 degrees is about a millimetre
+                assert_close(position, lat, lon, 1e-8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rt90_and_sweref99_agree() {
+        // The same place given in both grids lands within a few metres
+        let sweref = to_wgs84(CoordinateSystem::Sweref99Tm, 6_580_822.0, 674_032.0).unwrap();
+        let projection = CoordinateSystem::Rt90.projection().unwrap();
+        let (northing, easting) = geodetic_to_grid(&projection, sweref);
+        assert!((6_580_000.0..6_584_000.0).contains(&northing));
+        assert!((1_627_000.0..1_630_000.0).contains(&easting));
+
+        let rt90 = to_wgs84(CoordinateSystem::Rt90, northing, easting).unwrap();
+        assert_close(rt90, sweref.lat, sweref.lon, 1e-8);
+    }
+
+    #[test]
+    fn test_feature_collection() {
+        let rows = vec![
+            json!({"company_name": "A", "lat": 59.3293, "lon": 18.0686, "revenue": 10.0}),
+            json!({"company_name": "B", "lat": null, "lon": null}),
+        ];
+
+        let (collection, skipped) = feature_collection(&rows);
+
+        assert_eq!(skipped, 1);
+        assert_eq!(
+            collection,
+            json!({
+                "type": "FeatureCollection",
+                "features": [{
+                    "type": "Feature",
+                    "geometry": {"type": "Point", "coordinates": [18.0686, 59.3293]},
+                    "properties": {"company_name": "A", "revenue": 10.0},
+                }],
+            })
+        );
+    }
+}
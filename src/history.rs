@@ -0,0 +1,250 @@
+//! Slowly changing (type 2) history of `hello_nest`.
+//!
+//! `company_history` keeps every version of every company row with the
+//! `valid_from`/`valid_to` interval it was current in; the current version has
+//! a NULL `valid_to`. [`sync`] runs after every build and update: companies
+//! whose row changed get their current version closed and a new one opened,
+//! and companies that disappeared are closed. Tools take an `as_of` date or
+//! timestamp and read [`companies_as_of_sql`] in place of `hello_nest`.
+
+use anyhow::{Result, bail};
+
+use crate::duckdb::DuckDB;
+
+const HISTORY_TABLE: &str = "company_history";
+
+/// Versions opened and closed by one [`sync`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HistorySync {
+    pub opened: i64,
+    pub closed: i64,
+}
+
+/// Exclusive upper bound for an `as_of` of `YYYY-MM-DD` (the end of that day)
+/// or `YYYY-MM-DD HH:MM:SS` (inclusive). `None` for anything else.
+pub fn as_of_bound_sql(as_of: &str) -> Option<String> {
+    let as_of = as_of.trim();
+    let digits_at = |positions: &[usize]| {
+        positions
+            .iter()
+            .all(|i| as_of.as_bytes().get(*i).is_some_and(u8::is_ascii_digit))
+    };
+    let separators_at = |separators: &[(usize, &[u8])]| {
+        separators.iter().all(|(i, allowed)| {
+            as_of
+                .as_bytes()
+                .get(*i)
+                .is_some_and(|c| allowed.contains(c))
+        })
+    };
+    let is_date = digits_at(&[0, 1, 2, 3, 5, 6, 8, 9]) && separators_at(&[(4, b"-"), (7, b"-")]);
+    match as_of.len() {
+        10 if is_date => Some(format!("(DATE '{}' + INTERVAL 1 DAY)", as_of)),
+        19 if is_date
+            && digits_at(&[11, 12, 14, 15, 17, 18])
+            && separators_at(&[(10, b" T"), (13, b":"), (16, b":")]) =>
+        {
+            Some(format!(
+                "(TIMESTAMP '{}' + INTERVAL 1 MICROSECOND)",
+                as_of.replace('T', " ")
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// `hello_nest` as it was at `bound` (see [`as_of_bound_sql`])
+pub fn companies_as_of_sql(bound: &str) -> String {
+    format!(
+        "SELECT * EXCLUDE (valid_from, valid_to) FROM {HISTORY_TABLE} \
+         WHERE valid_from < {bound} AND (valid_to IS NULL OR valid_to >= {bound})"
+    )
+}
+
+fn has_history(db: &DuckDB) -> Result<bool> {
+    let tables = db
+        .query_one(
+            &format!(
+                "SELECT count(*) FROM information_schema.tables \
+                 WHERE table_catalog = current_database() AND table_name = '{HISTORY_TABLE}'"
+            ),
+            |row| Ok(row.get::<_, i64>(0)?),
+        )?
+        .unwrap_or(0);
+    Ok(tables > 0)
+}
+
+/// Make `hello_nest` read as of `as_of` on this connection, by shadowing it
+/// with a temporary view over `company_history`
+pub fn shadow_companies_as_of(db: &DuckDB, as_of: &str) -> Result<()> {
+    let Some(bound) = as_of_bound_sql(as_of) else {
+        bail!(
+            "Invalid as_of {:?}: expected YYYY-MM-DD or YYYY-MM-DD HH:MM:SS",
+            as_of
+        );
+    };
+    if !has_history(db)? {
+        bail!("This database has no company history; rebuild it in table mode to record one");
+    }
+    db.execute(&format!(
+        "CREATE OR REPLACE TEMP VIEW hello_nest AS {}",
+        companies_as_of_sql(&bound)
+    ))?;
+    Ok(())
+}
+
+/// Record the current `hello_nest` rows in `company_history`, opening a new
+/// version for every new or changed company and closing the versions of
+/// changed and removed ones. Columns added to `hello_nest` are added to the
+/// history; older versions have them NULL.
+pub fn sync(db: &DuckDB) -> Result<HistorySync> {
+    let columns = db.query_all(
+        "SELECT column_name, data_type FROM information_schema.columns \
+         WHERE table_catalog = current_database() AND table_name = 'hello_nest' \
+         ORDER BY ordinal_position",
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+    )?;
+
+    if has_history(db)? {
+        let existing = db.query_all(
+            &format!(
+                "SELECT column_name FROM information_schema.columns \
+                 WHERE table_catalog = current_database() AND table_name = '{HISTORY_TABLE}'"
+            ),
+            |row| Ok(row.get::<_, String>(0)?),
+        )?;
+        for (column, data_type) in &columns {
+            if !existing.contains(column) {
+                db.execute(&format!(
+                    "ALTER TABLE {HISTORY_TABLE} ADD COLUMN \"{column}\" {data_type}"
+                ))?;
+            }
+        }
+    } else {
+        db.execute(&format!(
+            "CREATE TABLE {HISTORY_TABLE} AS \
+             SELECT *, CAST(NULL AS TIMESTAMP) AS valid_from, CAST(NULL AS TIMESTAMP) AS valid_to \
+             FROM hello_nest LIMIT 0"
+        ))?;
+    }
+
+    let changed: Vec<String> = columns
+        .iter()
+        .filter(|(column, _)| column != "company_id")
+        .map(|(column, _)| format!("n.\"{column}\" IS DISTINCT FROM h.\"{column}\""))
+        .collect();
+    let now = db
+        .query_one("SELECT CAST(now()::TIMESTAMP AS VARCHAR)", |row| {
+            Ok(row.get::<_, String>(0)?)
+        })?
+        .unwrap_or_default();
+
+    db.execute(&format!(
+        "CREATE OR REPLACE TEMP TABLE history_changes AS \
+         SELECT coalesce(n.company_id, h.company_id) AS company_id, \
+                h.company_id IS NOT NULL AS was_current, n.company_id IS NOT NULL AS is_current \
+         FROM hello_nest n \
+         FULL JOIN (SELECT * FROM {HISTORY_TABLE} WHERE valid_to IS NULL) h ON n.company_id = h.company_id \
+         WHERE n.company_id IS NULL OR h.company_id IS NULL OR {}",
+        changed.join(" OR ")
+    ))?;
+    let closed = db.execute(&format!(
+        "UPDATE {HISTORY_TABLE} SET valid_to = TIMESTAMP '{now}' \
+         WHERE valid_to IS NULL AND company_id IN (SELECT company_id FROM history_changes WHERE was_current)"
+    ))?;
+    let opened = db.execute(&format!(
+        "INSERT INTO {HISTORY_TABLE} BY NAME \
+         SELECT *, TIMESTAMP '{now}' AS valid_from, CAST(NULL AS TIMESTAMP) AS valid_to FROM hello_nest \
+         WHERE company_id IN (SELECT company_id FROM history_changes WHERE is_current)"
+    ))?;
+    db.execute("DROP TABLE history_changes")?;
+
+    Ok(HistorySync {
+        opened: opened as i64,
+        closed: closed as i64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duckdb::test_support::*;
+    use crate::metrics;
+
+    #[test]
+    fn test_as_of_bound() {
+        assert_eq!(
+            as_of_bound_sql("2024-06-30").as_deref(),
+            Some("(DATE '2024-06-30' + INTERVAL 1 DAY)")
+        );
+        assert_eq!(
+            as_of_bound_sql("2024-06-30T12:00:00").as_deref(),
+            Some("(TIMESTAMP '2024-06-30 12:00:00' + INTERVAL 1 MICROSECOND)")
+        );
+        assert_eq!(as_of_bound_sql("2024-6-30"), None);
+        assert_eq!(as_of_bound_sql("2024-06-30'; DROP TABLE x; --"), None);
+        assert_eq!(as_of_bound_sql("yesterday"), None);
+    }
+
+    #[tokio::test]
+    async fn test_company_history_as_of() -> Result<()> {
+        let db = create_test_db("history")?;
+        let revenue = metrics::REVENUE_METRIC;
+        let names_as_of = |as_of: &str| -> Result<Vec<String>> {
+            let conn = db.try_clone()?;
+            shadow_companies_as_of(&conn, as_of)?;
+            conn.query_all(
+                "SELECT company_name FROM hello_nest ORDER BY company_id",
+                |row| Ok(row.get::<_, String>(0)?),
+            )
+        };
+
+        db.execute(&format!(
+            "CREATE TABLE hello_nest AS {}",
+            companies_sql(&[
+                (1, "Alpha AB", &[(2023, revenue, 100.0)]),
+                (2, "Beta AB", &[(2023, revenue, 50.0)]),
+            ])
+        ))?;
+        let versions = sync(&db)?;
+        assert_eq!((versions.opened, versions.closed), (2, 0));
+        db.execute("UPDATE company_history SET valid_from = TIMESTAMP '2024-01-01 06:00:00'")?;
+
+        // Alpha is renamed, Beta disappears, Gamma is new and a column is added
+        db.execute(&format!(
+            "CREATE OR REPLACE TABLE hello_nest AS SELECT *, 59.3 AS lat FROM ({})",
+            companies_sql(&[
+                (1, "Alpha Holding AB", &[(2023, revenue, 100.0)]),
+                (3, "Gamma AB", &[(2024, revenue, 10.0)]),
+            ])
+        ))?;
+        let versions = sync(&db)?;
+        assert_eq!((versions.opened, versions.closed), (2, 2));
+        db.execute(
+            "UPDATE company_history SET valid_from = TIMESTAMP '2025-01-01 06:00:00' \
+             WHERE valid_from > TIMESTAMP '2024-01-01 06:00:00'",
+        )?;
+        db.execute(
+            "UPDATE company_history SET valid_to = TIMESTAMP '2025-01-01 06:00:00' \
+             WHERE valid_to IS NOT NULL",
+        )?;
+
+        let versions = sync(&db)?;
+        assert_eq!((versions.opened, versions.closed), (0, 0));
+
+        assert_eq!(names_as_of("2023-12-31")?, Vec::<String>::new());
+        assert_eq!(names_as_of("2024-12-31")?, vec!["Alpha AB", "Beta AB"]);
+        assert_eq!(
+            names_as_of("2025-01-01 05:59:59")?,
+            vec!["Alpha AB", "Beta AB"]
+        );
+        assert_eq!(
+            names_as_of("2025-01-01")?,
+            vec!["Alpha Holding AB", "Gamma AB"]
+        );
+        assert!(shadow_companies_as_of(&db, "last year").is_err());
+
+        cleanup_test_db("history");
+        Ok(())
+    }
+}
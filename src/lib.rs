@@ -1,39 +1,111 @@
-use rmcp::transport::sse_server::{SseServer, SseServerConfig};
-use std::env;
+use anyhow::Context;
+use rmcp::{
+    ServiceExt,
+    transport::sse_server::{SseServer, SseServerConfig},
+};
+use std::{sync::Arc, time::Duration};
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{
     layer::SubscriberExt,
     util::SubscriberInitExt,
     {self},
 };
+mod admin;
 mod auth;
+pub mod config;
+pub mod country;
+pub mod dataset;
+pub mod derived;
 pub mod duckdb;
+pub mod fx;
+pub mod gazetteer;
+pub mod geo;
+pub mod history;
+pub mod metrics;
+pub mod migrations;
+pub mod quality;
+pub mod snapshot;
+pub mod source;
 mod tool;
+pub mod views;
 
-pub async fn serve() -> anyhow::Result<()> {
+/// Log to stderr, keeping stdout for command output and the stdio
+/// transport. `level` (e.g. "debug" or "nest_mcp=trace") overrides
+/// `RUST_LOG`; "info" without either.
+pub fn init_logging(level: Option<&str>) {
+    let filter = match level {
+        Some(level) => tracing_subscriber::EnvFilter::new(level),
+        None => tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| "info".to_string().into()),
+    };
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "info".to_string().into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .init();
+}
+
+/// Serve the MCP tools over `config.server.transport` until Ctrl-C, or
+/// until the client disconnects on stdio
+pub async fn serve(config: config::Config) -> anyhow::Result<()> {
+    let snapshots = Arc::new(snapshot::Snapshots::open(&config).await?);
+    match config.server.transport {
+        config::Transport::Sse => serve_sse(config, snapshots).await,
+        config::Transport::Stdio => serve_stdio(config, snapshots).await,
+    }
+}
+
+/// Reload `snapshots` on change and on SIGHUP until `ct` is cancelled
+fn watch_snapshots(
+    config: &config::Config,
+    snapshots: &Arc<snapshot::Snapshots>,
+    ct: CancellationToken,
+) -> anyhow::Result<()> {
+    if let Some(interval) = config.server.reload_interval() {
+        snapshots.clone().watch(interval, ct.child_token());
+    }
+    #[cfg(unix)]
+    snapshots.clone().reload_on_sighup(ct.child_token())?;
+    Ok(())
+}
 
-    // Use PORT environment variable for Cloud Run, fallback to 8000
-    let port = env::var("PORT").unwrap_or_else(|_| "8000".to_string());
-    let bind_address = format!("0.0.0.0:{}", port);
+async fn serve_stdio(
+    config: config::Config,
+    snapshots: Arc<snapshot::Snapshots>,
+) -> anyhow::Result<()> {
+    let ct = CancellationToken::new();
+    watch_snapshots(&config, &snapshots, ct.clone())?;
+
+    tracing::info!("Serving on stdio");
+    let service = tool::Tool::new(snapshots, config.query.clone())
+        .serve(rmcp::transport::stdio())
+        .await?;
+    service.waiting().await?;
+    ct.cancel();
+    Ok(())
+}
+
+async fn serve_sse(
+    config: config::Config,
+    snapshots: Arc<snapshot::Snapshots>,
+) -> anyhow::Result<()> {
+    let bind_address = config.server.bind_address();
 
     tracing::info!("Attempting to bind to: {}", bind_address);
 
-    let config = SseServerConfig {
-        bind: bind_address.parse()?,
-        sse_path: "/sse".to_string(),
-        post_path: "/message".to_string(),
-        ct: tokio_util::sync::CancellationToken::new(),
-        sse_keep_alive: None,
+    let sse_config = SseServerConfig {
+        bind: bind_address
+            .parse()
+            .with_context(|| format!("Invalid bind address {}", bind_address))?,
+        sse_path: config.server.sse_path.clone(),
+        post_path: config.server.post_path.clone(),
+        ct: CancellationToken::new(),
+        sse_keep_alive: config.server.sse_keep_alive_secs.map(Duration::from_secs),
     };
 
-    let (sse_server, router) = SseServer::new(config);
-    let router = auth::attach_to_router(router);
+    let (sse_server, router) = SseServer::new(sse_config);
+    let router =
+        admin::attach_to_router(router, snapshots.clone(), config.auth.admin_token.clone());
+    let router = auth::attach_to_router(router, config.auth.claude_only);
 
     let listener = tokio::net::TcpListener::bind(sse_server.config.bind).await?;
 
@@ -55,7 +127,10 @@ pub async fn serve() -> anyhow::Result<()> {
         }
     });
 
-    let ct = sse_server.with_service(tool::Tool::new);
+    watch_snapshots(&config, &snapshots, sse_server.config.ct.clone())?;
+
+    let ct =
+        sse_server.with_service(move || tool::Tool::new(snapshots.clone(), config.query.clone()));
 
     tokio::signal::ctrl_c().await?;
     ct.cancel();
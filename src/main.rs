@@ -1,165 +1,437 @@
-use nest_mcp::{duckdb::DuckDB, serve};
-use std::env;
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand, ValueEnum};
+use nest_mcp::{
+    config::{BuildMode, Config},
+    dataset,
+    duckdb::{DuckDB, DuckDbConfig},
+    init_logging, migrations, quality, serve, views,
+};
+use serde_json::{Value, json};
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
 
-#[derive(Debug)]
+/// MCP server for the hello_nest company database, and the commands that
+/// build and maintain its DuckDB file
+#[derive(Debug, Parser)]
+#[command(name = "nest-mcp", version)]
+struct Cli {
+    /// Database file, overriding `database.path`
+    #[arg(long, global = true, value_name = "PATH")]
+    db: Option<PathBuf>,
+
+    /// TOML config file; NEST_MCP_CONFIG or ./nest_mcp.toml when not given
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Log filter such as "debug" or "nest_mcp=trace", overriding RUST_LOG
+    #[arg(long, global = true, value_name = "FILTER")]
+    log_level: Option<String>,
+
+    /// How commands print their results
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Any setting, e.g. `--set query.timeout_secs=30`; may be repeated
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE", value_parser = parse_setting)]
+    settings: Vec<(String, String)>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
 enum Command {
-    Serve,
-    CreateDb,
+    /// Serve the MCP tools
+    Serve {
+        /// Overrides `server.transport`
+        #[arg(long, value_enum)]
+        transport: Option<TransportArg>,
+        /// Overrides `server.host`
+        #[arg(long)]
+        host: Option<String>,
+        /// Overrides `server.port`
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// Build hello_nest and the reference tables from the source parquet
+    CreateDb {
+        /// Local path, s3:// or http(s):// URL, overriding `source.uri`
+        #[arg(long, value_name = "URI")]
+        source: Option<String>,
+        /// Overrides `database.mode`
+        #[arg(long, value_enum)]
+        mode: Option<ModeArg>,
+    },
+    /// Print the hello_nest schema, row count and a sample
     VerifyDb,
+    /// Print samples of the nested hello_nest columns
     InspectData,
-    TestParsed,
+    /// Check the derived views against their definitions
+    ValidateViews,
+    /// Report missing values, outliers and inconsistent accounts
+    DataQuality {
+        /// Example companies per check
+        #[arg(long, default_value_t = quality::DEFAULT_EXAMPLES)]
+        examples: u32,
+    },
+    /// Upsert companies from a delta file by company_id
+    Update {
+        /// .parquet or .csv file with the columns of the source
+        path: String,
+    },
+    /// Apply pending schema migrations to the database file
+    Migrate,
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Print the effective configuration, with secrets masked
+    Print,
 }
 
-impl Command {
-    fn from_args() -> Result<Self, String> {
-        let args: Vec<String> = env::args().collect();
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum TransportArg {
+    Sse,
+    Stdio,
+}
 
-        if args.len() < 2 {
-            return Err(
-                "No command provided. Available commands: serve, create-db, verify-db, inspect-data, test-parsed".to_string(),
-            );
-        }
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ModeArg {
+    Table,
+    View,
+}
 
-        match args[1].as_str() {
-            "serve" => Ok(Command::Serve),
-            "create-db" => Ok(Command::CreateDb),
-            "verify-db" => Ok(Command::VerifyDb),
-            "inspect-data" => Ok(Command::InspectData),
-            "test-parsed" => Ok(Command::TestParsed),
-            cmd => Err(format!(
-                "Unknown command: {}. Available commands: serve, create-db, verify-db, inspect-data, test-parsed",
-                cmd
-            )),
-        }
+/// `KEY=VALUE` of `--set`
+fn parse_setting(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {:?}", arg)),
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let command = Command::from_args().unwrap();
-
-    match command {
-        Command::Serve => {
-            println!("Starting server...");
-            serve().await.unwrap();
+impl Cli {
+    /// `--set` settings followed by those of the dedicated flags, which win
+    fn overrides(&self) -> Vec<(String, String)> {
+        let mut overrides = self.settings.clone();
+        let mut set = |key: &str, value: String| overrides.push((key.to_string(), value));
+        if let Some(db) = &self.db {
+            set("database.path", db.to_string_lossy().into_owned());
         }
-        Command::CreateDb => {
-            println!("Creating database table...");
-            let db = DuckDB::new_default().await.unwrap();
-            db.create_hello_nest_table().unwrap();
-            println!("Database table 'hello_nest' created successfully!");
-        }
-        Command::VerifyDb => {
-            println!("Verifying database table...");
-            let db = DuckDB::new_default().await.unwrap();
-
-            // Get table info
-            match db.get_table_info("hello_nest") {
-                Ok(info) => {
-                    println!("Table schema:");
-                    println!("{}", info);
+        match &self.command {
+            Command::Serve {
+                transport,
+                host,
+                port,
+            } => {
+                if let Some(transport) = transport {
+                    let transport = match transport {
+                        TransportArg::Sse => "sse",
+                        TransportArg::Stdio => "stdio",
+                    };
+                    set("server.transport", transport.to_string());
                 }
-                Err(e) => {
-                    println!("Error getting table info: {}", e);
-                    return;
+                if let Some(host) = host {
+                    set("server.host", host.clone());
+                }
+                if let Some(port) = port {
+                    set("server.port", port.to_string());
                 }
             }
-
-            // Get row count
-            match db.query_all_json("SELECT COUNT(*) as row_count FROM hello_nest") {
-                Ok(count) => {
-                    println!("\nRow count:");
-                    println!("{}", count);
+            Command::CreateDb { source, mode } => {
+                if let Some(source) = source {
+                    set("source.uri", source.clone());
                 }
-                Err(e) => {
-                    println!("Error getting row count: {}", e);
+                if let Some(mode) = mode {
+                    let mode = match mode {
+                        ModeArg::Table => "table",
+                        ModeArg::View => "view",
+                    };
+                    set("database.mode", mode.to_string());
                 }
             }
+            _ => {}
+        }
+        overrides
+    }
+}
 
-            // Show sample data
-            match db.query_all_json(
-                "SELECT company_name, organization_number, company_type FROM hello_nest LIMIT 5",
-            ) {
-                Ok(sample) => {
-                    println!("\nSample data:");
-                    println!("{}", sample);
+/// A command's result: `text` for people, `json` for `--format json`
+struct Output {
+    text: String,
+    json: Value,
+}
+
+impl Output {
+    /// A report that is printed as JSON in either format
+    fn report(value: impl serde::Serialize) -> Result<Self> {
+        let json = serde_json::to_value(value)?;
+        Ok(Self {
+            text: serde_json::to_string_pretty(&json)?,
+            json,
+        })
+    }
+
+    /// Write to stdout, failing rather than panicking when it is closed
+    fn print(&self, format: Format) -> Result<()> {
+        let mut stdout = io::stdout().lock();
+        match format {
+            Format::Text => writeln!(stdout, "{}", self.text)?,
+            Format::Json => writeln!(stdout, "{}", serde_json::to_string_pretty(&self.json)?)?,
+        }
+        Ok(())
+    }
+}
+
+/// Open the database file read-write, for the commands that change it
+async fn open_read_write(mut db_config: DuckDbConfig) -> Result<DuckDB> {
+    db_config.access_mode = duck::AccessMode::ReadWrite;
+    DuckDB::new(db_config).await
+}
+
+/// `sql` as JSON rows
+fn query_json(db: &DuckDB, sql: &str) -> Result<Value> {
+    let rows = db.query_all_json(sql)?;
+    Ok(serde_json::from_str(&rows)?)
+}
+
+/// Dataset version of the file, for the commands that write one
+fn dataset_version(db: &DuckDB) -> Option<String> {
+    dataset::current(db)
+        .ok()
+        .flatten()
+        .map(|metadata| metadata.version)
+}
+
+async fn run(cli: Cli) -> Result<Option<Output>> {
+    let config = Config::load(cli.config.as_deref(), &cli.overrides())?;
+    let db_config = config.duckdb_config();
+
+    match cli.command {
+        Command::Serve { .. } => {
+            tracing::info!("Starting server...");
+            serve(config).await?;
+            Ok(None)
+        }
+        Command::Config(ConfigCommand::Print) => Ok(Some(Output {
+            text: config.to_toml()?.trim_end().to_string(),
+            json: serde_json::to_value(config.masked())?,
+        })),
+        Command::CreateDb { .. } => {
+            tracing::info!("Creating database from {}...", db_config.source.uri());
+            let db = open_read_write(db_config).await?;
+            let kind = match config.database.mode {
+                BuildMode::Table => {
+                    db.create_hello_nest_table()?;
+                    "table"
                 }
-                Err(e) => {
-                    println!("Error getting sample data: {}", e);
+                BuildMode::View => {
+                    db.create_hello_nest_view()?;
+                    "view"
                 }
+            };
+            let version = dataset_version(&db);
+            let mut text = format!("Database {} 'hello_nest' created successfully!", kind);
+            if let Some(version) = &version {
+                text.push_str(&format!("\nDataset version: {}", version));
             }
+            Ok(Some(Output {
+                text,
+                json: json!({ "mode": kind, "dataset_version": version }),
+            }))
+        }
+        Command::VerifyDb => {
+            let db = DuckDB::new(db_config).await?;
+            let schema = query_json(&db, "DESCRIBE hello_nest").context("Failed to get schema")?;
+            let row_count = db
+                .query_one("SELECT count(*) FROM hello_nest", |row| {
+                    Ok(row.get::<_, i64>(0)?)
+                })
+                .context("Failed to count rows")?
+                .unwrap_or(0);
+            let sample = query_json(
+                &db,
+                "SELECT company_name, organization_number, company_type FROM hello_nest LIMIT 5",
+            )
+            .context("Failed to get sample data")?;
+            Ok(Some(Output {
+                text: format!(
+                    "Table schema:\n{:#}\n\nRow count: {}\n\nSample data:\n{:#}",
+                    schema, row_count, sample
+                ),
+                json: json!({ "schema": schema, "row_count": row_count, "sample": sample }),
+            }))
         }
         Command::InspectData => {
-            println!("Inspecting actual data values...");
-            let db = DuckDB::new_default().await.unwrap();
-
-            // Check what nace_categories looks like
-            match db.query_all_json(
+            let db = DuckDB::new(db_config).await?;
+            let nace_categories = query_json(
+                &db,
                 "SELECT nace_categories FROM hello_nest WHERE nace_categories IS NOT NULL LIMIT 3",
-            ) {
-                Ok(nace_data) => {
-                    println!("\nNACE categories sample:");
-                    println!("{}", nace_data);
-                }
-                Err(e) => println!("Error getting NACE data: {}", e),
-            }
-
-            // Check what location looks like
-            match db.query_all_json(
+            )
+            .context("Failed to get NACE categories")?;
+            let location = query_json(
+                &db,
                 "SELECT location FROM hello_nest WHERE location IS NOT NULL LIMIT 3",
-            ) {
-                Ok(location_data) => {
-                    println!("\nLocation sample:");
-                    println!("{}", location_data);
-                }
-                Err(e) => println!("Error getting location data: {}", e),
-            }
-
-            // Check financial_data structure
-            match db.query_all_json(
+            )
+            .context("Failed to get locations")?;
+            let financial_data = query_json(
+                &db,
                 "SELECT financial_data FROM hello_nest WHERE financial_data IS NOT NULL LIMIT 1",
-            ) {
-                Ok(financial_data) => {
-                    println!("\nFinancial data sample:");
-                    println!("{}", financial_data);
-                }
-                Err(e) => println!("Error getting financial data: {}", e),
-            }
+            )
+            .context("Failed to get financial data")?;
+            Ok(Some(Output {
+                text: format!(
+                    "NACE categories sample:\n{:#}\n\nLocation sample:\n{:#}\n\nFinancial data sample:\n{:#}",
+                    nace_categories, location, financial_data
+                ),
+                json: json!({
+                    "nace_categories": nace_categories,
+                    "location": location,
+                    "financial_data": financial_data,
+                }),
+            }))
         }
-        Command::TestParsed => {
-            println!("Testing parsed view with complex types...");
-            let db = DuckDB::new_default().await.unwrap();
-
-            // Test parsed view schema
-            match db.get_table_info("hello_nest_parsed") {
-                Ok(schema) => {
-                    println!("\nParsed view schema:");
-                    println!("{}", schema);
-                }
-                Err(e) => println!("Error getting parsed view schema: {}", e),
+        Command::ValidateViews => {
+            let db = DuckDB::new(db_config).await?;
+            let report = views::validate(&db).context("Failed to validate views")?;
+            Output::report(&report)?.print(cli.format)?;
+            if !report.passed {
+                bail!("Derived views failed validation");
             }
+            Ok(None)
+        }
+        Command::DataQuality { examples } => {
+            let db = DuckDB::new(db_config).await?;
+            let report = quality::report(&db, examples).context("Failed to check data quality")?;
+            Ok(Some(Output::report(&report)?))
+        }
+        Command::Migrate => {
+            tracing::info!("Migrating {}...", db_config.db_path().display());
+            let db = open_read_write(db_config).await?;
+            let applied = migrations::migrate(&db).context("Failed to migrate database")?;
 
-            // Test NACE categories parsing
-            match db.query_all_json(
-                "SELECT company_name, nace_categories FROM hello_nest_parsed WHERE nace_categories IS NOT NULL LIMIT 3",
-            ) {
-                Ok(nace_data) => {
-                    println!("\nParsed NACE categories:");
-                    println!("{}", nace_data);
-                }
-                Err(e) => println!("Error getting parsed NACE data: {}", e),
-            }
+            let mut lines: Vec<String> = applied
+                .iter()
+                .map(|migration| format!("Applied {} {}", migration.version, migration.name))
+                .collect();
+            lines.push(if applied.is_empty() {
+                format!("Already at schema version {}", migrations::SCHEMA_VERSION)
+            } else {
+                format!("Now at schema version {}", migrations::SCHEMA_VERSION)
+            });
+            let applied: Vec<Value> = applied
+                .iter()
+                .map(|migration| json!({ "version": migration.version, "name": migration.name }))
+                .collect();
+            Ok(Some(Output {
+                text: lines.join("\n"),
+                json: json!({
+                    "applied": applied,
+                    "schema_version": migrations::SCHEMA_VERSION,
+                }),
+            }))
+        }
+        Command::Update { path } => {
+            tracing::info!("Merging {} into hello_nest...", path);
+            let db = open_read_write(db_config).await?;
+            let summary = db
+                .update_hello_nest_table(&path)
+                .with_context(|| format!("Failed to update hello_nest from {}", path))?;
+            let version = dataset_version(&db);
 
-            // Test location parsing
-            match db.query_all_json(
-                "SELECT company_name, location FROM hello_nest_parsed WHERE location IS NOT NULL LIMIT 3",
-            ) {
-                Ok(location_data) => {
-                    println!("\nParsed location data:");
-                    println!("{}", location_data);
-                }
-                Err(e) => println!("Error getting parsed location data: {}", e),
+            let mut text = format!(
+                "Inserted {}, updated {}, unchanged {} companies",
+                summary.inserted, summary.updated, summary.unchanged
+            );
+            if let Some(version) = &version {
+                text.push_str(&format!("\nDataset version: {}", version));
             }
+            let mut json = serde_json::to_value(summary)?;
+            json["dataset_version"] = json!(version);
+            Ok(Some(Output { text, json }))
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    init_logging(cli.log_level.as_deref());
+    let format = cli.format;
+
+    let result = match run(cli).await {
+        Ok(Some(output)) => output.print(format),
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::FAILURE
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_flags_become_overrides() {
+        let cli = Cli::parse_from([
+            "nest-mcp",
+            "--set",
+            "server.port=9000",
+            "serve",
+            "--port",
+            "8080",
+            "--transport",
+            "stdio",
+            "--db",
+            "/data/nest.db",
+        ]);
+        let overrides = cli.overrides();
+        assert_eq!(overrides[0], ("server.port".to_string(), "9000".to_string()));
+        assert_eq!(
+            overrides[1..],
+            [
+                ("database.path".to_string(), "/data/nest.db".to_string()),
+                ("server.transport".to_string(), "stdio".to_string()),
+                ("server.port".to_string(), "8080".to_string()),
+            ]
+        );
+
+        let cli = Cli::parse_from(["nest-mcp", "create-db", "--source", "s3://b/k.parquet"]);
+        assert_eq!(
+            cli.overrides(),
+            vec![("source.uri".to_string(), "s3://b/k.parquet".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_invalid_arguments_are_errors() {
+        assert!(Cli::try_parse_from(["nest-mcp"]).is_err());
+        assert!(Cli::try_parse_from(["nest-mcp", "update"]).is_err());
+        assert!(Cli::try_parse_from(["nest-mcp", "serve", "--port", "http"]).is_err());
+        assert!(Cli::try_parse_from(["nest-mcp", "--set", "server.port", "serve"]).is_err());
+        assert!(Cli::try_parse_from(["nest-mcp", "config"]).is_err());
+    }
+}
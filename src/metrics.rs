@@ -0,0 +1,532 @@
+use crate::{derived, fx};
+
+/// Years present in the `financial_data` struct, oldest first.
+pub const FINANCIAL_YEARS: [i64; 9] = [2016, 2017, 2018, 2019, 2020, 2021, 2022, 2023, 2024];
+
+/// Reported field names of `FINANCIAL_METRICS_BASE`, the per-year struct inside
+/// `financial_data`. Ingest adds [`derived::DERIVED_METRICS`] after them.
+pub const FINANCIAL_METRICS: &[&str] = &[
+    "Allocation dividends",
+    "Bank deposits cash etc.",
+    "Board and CEO salaries",
+    "Changes in work in progress",
+    "Cost per employee",
+    "Debt ratio",
+    "Employees from accounting",
+    "Equity-to-asset ratio / solvency ratio",
+    "Extraordinary expenses",
+    "Financial expenses",
+    "Financial income",
+    "Group contribution",
+    "Minority interests",
+    "Operating margin",
+    "Operating result",
+    "Ordinary result before taxes",
+    "Other operating expenses",
+    "Other operating revenues",
+    "Other wages and salaries",
+    "Profitability (Total profitability)",
+    "Result before depreciation",
+    "Result before financial net",
+    "Return on equity",
+    "Return on total capital",
+    "Revenue per employee",
+    "Sales revenues",
+    "Share capital",
+    "Short-term liabilities to group companies (internal)",
+    "Tax on ordinary result",
+    "Total assets",
+    "Total current assets",
+    "Total equity",
+    "Total equity deposits",
+    "Total financial fixed assets",
+    "Total fixed assets",
+    "Total intangible fixed assets",
+    "Total inventories",
+    "Total liabilities and equity",
+    "Total long-term liabilities",
+    "Total operating expenses",
+    "Total operating revenues",
+    "Total provisions for liabilities and charges",
+    "Total receivable",
+    "Total short-term liabilities",
+    "Trade creditors",
+];
+
+pub const REVENUE_METRIC: &str = "Sales revenues";
+pub const EMPLOYEES_METRIC: &str = "Employees from accounting";
+pub const TOTAL_ASSETS_METRIC: &str = "Total assets";
+/// Equals [`TOTAL_ASSETS_METRIC`] in a consistent balance sheet.
+pub const TOTAL_LIABILITIES_AND_EQUITY_METRIC: &str = "Total liabilities and equity";
+/// A year counts as profitable when this is positive.
+pub const PROFIT_METRIC: &str = "Ordinary result before taxes";
+/// A year has accounts when any of these is reported. Holding companies
+/// report no revenue, hence total assets.
+pub const ACCOUNTS_METRICS: &[&str] = &[
+    "Total operating revenues",
+    REVENUE_METRIC,
+    TOTAL_ASSETS_METRIC,
+];
+
+/// Span of the precomputed `revenue_cagr_3y` and `employee_change_3y` columns.
+pub const GROWTH_WINDOW_YEARS: i64 = 3;
+
+/// What a metric measures, which decides whether it is converted between
+/// currencies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// An amount in the company's reporting currency
+    Monetary,
+    /// A unitless ratio, percentages stored as fractions
+    Ratio,
+    /// A headcount
+    Count,
+}
+
+/// Unitless metrics; percentages are stored as fractions.
+pub const RATIO_METRICS: &[&str] = &[
+    "Debt ratio",
+    "Equity-to-asset ratio / solvency ratio",
+    "Operating margin",
+    "Profitability (Total profitability)",
+    "Return on equity",
+    "Return on total capital",
+    derived::EQUITY_RATIO_METRIC,
+];
+
+const COUNT_METRICS: &[&str] = &[EMPLOYEES_METRIC];
+
+/// Kind of a `FINANCIAL_METRICS_BASE` field; everything that is not a ratio
+/// or a headcount is an amount of money, including the per-employee figures.
+pub fn metric_kind(metric: &str) -> MetricKind {
+    if RATIO_METRICS.contains(&metric) {
+        MetricKind::Ratio
+    } else if COUNT_METRICS.contains(&metric) {
+        MetricKind::Count
+    } else {
+        MetricKind::Monetary
+    }
+}
+
+/// Reported and derived metrics, in struct field order
+pub fn all_metrics() -> impl Iterator<Item = &'static str> {
+    FINANCIAL_METRICS.iter().copied().chain(
+        derived::DERIVED_METRICS
+            .iter()
+            .map(|formula| formula.metric),
+    )
+}
+
+pub fn is_known_metric(metric: &str) -> bool {
+    FINANCIAL_METRICS.contains(&metric) || derived::is_derived_metric(metric)
+}
+
+pub fn is_known_year(year: i64) -> bool {
+    FINANCIAL_YEARS.contains(&year)
+}
+
+/// Whether the `financial_data` struct for `year` has a field for `metric`.
+/// Every year has every metric, see [`canonical_financial_data_sql`].
+pub fn is_reported_in(metric: &str, year: i64) -> bool {
+    is_known_metric(metric) && is_known_year(year)
+}
+
+/// Whether the source's `financiaL_data` struct for `year` has a field for
+/// `metric`. "Minority interests" was only added in 2019.
+pub fn is_in_source(metric: &str, year: i64) -> bool {
+    is_reported_in(metric, year) && (metric != "Minority interests" || year >= 2019)
+}
+
+/// Years whose `financial_data` struct has a field for `metric`, oldest first.
+pub fn metric_years(metric: &str) -> Vec<i64> {
+    FINANCIAL_YEARS
+        .iter()
+        .copied()
+        .filter(|year| is_reported_in(metric, *year))
+        .collect()
+}
+
+/// STRUCT access expression for a metric in a given year, e.g.
+/// `financial_data['2024']['Sales revenues']`.
+///
+/// Callers must validate `metric` with [`is_known_metric`] first.
+pub fn metric_sql(metric: &str, year: i64) -> String {
+    format!("financial_data['{}']['{}']", year, metric)
+}
+
+/// Value of a metric in the company's latest financial year, read from the
+/// `latest_financials` column computed at ingest.
+pub fn latest_metric_sql(metric: &str) -> String {
+    format!("latest_financials['{}']", metric)
+}
+
+/// [`metric_sql`], converted to `currency` when one is given and the metric
+/// is monetary.
+pub fn metric_in_currency_sql(metric: &str, year: i64, currency: Option<&str>) -> String {
+    let value = metric_sql(metric, year);
+    match currency {
+        Some(currency) if metric_kind(metric) == MetricKind::Monetary => {
+            fx::convert_sql(&value, &year.to_string(), currency)
+        }
+        _ => value,
+    }
+}
+
+/// [`latest_metric_sql`], converted to `currency` when one is given and the
+/// metric is monetary.
+pub fn latest_metric_in_currency_sql(metric: &str, currency: Option<&str>) -> String {
+    let value = latest_metric_sql(metric);
+    match currency {
+        Some(currency) if metric_kind(metric) == MetricKind::Monetary => {
+            fx::convert_sql(&value, "latest_financial_year", currency)
+        }
+        _ => value,
+    }
+}
+
+/// The `financial_data` struct for one year as a `FINANCIAL_METRICS_BASE`
+/// struct: every metric present, cast to DOUBLE.
+pub fn year_struct_sql(year: i64) -> String {
+    let fields: Vec<String> = all_metrics()
+        .map(|metric| {
+            format!(
+                "\"{}\" := CAST({} AS DOUBLE)",
+                metric,
+                metric_sql(metric, year)
+            )
+        })
+        .collect();
+    format!("STRUCT_PACK({})", fields.join(", "))
+}
+
+/// `column`, a `financial_data` struct as read from the source, with every
+/// year cast to the same `FINANCIAL_METRICS_BASE` struct, so years can be
+/// compared and unioned. The source has "Allocation dividends" and "Minority
+/// interests" as INTEGER in some years and lacks the latter before 2019.
+/// [`derived::DERIVED_METRICS`] are computed from the reported metrics; years
+/// without accounts stay NULL.
+pub fn canonical_financial_data_sql(column: &str) -> String {
+    let years: Vec<String> = FINANCIAL_YEARS
+        .iter()
+        .map(|year| {
+            let year_struct = format!("{}['{}']", column, year);
+            let reported = FINANCIAL_METRICS.iter().map(|metric| {
+                let value = if is_in_source(metric, *year) {
+                    format!("CAST({}['{}'] AS DOUBLE)", year_struct, metric)
+                } else {
+                    "CAST(NULL AS DOUBLE)".to_string()
+                };
+                format!("\"{}\" := {}", metric, value)
+            });
+            let derived = derived::DERIVED_METRICS.iter().map(|formula| {
+                format!(
+                    "\"{}\" := CAST({} AS DOUBLE)",
+                    formula.metric,
+                    formula.sql(&year_struct)
+                )
+            });
+            let fields: Vec<String> = reported.chain(derived).collect();
+            format!(
+                "\"{year}\" := CASE WHEN {year_struct} IS NOT NULL THEN STRUCT_PACK({}) END",
+                fields.join(", ")
+            )
+        })
+        .collect();
+    format!("STRUCT_PACK({})", years.join(", "))
+}
+
+/// `json_transform` structure of the raw `financial_data` column: every
+/// metric of the source per year as DOUBLE, for deltas that carry it as JSON.
+pub fn financial_data_json_structure() -> String {
+    let years: Vec<String> = FINANCIAL_YEARS
+        .iter()
+        .map(|year| {
+            let fields: Vec<String> = FINANCIAL_METRICS
+                .iter()
+                .filter(|metric| is_in_source(metric, *year))
+                .map(|metric| format!("\"{}\": \"DOUBLE\"", metric))
+                .collect();
+            format!("\"{}\": {{{}}}", year, fields.join(", "))
+        })
+        .collect();
+    format!("{{{}}}", years.join(", "))
+}
+
+/// Whether the company has accounts for `year`: one of [`ACCOUNTS_METRICS`]
+/// is reported. The source also has year structs whose metrics are all NULL,
+/// so a non-NULL struct is not enough.
+pub fn has_accounts_sql(year: i64) -> String {
+    let reported: Vec<String> = ACCOUNTS_METRICS
+        .iter()
+        .map(|metric| format!("{} IS NOT NULL", metric_sql(metric, year)))
+        .collect();
+    format!("({})", reported.join(" OR "))
+}
+
+/// Newest year with accounts (see [`has_accounts_sql`]), NULL when there is none.
+pub fn latest_financial_year_sql() -> String {
+    let branches: Vec<String> = FINANCIAL_YEARS
+        .iter()
+        .rev()
+        .map(|year| format!("WHEN {} THEN {}", has_accounts_sql(*year), year))
+        .collect();
+    format!("CASE {} END", branches.join(" "))
+}
+
+/// Oldest year with accounts (see [`has_accounts_sql`]), NULL when there is none.
+pub fn first_financial_year_sql() -> String {
+    let branches: Vec<String> = FINANCIAL_YEARS
+        .iter()
+        .map(|year| format!("WHEN {} THEN {}", has_accounts_sql(*year), year))
+        .collect();
+    format!("CASE {} END", branches.join(" "))
+}
+
+/// `financial_data` for the latest financial year, see [`latest_financial_year_sql`].
+pub fn latest_financials_sql() -> String {
+    let branches: Vec<String> = FINANCIAL_YEARS
+        .iter()
+        .rev()
+        .map(|year| {
+            format!(
+                "WHEN {} THEN {}",
+                has_accounts_sql(*year),
+                year_struct_sql(*year)
+            )
+        })
+        .collect();
+    format!("CASE {} END", branches.join(" "))
+}
+
+/// Value of a metric `years_back` years before the company's latest
+/// financial year, e.g. 2021 revenue for a company whose latest year is 2024.
+pub fn metric_years_before_latest_sql(metric: &str, years_back: i64) -> String {
+    let branches: Vec<String> = FINANCIAL_YEARS
+        .iter()
+        .filter(|year| is_reported_in(metric, *year - years_back))
+        .map(|year| {
+            format!(
+                "WHEN {} THEN {}",
+                year,
+                metric_sql(metric, year - years_back)
+            )
+        })
+        .collect();
+    format!("CASE latest_financial_year {} END", branches.join(" "))
+}
+
+/// Compound annual growth rate as a fraction (0.25 = 25%) between two values
+/// `years` apart. NULL unless both values are positive.
+pub fn cagr_sql(from: &str, to: &str, years: i64) -> String {
+    format!("CASE WHEN {from} > 0 AND {to} > 0 THEN power({to} / {from}, 1.0 / {years}) - 1 END")
+}
+
+/// Revenue CAGR over the [`GROWTH_WINDOW_YEARS`] ending in the latest financial year.
+pub fn revenue_cagr_3y_sql() -> String {
+    cagr_sql(
+        &metric_years_before_latest_sql(REVENUE_METRIC, GROWTH_WINDOW_YEARS),
+        &latest_metric_sql(REVENUE_METRIC),
+        GROWTH_WINDOW_YEARS,
+    )
+}
+
+/// Change in headcount over the [`GROWTH_WINDOW_YEARS`] ending in the latest financial year.
+pub fn employee_change_3y_sql() -> String {
+    format!(
+        "{} - {}",
+        latest_metric_sql(EMPLOYEES_METRIC),
+        metric_years_before_latest_sql(EMPLOYEES_METRIC, GROWTH_WINDOW_YEARS)
+    )
+}
+
+/// Number of consecutive profitable years ending in the latest financial year.
+/// A year without a reported result breaks the streak.
+pub fn profitable_years_streak_sql() -> String {
+    let profitable: Vec<String> = metric_years(PROFIT_METRIC)
+        .iter()
+        .map(|year| format!("coalesce({} > 0, false)", metric_sql(PROFIT_METRIC, *year)))
+        .collect();
+    let first_year = FINANCIAL_YEARS[0];
+    let reported_years = format!("latest_financial_year - {}", first_year - 1);
+    format!(
+        "coalesce(list_position(list_reverse(list_slice([{}], 1, {})), false) - 1, {})",
+        profitable.join(", "),
+        reported_years,
+        reported_years
+    )
+}
+
+/// `VALUES` list with one row per year the metric exists in, exposed as
+/// `metric_data(<column>)`.
+fn metric_values_sql(metric: &str, column: &str, currency: Option<&str>) -> String {
+    let rows: Vec<String> = metric_years(metric)
+        .iter()
+        .map(|year| format!("({})", metric_in_currency_sql(metric, *year, currency)))
+        .collect();
+    format!("(VALUES {}) AS metric_data({})", rows.join(", "), column)
+}
+
+/// True when `predicate` (written against `column`) holds for at least one
+/// reported year of the metric, optionally converted to `currency`.
+pub fn any_year_sql(metric: &str, column: &str, predicate: &str, currency: Option<&str>) -> String {
+    format!(
+        "EXISTS (SELECT 1 FROM {} WHERE {} IS NOT NULL AND {})",
+        metric_values_sql(metric, column, currency),
+        column,
+        predicate
+    )
+}
+
+/// True when `predicate` (written against `column`) holds for every reported
+/// year of the metric, optionally converted to `currency`. NULL, and so
+/// filtered out, when nothing is reported.
+pub fn all_years_sql(
+    metric: &str,
+    column: &str,
+    predicate: &str,
+    currency: Option<&str>,
+) -> String {
+    format!(
+        "(SELECT bool_and({}) FROM {} WHERE {} IS NOT NULL)",
+        predicate,
+        metric_values_sql(metric, column, currency),
+        column
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_financials_prefers_newest_year() {
+        let year_sql = latest_financial_year_sql();
+        assert!(year_sql.starts_with(
+            "CASE WHEN (financial_data['2024']['Total operating revenues'] IS NOT NULL \
+             OR financial_data['2024']['Sales revenues'] IS NOT NULL \
+             OR financial_data['2024']['Total assets'] IS NOT NULL) THEN 2024 "
+        ));
+        assert!(
+            year_sql
+                .ends_with("OR financial_data['2016']['Total assets'] IS NOT NULL) THEN 2016 END")
+        );
+
+        let financials_sql = latest_financials_sql();
+        assert!(financials_sql.starts_with(&format!(
+            "CASE WHEN {} THEN STRUCT_PACK(",
+            has_accounts_sql(2024)
+        )));
+        assert_eq!(
+            financials_sql.matches("STRUCT_PACK(").count(),
+            FINANCIAL_YEARS.len()
+        );
+    }
+
+    #[test]
+    fn test_first_financial_year_prefers_oldest_year() {
+        let sql = first_financial_year_sql();
+        assert!(sql.starts_with(&format!("CASE WHEN {} THEN 2016 ", has_accounts_sql(2016))));
+        assert!(sql.ends_with(&format!("WHEN {} THEN 2024 END", has_accounts_sql(2024))));
+    }
+
+    #[test]
+    fn test_years_before_latest_skips_unknown_years() {
+        let sql = metric_years_before_latest_sql(REVENUE_METRIC, 3);
+
+        assert!(sql.starts_with(
+            "CASE latest_financial_year WHEN 2019 THEN financial_data['2016']['Sales revenues']"
+        ));
+        assert!(sql.ends_with("WHEN 2024 THEN financial_data['2021']['Sales revenues'] END"));
+        assert!(!sql.contains("WHEN 2018"));
+    }
+
+    #[test]
+    fn test_cagr_sql_guards_non_positive_values() {
+        assert_eq!(
+            cagr_sql("a", "b", 4),
+            "CASE WHEN a > 0 AND b > 0 THEN power(b / a, 1.0 / 4) - 1 END"
+        );
+    }
+
+    #[test]
+    fn test_year_struct_fills_missing_metrics() {
+        let sql = year_struct_sql(2017);
+
+        assert!(sql.contains(
+            "\"Allocation dividends\" := CAST(financial_data['2017']['Allocation dividends'] AS DOUBLE)"
+        ));
+        assert!(sql.contains(
+            "\"Minority interests\" := CAST(financial_data['2017']['Minority interests'] AS DOUBLE)"
+        ));
+        assert!(sql.contains("\"EBITDA\" := CAST(financial_data['2017']['EBITDA'] AS DOUBLE)"));
+        assert_eq!(sql.matches(" := ").count(), all_metrics().count());
+    }
+
+    #[test]
+    fn test_canonical_financial_data_has_every_year_and_metric() {
+        let sql = canonical_financial_data_sql("\"financiaL_data\"");
+
+        assert!(sql.contains(
+            "\"2016\" := CASE WHEN \"financiaL_data\"['2016'] IS NOT NULL THEN STRUCT_PACK("
+        ));
+        assert!(sql.contains(
+            "\"Allocation dividends\" := CAST(\"financiaL_data\"['2016']['Allocation dividends'] AS DOUBLE)"
+        ));
+        assert!(sql.contains("\"Minority interests\" := CAST(NULL AS DOUBLE)"));
+        assert!(sql.contains(
+            "\"EBITDA\" := CAST((\"financiaL_data\"['2024']['Result before depreciation']) AS DOUBLE)"
+        ));
+        assert_eq!(
+            sql.matches(" := ").count(),
+            FINANCIAL_YEARS.len() * (all_metrics().count() + 1)
+        );
+    }
+
+    #[test]
+    fn test_minority_interests_in_every_year() {
+        assert_eq!(metric_years("Minority interests"), FINANCIAL_YEARS);
+        assert!(any_year_sql("Minority interests", "value", "value > 0", None).contains("'2018'"));
+        assert!(!is_in_source("Minority interests", 2018));
+        assert!(is_in_source("Minority interests", 2019));
+        assert_eq!(
+            financial_data_json_structure()
+                .matches("\"Minority interests\"")
+                .count(),
+            6
+        );
+    }
+
+    #[test]
+    fn test_only_monetary_metrics_are_converted() {
+        assert_eq!(metric_kind(REVENUE_METRIC), MetricKind::Monetary);
+        assert_eq!(metric_kind("Revenue per employee"), MetricKind::Monetary);
+        assert_eq!(metric_kind("Operating margin"), MetricKind::Ratio);
+        assert_eq!(metric_kind(EMPLOYEES_METRIC), MetricKind::Count);
+
+        assert_eq!(
+            metric_in_currency_sql(REVENUE_METRIC, 2023, Some("EUR")),
+            "(financial_data['2023']['Sales revenues'] * fx_rate(2023, currency, 'EUR'))"
+        );
+        assert_eq!(
+            latest_metric_in_currency_sql(REVENUE_METRIC, Some("EUR")),
+            "(latest_financials['Sales revenues'] * fx_rate(latest_financial_year, currency, 'EUR'))"
+        );
+        assert_eq!(
+            metric_in_currency_sql("Operating margin", 2023, Some("EUR")),
+            metric_sql("Operating margin", 2023)
+        );
+        assert_eq!(
+            latest_metric_in_currency_sql(EMPLOYEES_METRIC, Some("EUR")),
+            latest_metric_sql(EMPLOYEES_METRIC)
+        );
+    }
+
+    #[test]
+    fn test_known_metrics() {
+        assert!(is_known_metric("Operating margin"));
+        assert!(!is_known_metric("operating margin"));
+        assert!(!is_known_metric(
+            "Sales revenues']; DROP TABLE hello_nest; --"
+        ));
+    }
+}
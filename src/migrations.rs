@@ -0,0 +1,500 @@
+//! Schema migrations for the database file.
+//!
+//! Columns and tables were added to the file over time (country and currency,
+//! derived metrics, coordinates, gazetteer locations, the reference tables,
+//! history, metadata) and `financial_data` got one struct type for all years.
+//! [`MIGRATIONS`] brings a file built by any earlier version up to date
+//! without re-reading the source: `migrate` applies the ones missing from
+//! `schema_version` in order, each in its own transaction. Every migration
+//! first checks whether the file already has what it adds, or only
+//! recomputes values, so running one twice is harmless.
+//!
+//! `create-db` builds the current schema and records every migration as
+//! applied. The server only opens files at [`SCHEMA_VERSION`], see
+//! [`check_compatible`].
+
+use anyhow::{Context, Result, bail};
+use std::cmp::Ordering;
+
+use crate::{country::Country, dataset, derived, duckdb::DuckDB, history, metrics, views};
+
+const SCHEMA_VERSION_TABLE_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS schema_version (
+        version BIGINT PRIMARY KEY,
+        name VARCHAR,
+        applied_at TIMESTAMP
+    );
+"#;
+
+pub enum Step {
+    Sql(&'static str),
+    Rust(fn(&DuckDB) -> Result<()>),
+}
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub step: Step,
+}
+
+/// Every migration, oldest first. Append new ones; never change or reorder
+/// released ones.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "country_and_currency",
+        step: Step::Rust(add_country_and_currency),
+    },
+    Migration {
+        version: 2,
+        name: "derived_metrics",
+        step: Step::Rust(add_derived_metrics),
+    },
+    Migration {
+        version: 3,
+        name: "financial_summary",
+        step: Step::Rust(add_financial_summary),
+    },
+    Migration {
+        version: 4,
+        name: "wgs84_coordinates",
+        step: Step::Rust(add_wgs84_coordinates),
+    },
+    Migration {
+        version: 5,
+        name: "gazetteer_locations",
+        step: Step::Rust(add_gazetteer_locations),
+    },
+    Migration {
+        version: 6,
+        name: "reference_tables",
+        step: Step::Rust(create_reference_tables),
+    },
+    Migration {
+        version: 7,
+        name: "company_history",
+        step: Step::Rust(create_company_history),
+    },
+    Migration {
+        version: 8,
+        name: "dataset_metadata",
+        step: Step::Sql(dataset::METADATA_TABLE_SQL),
+    },
+    Migration {
+        version: 9,
+        name: "canonical_financial_data",
+        step: Step::Rust(canonical_financial_data),
+    },
+    Migration {
+        version: 10,
+        name: "latest_year_from_accounts",
+        step: Step::Rust(recompute_financial_summary),
+    },
+    Migration {
+        version: 11,
+        name: "drop_account_codes",
+        step: Step::Sql("DROP TABLE IF EXISTS account_codes"),
+    },
+    Migration {
+        version: 12,
+        name: "ebitda_from_result_before_depreciation",
+        step: Step::Rust(recompute_derived_metrics),
+    },
+];
+
+/// The schema version this build reads and writes
+pub const SCHEMA_VERSION: i64 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+fn has_table(db: &DuckDB, table: &str) -> Result<bool> {
+    let tables = db
+        .query_one(
+            &format!(
+                "SELECT count(*) FROM information_schema.tables \
+                 WHERE table_catalog = current_database() AND table_schema = 'main' \
+                 AND table_name = '{}'",
+                table
+            ),
+            |row| Ok(row.get::<_, i64>(0)?),
+        )?
+        .unwrap_or(0);
+    Ok(tables > 0)
+}
+
+/// Data type of a column of `table`, `None` if there is no such column
+fn column_type(db: &DuckDB, table: &str, column: &str) -> Result<Option<String>> {
+    db.query_one(
+        &format!(
+            "SELECT data_type FROM information_schema.columns \
+             WHERE table_catalog = current_database() AND table_schema = 'main' \
+             AND table_name = '{}' AND column_name = '{}'",
+            table, column
+        ),
+        |row| Ok(row.get::<_, String>(0)?),
+    )
+}
+
+/// Rewrite `table` as `select` over the current one. Struct columns can
+/// only change type this way, not with `ALTER TABLE`.
+fn rebuild(db: &DuckDB, table: &str, select: &str) -> Result<()> {
+    db.execute(&format!(
+        "CREATE TABLE {table}_migrated AS {select} FROM {table}"
+    ))?;
+    db.execute(&format!("DROP TABLE {table}"))?;
+    db.execute(&format!("ALTER TABLE {table}_migrated RENAME TO {table}"))?;
+    Ok(())
+}
+
+fn add_country_and_currency(db: &DuckDB) -> Result<()> {
+    if column_type(db, "hello_nest", "country")?.is_some() {
+        return Ok(());
+    }
+    // Files from before the country column were built from the Swedish
+    // hello_nest.parquet
+    rebuild(
+        db,
+        "hello_nest",
+        &format!(
+            "SELECT *, '{}' AS country, '{}' AS currency",
+            Country::Sweden.code(),
+            Country::Sweden.currency()
+        ),
+    )
+}
+
+fn add_derived_metrics(db: &DuckDB) -> Result<()> {
+    let financial_data = column_type(db, "hello_nest", "financial_data")?.unwrap_or_default();
+    if derived::DERIVED_METRICS
+        .iter()
+        .all(|formula| financial_data.contains(formula.metric))
+    {
+        return Ok(());
+    }
+    rebuild(
+        db,
+        "hello_nest",
+        &format!(
+            "SELECT * REPLACE ({} AS financial_data)",
+            derived::financial_data_with_derived_sql("financial_data")
+        ),
+    )
+}
+
+fn add_financial_summary(db: &DuckDB) -> Result<()> {
+    if column_type(db, "hello_nest", "latest_financial_year")?.is_some() {
+        return Ok(());
+    }
+    rebuild(
+        db,
+        "hello_nest",
+        &format!(
+            "SELECT *, {} AS latest_financial_year, {} AS latest_financials, \
+             {} AS revenue_cagr_3y, {} AS employee_change_3y, {} AS profitable_years_streak",
+            metrics::latest_financial_year_sql(),
+            metrics::latest_financials_sql(),
+            metrics::revenue_cagr_3y_sql(),
+            metrics::employee_change_3y_sql(),
+            metrics::profitable_years_streak_sql()
+        ),
+    )
+}
+
+fn add_wgs84_coordinates(db: &DuckDB) -> Result<()> {
+    if column_type(db, "hello_nest", "lat")?.is_some() {
+        return Ok(());
+    }
+    db.add_wgs84_coordinates("hello_nest")
+}
+
+fn add_gazetteer_locations(db: &DuckDB) -> Result<()> {
+    db.create_gazetteer_tables()?;
+    if column_type(db, "hello_nest", "county_code")?.is_some() {
+        return Ok(());
+    }
+    db.add_normalized_locations("hello_nest")
+}
+
+fn create_reference_tables(db: &DuckDB) -> Result<()> {
+    db.create_reference_tables()
+}
+
+fn create_company_history(db: &DuckDB) -> Result<()> {
+    // Views over parquet keep no history
+    if has_table(db, "company_history")? || db.is_view_mode()? {
+        return Ok(());
+    }
+    history::sync(db)?;
+    Ok(())
+}
+
+/// Give every year of `financial_data` the same struct type, in hello_nest
+/// and in its history, and recreate the derived views over the new type. A
+/// hello_nest view gets it when `create-db` recreates the view.
+fn canonical_financial_data(db: &DuckDB) -> Result<()> {
+    let mut rebuilt = false;
+    for table in ["hello_nest", "company_history"] {
+        if table == "hello_nest" && db.is_view_mode()? {
+            continue;
+        }
+        let Some(financial_data) = column_type(db, table, "financial_data")? else {
+            continue;
+        };
+        let canonical = financial_data
+            .matches("\"Minority interests\" DOUBLE")
+            .count()
+            == metrics::FINANCIAL_YEARS.len()
+            && !financial_data.contains("INTEGER");
+        if !canonical {
+            rebuild(
+                db,
+                table,
+                &format!(
+                    "SELECT * REPLACE ({} AS financial_data)",
+                    metrics::canonical_financial_data_sql("financial_data")
+                ),
+            )?;
+            rebuilt = true;
+        }
+    }
+    if rebuilt && has_table(db, "derived_views")? {
+        views::create(db)?;
+    }
+    Ok(())
+}
+
+/// Rewrite hello_nest (unless it is a view) and its history with each of
+/// `selects` in turn, and recreate the derived views over them
+fn rebuild_companies(db: &DuckDB, selects: &[String]) -> Result<()> {
+    for table in ["hello_nest", "company_history"] {
+        let skip = match table {
+            "hello_nest" => db.is_view_mode()?,
+            _ => !has_table(db, table)?,
+        };
+        if skip {
+            continue;
+        }
+        for select in selects {
+            rebuild(db, table, select)?;
+        }
+    }
+    if has_table(db, "derived_views")? {
+        views::create(db)?;
+    }
+    Ok(())
+}
+
+/// Recompute the latest financial year, which used to be the newest year
+/// with a non-NULL struct even when all its metrics were NULL, and the
+/// columns that depend on it. Each step reads the one before.
+fn recompute_financial_summary(db: &DuckDB) -> Result<()> {
+    rebuild_companies(
+        db,
+        &[
+            format!(
+                "SELECT * REPLACE ({} AS latest_financial_year)",
+                metrics::latest_financial_year_sql()
+            ),
+            format!(
+                "SELECT * REPLACE ({} AS latest_financials)",
+                metrics::latest_financials_sql()
+            ),
+            format!(
+                "SELECT * REPLACE ({} AS revenue_cagr_3y, {} AS employee_change_3y, \
+                 {} AS profitable_years_streak)",
+                metrics::revenue_cagr_3y_sql(),
+                metrics::employee_change_3y_sql(),
+                metrics::profitable_years_streak_sql()
+            ),
+        ],
+    )
+}
+
+/// Recompute the derived metrics, since EBITDA used to be Total operating
+/// revenues + Total operating expenses, and the latest financials that
+/// include them
+fn recompute_derived_metrics(db: &DuckDB) -> Result<()> {
+    rebuild_companies(
+        db,
+        &[
+            format!(
+                "SELECT * REPLACE ({} AS financial_data)",
+                metrics::canonical_financial_data_sql("financial_data")
+            ),
+            format!(
+                "SELECT * REPLACE ({} AS latest_financials)",
+                metrics::latest_financials_sql()
+            ),
+        ],
+    )
+}
+
+/// Newest migration recorded in `schema_version`, 0 for a file without one
+pub fn current_version(db: &DuckDB) -> Result<i64> {
+    if !has_table(db, "schema_version")? {
+        return Ok(0);
+    }
+    Ok(db
+        .query_one("SELECT max(version) FROM schema_version", |row| {
+            Ok(row.get::<_, Option<i64>>(0)?)
+        })?
+        .flatten()
+        .unwrap_or(0))
+}
+
+fn record(db: &DuckDB, migration: &Migration) -> Result<()> {
+    db.execute(&format!(
+        "INSERT INTO schema_version VALUES ({}, '{}', now()::TIMESTAMP)",
+        migration.version, migration.name
+    ))?;
+    Ok(())
+}
+
+fn apply(db: &DuckDB, migration: &Migration) -> Result<()> {
+    match &migration.step {
+        Step::Sql(sql) => {
+            db.execute(sql)?;
+        }
+        Step::Rust(step) => step(db)?,
+    }
+    record(db, migration)
+}
+
+/// Apply the migrations newer than the file's version, in order. Returns the
+/// ones applied.
+pub fn migrate(db: &DuckDB) -> Result<Vec<&'static Migration>> {
+    db.execute(SCHEMA_VERSION_TABLE_SQL)?;
+    let current = current_version(db)?;
+    if current > SCHEMA_VERSION {
+        bail!(
+            "Database schema version {} is newer than version {} this build knows; upgrade nest-mcp",
+            current,
+            SCHEMA_VERSION
+        );
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        db.execute("BEGIN TRANSACTION")?;
+        match apply(db, migration) {
+            Ok(()) => db.execute("COMMIT")?,
+            Err(e) => {
+                db.execute("ROLLBACK")?;
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to apply migration {} ({})",
+                        migration.version, migration.name
+                    )
+                });
+            }
+        };
+        tracing::info!(
+            version = migration.version,
+            name = migration.name,
+            "Applied migration"
+        );
+        applied.push(migration);
+    }
+    Ok(applied)
+}
+
+/// Record every migration as applied, for a file just built with the current
+/// schema
+pub fn record_current(db: &DuckDB) -> Result<()> {
+    db.execute(SCHEMA_VERSION_TABLE_SQL)?;
+    let current = current_version(db)?;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        record(db, migration)?;
+    }
+    Ok(())
+}
+
+/// Fail unless the file is at [`SCHEMA_VERSION`], with what to do about it
+pub fn check_compatible(db: &DuckDB) -> Result<()> {
+    let version = current_version(db)?;
+    match version.cmp(&SCHEMA_VERSION) {
+        Ordering::Equal => Ok(()),
+        Ordering::Less => bail!(
+            "Database schema version {} is older than version {} this build needs; \
+             run `nest-mcp migrate` on a copy of the file and rename it over this one, \
+             or rebuild it with `nest-mcp create-db`",
+            version,
+            SCHEMA_VERSION
+        ),
+        Ordering::Greater => bail!(
+            "Database schema version {} is newer than version {} this build supports; \
+             upgrade nest-mcp",
+            version,
+            SCHEMA_VERSION
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duckdb::test_support::*;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        let versions: Vec<i64> = MIGRATIONS.iter().map(|m| m.version).collect();
+        let expected: Vec<i64> = (1..=MIGRATIONS.len() as i64).collect();
+        assert_eq!(versions, expected);
+        assert_eq!(SCHEMA_VERSION, MIGRATIONS.len() as i64);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_baseline_file() -> Result<()> {
+        let db = create_test_db("migrate")?;
+        db.register_macros();
+        assert!(check_compatible(&db).is_err());
+
+        // The columns of the first hello_nest tables, before any migration
+        db.execute(&format!(
+            r#"CREATE TABLE hello_nest AS
+               SELECT 1 AS company_id, 'Alpha AB' AS company_name, 5560000001 AS organization_number,
+                   '["62010 Dataprogrammering"]' AS nace_categories,
+                   {{'county': 'Stockholms Län', 'coordinates': {{'XCoordinate': 18.0, 'YCoordinate': 59.36, 'coordinateSystem': 'EPSG:4326'}},
+                     'countryPart': 'Östra Sverige', 'municipality': 'Solna'}} AS location,
+                   {} AS financial_data"#,
+            financial_data_sql(&[
+                (2024, "Total operating revenues", 1000.0),
+                (2024, "Total operating expenses", -900.0),
+                (2024, "Result before depreciation", 150.0),
+            ])
+        ))?;
+
+        let applied = migrate(&db)?;
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(current_version(&db)?, SCHEMA_VERSION);
+        check_compatible(&db)?;
+
+        let company = db.query_one(
+            "SELECT concat_ws('/', country, currency, county_code, municipality, latest_financial_year, \
+             financial_data['2024']['EBITDA'], round(lat, 2)) FROM hello_nest",
+            |row| Ok(row.get::<_, String>(0)?),
+        )?;
+        assert_eq!(company.as_deref(), Some("SE/SEK/01/Solna/2024/150.0/59.36"));
+        for table in [
+            "fx_rates",
+            "ratio_mismatches",
+            "change_log",
+            "company_history",
+            "dataset_metadata",
+        ] {
+            db.query_one(&format!("SELECT count(*) FROM {}", table), |row| {
+                Ok(row.get::<_, i64>(0)?)
+            })?;
+        }
+        let canonical = db.query_one(
+            "SELECT typeof(financial_data['2016']) = typeof(financial_data['2024']) FROM hello_nest",
+            |row| Ok(row.get::<_, bool>(0)?),
+        )?;
+        assert_eq!(canonical, Some(true));
+        assert!(views::validate(&db)?.passed);
+
+        // Migrations already applied are not run again
+        assert!(migrate(&db)?.is_empty());
+
+        cleanup_test_db("migrate");
+        Ok(())
+    }
+}
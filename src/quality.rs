@@ -0,0 +1,380 @@
+//! Data-quality checks over `hello_nest`.
+//!
+//! The source accounts mix units (the converter notes values are "sometimes
+//! percentage, sometimes SEK, sometimes MSEK"), so these checks look for the
+//! symptoms: implausible revenue per employee, ratios far outside any sensible
+//! range or away from their recomputed value, balance sheets that do not
+//! balance, gaps between reported years and the share of NULLs per metric and
+//! year.
+//!
+//! Every check is a findings query with one row per offending company and
+//! year, which [`report`] summarises into counts and a few examples.
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{derived, duckdb::DuckDB, metrics};
+
+/// Revenue per employee outside this range (in EUR, converted with the
+/// year's average rate) is implausible and usually a unit mix-up.
+pub const REVENUE_PER_EMPLOYEE_RANGE_EUR: (f64, f64) = (10_000.0, 5_000_000.0);
+/// Ratios are stored as fractions, so anything beyond ±10 (±1000%) is suspect.
+pub const RATIO_BOUND: f64 = 10.0;
+/// Relative difference allowed between total assets and total liabilities
+/// and equity before the balance sheet counts as broken.
+pub const BALANCE_TOLERANCE: f64 = 0.01;
+
+pub const DEFAULT_EXAMPLES: u32 = 5;
+pub const MAX_EXAMPLES: u32 = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub description: String,
+    /// Query with `company_id`, `company_name`, `year`, `metric` and `value`
+    /// columns, one row per finding
+    pub findings_sql: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QualityReport {
+    pub companies: i64,
+    pub companies_without_financials: i64,
+    pub checks: Vec<CheckReport>,
+    /// Non-NULL share of every metric among the companies reporting each year
+    pub coverage: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    pub check: &'static str,
+    pub description: String,
+    pub violations: i64,
+    pub companies: i64,
+    pub by_metric_year: Value,
+    pub examples: Value,
+}
+
+/// One `SELECT` per year joined with `UNION ALL`, skipping years `select`
+/// returns `None` for.
+fn union_years(select: impl Fn(i64) -> Option<String>) -> String {
+    let selects: Vec<String> = metrics::FINANCIAL_YEARS
+        .iter()
+        .filter_map(|year| select(*year))
+        .collect();
+    selects.join(" UNION ALL ")
+}
+
+/// Sales revenues per accounted employee, in EUR, outside
+/// [`REVENUE_PER_EMPLOYEE_RANGE_EUR`]
+pub fn revenue_per_employee_check() -> Check {
+    let (min, max) = REVENUE_PER_EMPLOYEE_RANGE_EUR;
+    let findings_sql = union_years(|year| {
+        let revenue = metrics::metric_sql(metrics::REVENUE_METRIC, year);
+        let employees = metrics::metric_sql(metrics::EMPLOYEES_METRIC, year);
+        let per_employee = format!(
+            "{} / {} * fx_rate({}, currency, 'EUR')",
+            revenue, employees, year
+        );
+        Some(format!(
+            "SELECT company_id, company_name, {year} AS year, 'Revenue per employee' AS metric, \
+             {per_employee} AS value, currency, {revenue} AS revenue, {employees} AS employees \
+             FROM hello_nest \
+             WHERE {employees} > 0 AND {revenue} IS NOT NULL \
+             AND {per_employee} NOT BETWEEN {min} AND {max}"
+        ))
+    });
+    Check {
+        name: "revenue_per_employee",
+        description: format!(
+            "Sales revenues / Employees from accounting outside {} - {} EUR, usually revenue or headcount in the wrong unit",
+            min, max
+        ),
+        findings_sql,
+    }
+}
+
+/// Ratio metrics beyond ±[`RATIO_BOUND`]
+pub fn ratio_range_check() -> Check {
+    let selects: Vec<String> = metrics::RATIO_METRICS
+        .iter()
+        .map(|metric| {
+            union_years(|year| {
+                let value = metrics::metric_sql(metric, year);
+                Some(format!(
+                    "SELECT company_id, company_name, {year} AS year, '{metric}' AS metric, \
+                     {value} AS value FROM hello_nest WHERE abs({value}) > {RATIO_BOUND}"
+                ))
+            })
+        })
+        .collect();
+    Check {
+        name: "ratio_out_of_range",
+        description: format!(
+            "Ratios outside [-{bound}, {bound}]; they are stored as fractions, so these are likely percentages or amounts",
+            bound = RATIO_BOUND
+        ),
+        findings_sql: selects.join(" UNION ALL "),
+    }
+}
+
+/// Total assets that differ from total liabilities and equity by more than
+/// [`BALANCE_TOLERANCE`]. `ratio` shows unit mix-ups, e.g. 1000 for a
+/// balance sheet with one side in thousands.
+pub fn balance_sheet_check() -> Check {
+    let findings_sql = union_years(|year| {
+        let assets = metrics::metric_sql(metrics::TOTAL_ASSETS_METRIC, year);
+        let liabilities = metrics::metric_sql(metrics::TOTAL_LIABILITIES_AND_EQUITY_METRIC, year);
+        Some(format!(
+            "SELECT company_id, company_name, {year} AS year, '{metric}' AS metric, \
+             {liabilities} AS value, {assets} AS total_assets, \
+             round({assets} / nullif({liabilities}, 0), 3) AS ratio \
+             FROM hello_nest \
+             WHERE abs({assets} - {liabilities}) > {BALANCE_TOLERANCE} * greatest(abs({assets}), abs({liabilities}))",
+            metric = metrics::TOTAL_LIABILITIES_AND_EQUITY_METRIC
+        ))
+    });
+    Check {
+        name: "balance_sheet_identity",
+        description: format!(
+            "Total assets differs from Total liabilities and equity by more than {}%; `ratio` is assets / (liabilities and equity)",
+            BALANCE_TOLERANCE * 100.0
+        ),
+        findings_sql,
+    }
+}
+
+/// Years without accounts between a company's first and latest reported year
+pub fn missing_years_check() -> Check {
+    let first_year = metrics::first_financial_year_sql();
+    let findings_sql = union_years(|year| {
+        Some(format!(
+            "SELECT company_id, company_name, {year} AS year, 'financial_data' AS metric, \
+             CAST(NULL AS DOUBLE) AS value, {first_year} AS first_financial_year, latest_financial_year \
+             FROM hello_nest \
+             WHERE NOT {has_accounts} AND {first_year} < {year} AND latest_financial_year > {year}",
+            has_accounts = metrics::has_accounts_sql(year)
+        ))
+    });
+    Check {
+        name: "missing_years",
+        description:
+            "Years without accounts between the company's first and latest reported financial year"
+                .to_string(),
+        findings_sql,
+    }
+}
+
+/// Reported ratios that disagree with the value recomputed from base amounts
+pub fn ratio_mismatch_check() -> Check {
+    Check {
+        name: "ratio_mismatch",
+        description: format!(
+            "Operating margin, solvency ratio and Debt ratio more than {}% away from their value recomputed from base amounts; `scale` is reported / computed",
+            derived::MISMATCH_RELATIVE_TOLERANCE * 100.0
+        ),
+        findings_sql: derived::ratio_mismatches_sql(),
+    }
+}
+
+pub fn checks() -> Vec<Check> {
+    vec![
+        revenue_per_employee_check(),
+        ratio_range_check(),
+        balance_sheet_check(),
+        ratio_mismatch_check(),
+        missing_years_check(),
+    ]
+}
+
+/// Per metric and year: companies with accounts for the year (see
+/// [`metrics::has_accounts_sql`]), how many of them have the metric, and that
+/// share as `coverage`.
+pub fn coverage_sql() -> String {
+    let per_year: Vec<String> = metrics::FINANCIAL_YEARS
+        .iter()
+        .map(|year| {
+            let has_accounts = metrics::has_accounts_sql(*year);
+            let counts: Vec<String> = metrics::FINANCIAL_METRICS
+                .iter()
+                .map(|metric| {
+                    format!(
+                        "{{'metric': '{}', 'non_null': count({}) FILTER (WHERE {})}}",
+                        metric,
+                        metrics::metric_sql(metric, *year),
+                        has_accounts
+                    )
+                })
+                .collect();
+            format!(
+                "SELECT {year} AS year, count(*) FILTER (WHERE {has_accounts}) AS reporting, [{}] AS metrics FROM hello_nest",
+                counts.join(", ")
+            )
+        })
+        .collect();
+    format!(
+        "SELECT metric, year, reporting, non_null, round(non_null / nullif(reporting, 0), 4) AS coverage \
+         FROM (SELECT year, reporting, unnest(metrics, recursive := true) FROM ({})) \
+         ORDER BY metric, year",
+        per_year.join(" UNION ALL ")
+    )
+}
+
+fn check_report(db: &DuckDB, check: Check, examples: u32) -> Result<CheckReport> {
+    let findings = &check.findings_sql;
+    let (violations, companies) = db
+        .query_one(
+            &format!(
+                "SELECT count(*), count(DISTINCT company_id) FROM ({})",
+                findings
+            ),
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+        )?
+        .unwrap_or_default();
+    let by_metric_year = db.query_all_value(&format!(
+        "SELECT metric, year, count(*) AS violations FROM ({}) GROUP BY metric, year ORDER BY metric, year",
+        findings
+    ))?;
+    let examples = db.query_all_value(&format!(
+        "SELECT * FROM ({}) ORDER BY year DESC, company_id LIMIT {}",
+        findings, examples
+    ))?;
+    Ok(CheckReport {
+        check: check.name,
+        description: check.description,
+        violations,
+        companies,
+        by_metric_year,
+        examples,
+    })
+}
+
+/// Run every check against `hello_nest`, with up to `examples` offending rows
+/// per check.
+pub fn report(db: &DuckDB, examples: u32) -> Result<QualityReport> {
+    let examples = examples.min(MAX_EXAMPLES);
+    let (companies, companies_without_financials) = db
+        .query_one(
+            "SELECT count(*), count(*) FILTER (WHERE latest_financial_year IS NULL) FROM hello_nest",
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+        )?
+        .unwrap_or_default();
+    let checks = checks()
+        .into_iter()
+        .map(|check| check_report(db, check, examples))
+        .collect::<Result<Vec<_>>>()?;
+    let coverage = db.query_all_value(&coverage_sql())?;
+    Ok(QualityReport {
+        companies,
+        companies_without_financials,
+        checks,
+        coverage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derived;
+    use crate::duckdb::test_support::*;
+
+    #[test]
+    fn test_ratio_check_covers_every_ratio_year() {
+        let sql = ratio_range_check().findings_sql;
+        let reported: usize = metrics::RATIO_METRICS
+            .iter()
+            .map(|metric| metrics::metric_years(metric).len())
+            .sum();
+        assert_eq!(sql.matches("SELECT ").count(), reported);
+        assert!(sql.contains("abs(financial_data['2024']['Operating margin']) > 10"));
+    }
+
+    #[test]
+    fn test_balance_sheet_check_uses_relative_tolerance() {
+        let sql = balance_sheet_check().findings_sql;
+        assert!(sql.contains(
+            "abs(financial_data['2016']['Total assets'] - financial_data['2016']['Total liabilities and equity']) > 0.01 * greatest("
+        ));
+        assert_eq!(
+            sql.matches(" UNION ALL ").count(),
+            metrics::FINANCIAL_YEARS.len() - 1
+        );
+    }
+
+    #[test]
+    fn test_coverage_has_every_metric_and_year() {
+        let sql = coverage_sql();
+        assert!(
+            sql.contains("count(financial_data['2018']['Minority interests']) FILTER (WHERE (")
+        );
+        assert!(sql.contains("count(*) FILTER (WHERE (financial_data['2024']['Total operating revenues'] IS NOT NULL OR "));
+        assert_eq!(
+            sql.matches("'non_null': ").count(),
+            metrics::FINANCIAL_METRICS.len() * metrics::FINANCIAL_YEARS.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_data_quality_checks() -> Result<()> {
+        let db = create_test_db("data_quality")?;
+        db.register_macros();
+
+        let plausible = derived::financial_data_with_derived_sql(&financial_data_sql(&[
+            (2022, metrics::REVENUE_METRIC, 9e6),
+            (2024, metrics::REVENUE_METRIC, 1e7),
+            (2024, metrics::EMPLOYEES_METRIC, 10.0),
+            (2024, "Operating margin", 0.1),
+            (2024, "Total assets", 5e6),
+            (2024, "Total liabilities and equity", 5e3),
+        ]));
+        let implausible = derived::financial_data_with_derived_sql(&financial_data_sql(&[
+            (2024, metrics::REVENUE_METRIC, 1e4),
+            (2024, metrics::EMPLOYEES_METRIC, 10.0),
+            (2024, "Operating margin", 15.0),
+            (2024, "Total assets", 1e6),
+            (2024, "Total liabilities and equity", 1e6),
+        ]));
+        let no_accounts = derived::financial_data_with_derived_sql(&financial_data_sql(&[(
+            2024, EMPTY_YEAR, 0.0,
+        )]));
+        db.execute(&format!(
+            "CREATE TABLE hello_nest AS \
+             SELECT 1 AS company_id, 'Plausible AB' AS company_name, 'SEK' AS currency, 2024 AS latest_financial_year, {} AS financial_data \
+             UNION ALL SELECT 2, 'Implausible AB', 'SEK', 2024, {} \
+             UNION ALL SELECT 3, 'Empty AB', 'SEK', NULL, {}",
+            plausible, implausible, no_accounts
+        ))?;
+
+        // Company 1 has its liabilities in thousands and no 2023 accounts
+        for (check, company_id) in [
+            ("revenue_per_employee", 2),
+            ("ratio_out_of_range", 2),
+            ("balance_sheet_identity", 1),
+            ("missing_years", 1),
+        ] {
+            let check = checks().into_iter().find(|c| c.name == check).unwrap();
+            let company_ids = db.query_all(
+                &format!(
+                    "SELECT company_id FROM ({}) ORDER BY company_id",
+                    check.findings_sql
+                ),
+                |row| Ok(row.get::<_, i64>(0)?),
+            )?;
+            assert_eq!(company_ids, vec![company_id], "{}", check.name);
+        }
+
+        let coverage = db.query_all(
+            &format!(
+                "SELECT reporting, non_null FROM ({}) WHERE metric = 'Operating margin' AND year = 2024",
+                coverage_sql()
+            ),
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+        )?;
+        // Empty AB's 2024 struct has no accounts and does not count
+        assert_eq!(coverage, vec![(2, 2)]);
+
+        cleanup_test_db("data_quality");
+        Ok(())
+    }
+}
@@ -0,0 +1,414 @@
+//! Read-only database snapshots the server can swap without restarting.
+//!
+//! Every tool call borrows a [`Connection`] to the current [`Snapshot`].
+//! [`Snapshots::reload`] opens the database file again and swaps the new
+//! snapshot in; connections already handed out keep the old one open until
+//! their query finishes.
+//!
+//! At most `database.pool_size` connections are out at once, and each is
+//! interrupted after `query.timeout_secs`.
+//!
+//! A reload is triggered by a change to the file's modification time or size
+//! (polled every `server.reload_interval_secs`), by `SIGHUP`, or by
+//! `POST /admin/reload`. An open read-only database blocks writers to the same
+//! file, so refresh it by writing a new file and renaming it over the old path.
+//! Files at another schema version than this build's are refused, at startup
+//! and on reload (see [`crate::migrations`]); polling does not retry a refused
+//! file until it changes again.
+
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::Config,
+    dataset::{self, DatasetMetadata},
+    duckdb::DuckDB,
+    migrations,
+};
+
+/// What the file looked like when a snapshot was opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileVersion {
+    modified: SystemTime,
+    len: u64,
+}
+
+fn file_version(path: &Path) -> Option<FileVersion> {
+    let metadata = fs::metadata(path).ok()?;
+    Some(FileVersion {
+        modified: metadata.modified().ok()?,
+        len: metadata.len(),
+    })
+}
+
+/// One opened database file
+pub struct Snapshot {
+    db: Mutex<DuckDB>,
+    version: Option<FileVersion>,
+    pub loaded_at: SystemTime,
+    /// The build recorded in `dataset_metadata`, if any
+    pub dataset: Option<DatasetMetadata>,
+}
+
+/// A connection to a snapshot, which keeps the snapshot open and holds a
+/// place in the pool while alive
+pub struct Connection {
+    db: DuckDB,
+    snapshot: Arc<Snapshot>,
+    timeout: Option<JoinHandle<()>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(timeout) = self.timeout.take() {
+            timeout.abort();
+        }
+    }
+}
+
+impl Connection {
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+}
+
+impl Deref for Connection {
+    type Target = DuckDB;
+
+    fn deref(&self) -> &DuckDB {
+        &self.db
+    }
+}
+
+pub struct Snapshots {
+    config: Config,
+    path: PathBuf,
+    current: RwLock<Arc<Snapshot>>,
+    pool: Arc<Semaphore>,
+    /// Serialises reloads so two triggers don't open the file twice
+    reloading: tokio::sync::Mutex<()>,
+    /// The file the last failed reload from [`Self::watch`] opened, so a bad
+    /// file is reported once instead of on every poll
+    failed: Mutex<Option<FileVersion>>,
+}
+
+impl Snapshots {
+    /// Open the configured database
+    pub async fn open(config: &Config) -> Result<Self> {
+        let path = config.duckdb_config().db_path();
+        let snapshot = Self::open_snapshot(config, &path).await?;
+        Ok(Self {
+            config: config.clone(),
+            path,
+            current: RwLock::new(Arc::new(snapshot)),
+            pool: Arc::new(Semaphore::new(config.database.pool_size as usize)),
+            reloading: tokio::sync::Mutex::new(()),
+            failed: Mutex::new(None),
+        })
+    }
+
+    async fn open_snapshot(config: &Config, path: &Path) -> Result<Snapshot> {
+        let version = file_version(path);
+        let db = DuckDB::new(config.duckdb_config())
+            .await
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        migrations::check_compatible(&db)
+            .with_context(|| format!("Cannot serve {}", path.display()))?;
+        let dataset = dataset::current(&db).unwrap_or_else(|e| {
+            tracing::warn!(error = %e, "Failed to read dataset metadata");
+            None
+        });
+        Ok(Snapshot {
+            db: Mutex::new(db),
+            version,
+            loaded_at: SystemTime::now(),
+            dataset,
+        })
+    }
+
+    fn current(&self) -> Arc<Snapshot> {
+        self.current
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// The build recorded in the current snapshot's `dataset_metadata`
+    pub fn dataset(&self) -> Option<DatasetMetadata> {
+        self.current().dataset.clone()
+    }
+
+    /// A new connection to the current snapshot, once the pool has room
+    pub async fn connect(&self) -> Result<Connection> {
+        let permit = self.pool.clone().acquire_owned().await?;
+        let snapshot = self.current();
+        let db = snapshot
+            .db
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .try_clone()?;
+        let timeout = self.config.query.timeout().map(|timeout| {
+            let interrupt = db.interrupt_handle();
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                tracing::warn!(?timeout, "Interrupting query that ran past the timeout");
+                interrupt.interrupt();
+            })
+        });
+        Ok(Connection {
+            db,
+            snapshot,
+            timeout,
+            _permit: permit,
+        })
+    }
+
+    /// Open the database file again and swap it in. On failure the current
+    /// snapshot keeps serving.
+    pub async fn reload(&self) -> Result<SystemTime> {
+        let _reloading = self.reloading.lock().await;
+        let snapshot = Arc::new(Self::open_snapshot(&self.config, &self.path).await?);
+        let loaded_at = snapshot.loaded_at;
+        *self
+            .current
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = snapshot.clone();
+        tracing::info!(
+            path = %self.path.display(),
+            dataset = snapshot.dataset.as_ref().map(|dataset| dataset.version.as_str()),
+            "Reloaded database snapshot"
+        );
+        Ok(loaded_at)
+    }
+
+    /// Whether the file differs from the one the current snapshot opened
+    pub fn is_stale(&self) -> bool {
+        let version = file_version(&self.path);
+        version.is_some() && version != self.current().version
+    }
+
+    /// Reload if the file is stale and not the one the last failed reload
+    /// opened. Returns whether a new snapshot was swapped in.
+    async fn reload_if_changed(&self) -> Result<bool> {
+        let version = file_version(&self.path);
+        let failed = *self
+            .failed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !self.is_stale() || version == failed {
+            return Ok(false);
+        }
+        let reloaded = self.reload().await;
+        *self
+            .failed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+            reloaded.is_err().then_some(version).flatten();
+        reloaded.map(|_| true)
+    }
+
+    /// Reload whenever the file changes, every `interval`, until `ct` is
+    /// cancelled
+    pub fn watch(self: Arc<Self>, interval: Duration, ct: CancellationToken) {
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = ct.cancelled() => break,
+                    _ = ticks.tick() => {}
+                }
+                if let Err(e) = self.reload_if_changed().await {
+                    tracing::error!(error = %e, "Failed to reload changed database");
+                }
+            }
+        });
+    }
+
+    /// Reload on every `SIGHUP` until `ct` is cancelled
+    #[cfg(unix)]
+    pub fn reload_on_sighup(self: Arc<Self>, ct: CancellationToken) -> Result<()> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangups = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = ct.cancelled() => break,
+                    received = hangups.recv() => if received.is_none() { break },
+                }
+                if let Err(e) = self.reload().await {
+                    tracing::error!(error = %e, "Failed to reload database on SIGHUP");
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::AccessMode, source::DEFAULT_SOURCE};
+    use std::time::Instant;
+
+    fn test_dir(name: &str) -> Result<PathBuf> {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    /// The configuration serving `file` in `dir`
+    fn test_config(dir: &Path, file: &str) -> Config {
+        let mut config = Config::default();
+        config.database.temp_directory = Some(dir.to_path_buf());
+        config.database.path = PathBuf::from(file);
+        config
+    }
+
+    async fn open_read_write(dir: &Path, file: &str) -> Result<DuckDB> {
+        let mut config = test_config(dir, file);
+        config.database.access_mode = AccessMode::ReadWrite;
+        config.source.uri = dir.join(format!("{}.parquet", file)).display().to_string();
+        DuckDB::new(config.duckdb_config()).await
+    }
+
+    /// Build `file` in `dir` like `create-db` does, from the first
+    /// `companies` companies of the bundled parquet
+    async fn create_db(dir: &Path, file: &str, companies: u32) -> Result<PathBuf> {
+        let db = open_read_write(dir, file).await?;
+        db.execute(&format!(
+            "COPY (SELECT * FROM read_parquet('{}') ORDER BY company_id LIMIT {}) TO '{}' (FORMAT parquet)",
+            DEFAULT_SOURCE,
+            companies,
+            dir.join(format!("{}.parquet", file)).display()
+        ))?;
+        db.create_hello_nest_table()?;
+        Ok(dir.join(file))
+    }
+
+    fn companies(connection: &Connection) -> Result<i64> {
+        Ok(connection
+            .query_one("SELECT count(*) FROM hello_nest", |row| {
+                Ok(row.get::<_, i64>(0)?)
+            })?
+            .unwrap_or(0))
+    }
+
+    #[tokio::test]
+    async fn test_reload_swaps_in_a_file_renamed_over_the_path() -> Result<()> {
+        let dir = test_dir("nest_mcp_snapshot_reload")?;
+        let served = create_db(&dir, "served.db", 20).await?;
+        let replacement = create_db(&dir, "replacement.db", 10).await?;
+        let snapshots = Snapshots::open(&test_config(&dir, "served.db")).await?;
+        let in_flight = snapshots.connect().await?;
+
+        fs::rename(&replacement, &served)?;
+        assert!(snapshots.is_stale());
+        // Until the reload, new connections still read the old file
+        assert_eq!(companies(&snapshots.connect().await?)?, 20);
+
+        snapshots.reload().await?;
+        assert!(!snapshots.is_stale());
+        assert_eq!(companies(&snapshots.connect().await?)?, 10);
+        assert_eq!(companies(&in_flight)?, 20);
+
+        drop(in_flight);
+        drop(snapshots);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_reload_keeps_serving_and_is_not_retried() -> Result<()> {
+        let dir = test_dir("nest_mcp_snapshot_failed_reload")?;
+        let served = create_db(&dir, "served.db", 20).await?;
+        let wrong_schema = create_db(&dir, "wrong_schema.db", 10).await?;
+        open_read_write(&dir, "wrong_schema.db")
+            .await?
+            .execute(&format!(
+                "DELETE FROM schema_version WHERE version = {}",
+                migrations::SCHEMA_VERSION
+            ))?;
+        let snapshots = Snapshots::open(&test_config(&dir, "served.db")).await?;
+
+        fs::rename(&wrong_schema, &served)?;
+        assert!(snapshots.reload_if_changed().await.is_err());
+        assert_eq!(companies(&snapshots.connect().await?)?, 20);
+        // The same file is not opened again on the next poll
+        assert!(snapshots.is_stale());
+        assert!(!snapshots.reload_if_changed().await?);
+
+        let fixed = create_db(&dir, "fixed.db", 10).await?;
+        fs::rename(&fixed, &served)?;
+        assert!(snapshots.reload_if_changed().await?);
+        assert_eq!(companies(&snapshots.connect().await?)?, 10);
+
+        drop(snapshots);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_pool_size_and_query_timeout() -> Result<()> {
+        let dir = test_dir("nest_mcp_snapshot_pool")?;
+        create_db(&dir, "served.db", 5).await?;
+        let mut config = test_config(&dir, "served.db");
+        config.database.pool_size = 1;
+        config.query.timeout_secs = 1;
+        let snapshots = Snapshots::open(&config).await?;
+
+        let first = snapshots.connect().await?;
+        let waiting = tokio::time::timeout(Duration::from_millis(200), snapshots.connect()).await;
+        assert!(waiting.is_err());
+
+        let started = Instant::now();
+        let result = first.query_one(
+            "SELECT count(*) FROM range(100000000) a, range(100000000) b WHERE a.range + b.range < 0",
+            |row| Ok(row.get::<_, i64>(0)?),
+        );
+        // Interrupted a second after connecting
+        assert!(result.is_err());
+        assert!(started.elapsed() > Duration::from_millis(500));
+        assert!(started.elapsed() < Duration::from_secs(30));
+
+        drop(first);
+        let second = tokio::time::timeout(Duration::from_secs(1), snapshots.connect()).await??;
+        assert_eq!(companies(&second)?, 5);
+
+        drop(second);
+        drop(snapshots);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_version_changes_on_replace() -> Result<()> {
+        let path = std::env::temp_dir().join("nest_mcp_snapshot_version.db");
+        let replacement = path.with_extension("db.new");
+        fs::write(&path, b"old")?;
+        let old = file_version(&path);
+        assert!(old.is_some());
+
+        fs::write(&replacement, b"newer")?;
+        fs::rename(&replacement, &path)?;
+        assert_ne!(file_version(&path), old);
+
+        fs::remove_file(&path)?;
+        assert_eq!(file_version(&path), None);
+        Ok(())
+    }
+}
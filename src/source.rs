@@ -0,0 +1,221 @@
+//! Where the company data is read from.
+//!
+//! `create-db` reads `hello_nest.parquet` from the working directory unless a
+//! source is configured: another local path, an `s3://` URI or an `http(s)://`
+//! URL. Local and S3 sources may be globs over hive-partitioned files, e.g.
+//! `data/country=SE/*.parquet`. Remote sources are read through DuckDB's
+//! httpfs extension; S3 ones
+//! also need a secret. Both are only set up when a remote source is used, so
+//! local builds work offline without the httpfs and aws extensions.
+
+use anyhow::{Context, Result};
+use std::{path::Path, time::Duration};
+
+use crate::duckdb::DuckDB;
+
+pub const DEFAULT_SOURCE: &str = "hello_nest.parquet";
+const SECRET_NAME: &str = "nest_mcp_s3";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Local(String),
+    S3(String),
+    Http(String),
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Source::Local(DEFAULT_SOURCE.to_string())
+    }
+}
+
+impl Source {
+    pub fn parse(uri: &str) -> Self {
+        let uri = uri.trim();
+        let scheme = uri
+            .split_once("://")
+            .map(|(scheme, _)| scheme.to_ascii_lowercase());
+        match scheme.as_deref() {
+            Some("s3" | "s3a" | "s3n") => Source::S3(uri.to_string()),
+            Some("http" | "https") => Source::Http(uri.to_string()),
+            _ => Source::Local(uri.strip_prefix("file://").unwrap_or(uri).to_string()),
+        }
+    }
+
+    pub fn uri(&self) -> &str {
+        match self {
+            Source::Local(uri) | Source::S3(uri) | Source::Http(uri) => uri,
+        }
+    }
+
+    pub fn is_remote(&self) -> bool {
+        !matches!(self, Source::Local(_))
+    }
+
+    /// The source as a quoted SQL string, e.g. `'s3://bucket/hello_nest.parquet'`
+    pub fn sql_literal(&self) -> String {
+        format!("'{}'", self.uri().replace('\'', "''"))
+    }
+
+    /// Relation reading every file of the source as one, with hive partition
+    /// directories (`key=value/`) as columns
+    pub fn read_parquet_sql(&self) -> String {
+        format!(
+            "read_parquet({}, hive_partitioning = true, union_by_name = true)",
+            self.sql_literal()
+        )
+    }
+
+    /// The source with a relative local path made absolute, for views that
+    /// are read from another working directory
+    pub fn absolute(&self) -> Self {
+        match self {
+            Source::Local(path) if Path::new(path).is_relative() => std::env::current_dir()
+                .map(|dir| Source::Local(dir.join(path).to_string_lossy().into_owned()))
+                .unwrap_or_else(|_| self.clone()),
+            _ => self.clone(),
+        }
+    }
+}
+
+/// Endpoint and credentials for S3 sources. Without a key the AWS credential
+/// chain (environment, profile, instance role) is used; `endpoint` with
+/// `url_style = "path"` and `use_ssl = false` points at a MinIO stand-in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Config {
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub url_style: Option<String>,
+    pub use_ssl: bool,
+    pub key_id: Option<String>,
+    pub secret: Option<String>,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            region: None,
+            url_style: None,
+            use_ssl: true,
+            key_id: None,
+            secret: None,
+        }
+    }
+}
+
+/// Everything needed to read remote sources. Setting any of the http options
+/// loads httpfs, so they are applied by [`prepare`] rather than on connect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteSettings {
+    pub http_timeout: Duration,
+    pub http_keep_alive: bool,
+    pub http_retries: u32,
+    pub s3_uploader_thread_limit: u32,
+    pub s3: S3Config,
+}
+
+/// `CREATE SECRET` for S3 sources with `config`
+pub fn s3_secret_sql(config: &S3Config) -> String {
+    let quote = |value: &str| format!("'{}'", value.replace('\'', "''"));
+    let mut options = vec!["TYPE s3".to_string()];
+    match (&config.key_id, &config.secret) {
+        (Some(key_id), Some(secret)) => {
+            options.push("PROVIDER config".to_string());
+            options.push(format!("KEY_ID {}", quote(key_id)));
+            options.push(format!("SECRET {}", quote(secret)));
+        }
+        _ => options.push("PROVIDER credential_chain".to_string()),
+    }
+    if let Some(endpoint) = &config.endpoint {
+        options.push(format!("ENDPOINT {}", quote(endpoint)));
+    }
+    if let Some(region) = &config.region {
+        options.push(format!("REGION {}", quote(region)));
+    }
+    if let Some(url_style) = &config.url_style {
+        options.push(format!("URL_STYLE {}", quote(url_style)));
+    }
+    if !config.use_ssl {
+        options.push("USE_SSL false".to_string());
+    }
+    format!(
+        "CREATE OR REPLACE SECRET {} ({})",
+        SECRET_NAME,
+        options.join(", ")
+    )
+}
+
+/// Make `source` readable on `db`: remote sources get the http settings and
+/// S3 ones the secret too; local ones need nothing
+pub fn prepare(db: &DuckDB, source: &Source, remote: &RemoteSettings) -> Result<()> {
+    if !source.is_remote() {
+        return Ok(());
+    }
+    let statements = [
+        "LOAD httpfs".to_string(),
+        format!("SET http_timeout = {}", remote.http_timeout.as_millis()),
+        format!("SET http_keep_alive = {}", remote.http_keep_alive),
+        format!("SET http_retries = {}", remote.http_retries),
+        format!(
+            "SET s3_uploader_thread_limit = {}",
+            remote.s3_uploader_thread_limit
+        ),
+    ];
+    for sql in &statements {
+        db.execute(sql)
+            .with_context(|| format!("Failed to load httpfs to read {}", source.uri()))?;
+    }
+    if let Source::S3(uri) = source {
+        db.execute(&s3_secret_sql(&remote.s3))
+            .with_context(|| format!("Failed to create S3 credentials for {}", uri))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            Source::parse("data/hello_nest.parquet"),
+            Source::Local("data/hello_nest.parquet".to_string())
+        );
+        assert_eq!(
+            Source::parse("file:///data/hello_nest.parquet"),
+            Source::Local("/data/hello_nest.parquet".to_string())
+        );
+        assert_eq!(
+            Source::parse("S3://bucket/hello_nest.parquet"),
+            Source::S3("S3://bucket/hello_nest.parquet".to_string())
+        );
+        assert!(Source::parse("https://example.com/hello_nest.parquet").is_remote());
+        assert_eq!(
+            Source::parse("it's.parquet").sql_literal(),
+            "'it''s.parquet'"
+        );
+    }
+
+    #[test]
+    fn test_s3_secret_sql() {
+        assert_eq!(
+            s3_secret_sql(&S3Config::default()),
+            "CREATE OR REPLACE SECRET nest_mcp_s3 (TYPE s3, PROVIDER credential_chain)"
+        );
+        let minio = S3Config {
+            endpoint: Some("localhost:9000".to_string()),
+            url_style: Some("path".to_string()),
+            use_ssl: false,
+            key_id: Some("minio".to_string()),
+            secret: Some("minio123".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            s3_secret_sql(&minio),
+            "CREATE OR REPLACE SECRET nest_mcp_s3 (TYPE s3, PROVIDER config, KEY_ID 'minio', \
+             SECRET 'minio123', ENDPOINT 'localhost:9000', URL_STYLE 'path', USE_SSL false)"
+        );
+    }
+}
@@ -1,4 +1,12 @@
-use crate::duckdb::DuckDB;
+use crate::{
+    config::QueryConfig,
+    country::Country,
+    dataset::{self, DatasetMetadata},
+    fx, gazetteer,
+    geo::{self, LatLon},
+    history, metrics, quality,
+    snapshot::{Connection, Snapshots},
+};
 use rmcp::{
     ErrorData as McpError, RoleServer, ServerHandler,
     handler::server::{router::tool::ToolRouter, tool::Parameters},
@@ -7,18 +15,54 @@ use rmcp::{
     service::RequestContext,
     tool, tool_handler, tool_router,
 };
+use serde_json::{Map, Value};
+use std::sync::Arc;
 
 #[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
 pub struct QueryRequest {
     pub sql: String,
+
+    #[serde(default)]
+    #[schemars(
+        description = "\"json\" (default) for rows as JSON, or \"geojson\" for a GeoJSON FeatureCollection resource. GeoJSON needs `lat` and `lon` columns in the result; all other columns become feature properties.",
+        example = "\"geojson\""
+    )]
+    pub format: OutputFormat,
+
+    #[serde(default)]
+    #[schemars(
+        description = "Run the query against hello_nest as it was at this date (end of day) or UTC timestamp, \"YYYY-MM-DD\" or \"YYYY-MM-DD HH:MM:SS\", to reproduce a past analysis. Other tables are always current.",
+        example = "\"2025-06-30\""
+    )]
+    pub as_of: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Geojson,
+}
+
+#[derive(Debug, Default, serde::Deserialize, schemars::JsonSchema)]
+#[serde(default)]
+pub struct DataQualityRequest {
+    #[schemars(
+        description = "Offending rows to include as examples per check (default 5, capped at 100)",
+        example = "20"
+    )]
+    pub examples: Option<u32>,
 }
 
 #[derive(Debug, Default, serde::Deserialize, schemars::JsonSchema)]
 #[serde(default)]
-#[schemars(description = "Search for Swedish companies with various filters")]
+#[schemars(
+    description = "Search for companies with various filters. Companies are Swedish unless their `country` says otherwise"
+)]
 pub struct SearchRequest {
     #[schemars(
-        description = "Swedish company name to search for (supports partial matching, case-insensitive)",
+        description = "Company name to search for (supports partial matching, case-insensitive)",
         example = "\"Scania\""
     )]
     pub company_name: Option<String>,
@@ -30,7 +74,7 @@ pub struct SearchRequest {
     pub foundation_year: Option<(i64, i64)>,
 
     #[schemars(
-        description = "Swedish NACE industry categories to filter by (supports partial matching). Multiple categories can be provided to match any of them.",
+        description = "NACE industry categories to filter by (supports partial matching). Multiple categories can be provided to match any of them.",
         example = "[\"43320 Byggnadssnickeriarbeten\", \"78200 Personaluthyrning\", \"73111 Reklambyråverksamhet\"]"
     )]
     pub nace_categories: Option<Vec<String>>,
@@ -42,28 +86,405 @@ pub struct SearchRequest {
     pub company_purpose: Option<String>,
 
     #[schemars(
-        description = "Revenue range as [min_revenue, max_revenue] tuple in SEK (both inclusive)",
+        description = "Counties (län) to filter by; matches any of them. Accepts short or official names in any case, with or without å/ä/ö, e.g. \"Stockholm\", \"Skåne län\" or \"vastra gotaland\", or two-digit county codes.",
+        example = "[\"Stockholm\", \"Uppsala län\"]"
+    )]
+    pub county: Option<Vec<String>>,
+
+    #[schemars(
+        description = "Municipalities (kommuner) to filter by; matches any of them. Case- and diacritic-insensitive, e.g. \"Göteborg\" or \"goteborg\", or four-digit municipality codes.",
+        example = "[\"Göteborg\", \"Mölndal\"]"
+    )]
+    pub municipality: Option<Vec<String>>,
+
+    #[schemars(
+        description = "Country parts (riksområden) to filter by, matched case- and diacritic-insensitively as partial text against location.countryPart; matches any of them. Values in the data: \"Stockholm (riksområde)\", \"Östra Mellansverige\", \"Småland Med Öarna\", \"Sydsverige\", \"Västsverige\", \"Norra Mellansverige\", \"Mellersta Norrland\", \"Övre Norrland\", \"Hela Sverige\".",
+        example = "[\"Sydsverige\", \"Vastsverige\"]"
+    )]
+    pub country_part: Option<Vec<String>>,
+
+    #[schemars(
+        description = "Countries to filter by; matches any of them. ISO codes or names, e.g. \"SE\", \"Norway\" or \"Danmark\". County, municipality, country_part and place names are Swedish only, and financial data uses the Swedish metric names for every country.",
+        example = "[\"NO\", \"DK\"]"
+    )]
+    pub country: Option<Vec<String>>,
+
+    #[schemars(
+        description = "Currency to compare and return money in: \"SEK\", \"NOK\", \"DKK\" or \"EUR\". Converts revenue, revenue_range, monetary metric_filters and metric sorts using each financial year's average exchange rate, so companies from different countries can be compared. Ratios (margins, returns, solvency) and headcounts are never converted, and neither are financial_data, latest_financials or growth rates. Without it, values stay in each company's own currency.",
+        example = "\"EUR\""
+    )]
+    pub currency: Option<String>,
+
+    #[schemars(
+        description = "Revenue range as [min_revenue, max_revenue] tuple in the company's own currency, see `currency` (both inclusive)",
         example = "[1000000, 10000000]"
     )]
     pub revenue_range: Option<(f64, f64)>,
 
+    #[schemars(
+        description = "Which years revenue_range applies to: a year (2016-2024), \"latest\" (the company's latest financial year, the default), \"any\" (at least one year in range) or \"all\" (every reported year in range)",
+        example = "\"latest\""
+    )]
+    pub revenue_year: Option<MetricYear>,
+
     #[schemars(
         description = "Employee number range as [min_employees, max_employees] tuple (both inclusive)",
         example = "[10, 100]"
     )]
     pub employee_range: Option<(f64, f64)>,
+
+    #[schemars(
+        description = "Which years employee_range applies to: a year (2016-2024), \"latest\" (the company's latest financial year, the default), \"any\" (at least one year in range) or \"all\" (every reported year in range)",
+        example = "\"latest\""
+    )]
+    pub employee_year: Option<MetricYear>,
+
+    #[schemars(
+        description = "Sort key: \"company_name\" (default), \"foundation_year\", \"revenue\" (Sales revenues in the latest financial year), \"employees\" (Employees from accounting in the latest financial year), \"revenue_growth\" (3-year revenue CAGR), \"distance\" (from `near`, nearest first), or {\"metric\": <FINANCIAL_METRICS_BASE field>, \"year\": <2016-2024>}",
+        example = "{\"metric\": \"Operating margin\", \"year\": 2023}"
+    )]
+    pub sort_by: Option<SortBy>,
+
+    #[schemars(
+        description = "Sort direction: \"asc\" or \"desc\". Defaults to \"asc\" for company_name and \"desc\" for everything else. Missing values always sort last.",
+        example = "\"desc\""
+    )]
+    pub sort_order: Option<SortOrder>,
+
+    #[schemars(
+        description = "Maximum number of companies to return (default and cap 1000)",
+        example = "50"
+    )]
+    pub limit: Option<u32>,
+
+    #[schemars(
+        description = "Number of matching companies to skip. Use the `next_offset` from a previous response to fetch the next page.",
+        example = "100"
+    )]
+    pub offset: Option<u32>,
+
+    #[schemars(
+        description = "Columns to return for each company. Defaults to company_id, company_name, organization_number, municipality, nace_categories, revenue and employees. \"revenue\" and \"employees\" are taken from the company's latest financial year. Add \"financial_data\" to get the full per-year financials (large). With `near`, a distance_km column is always appended.",
+        example = "[\"company_name\", \"organization_number\", \"homepage\", \"revenue\"]"
+    )]
+    pub fields: Option<Vec<SearchField>>,
+
+    #[schemars(
+        description = "Filters on any FINANCIAL_METRICS_BASE field; all of them must hold. `year` is a specific year (2016-2024), \"latest\" (the company's latest financial year, the default), \"any\" (at least one year matches) or \"all\" (every reported year matches). Values are compared as stored in financial_data.",
+        example = "[{\"metric\": \"Operating margin\", \"year\": 2023, \"op\": \">=\", \"value\": 0.1}]"
+    )]
+    pub metric_filters: Option<Vec<MetricFilter>>,
+
+    #[schemars(
+        description = "Revenue growth filter on the compound annual growth rate (CAGR) of Sales revenues between from_year and to_year. Without years it uses the 3 years ending in the company's latest financial year.",
+        example = "{\"from_year\": 2020, \"to_year\": 2024, \"min_cagr_percent\": 20}"
+    )]
+    pub revenue_growth: Option<RevenueGrowthFilter>,
+
+    #[schemars(
+        description = "Headcount growth filter on the change in Employees from accounting between from_year and to_year. Without years it uses the 3 years ending in the company's latest financial year.",
+        example = "{\"min_change\": 5}"
+    )]
+    pub employee_growth: Option<EmployeeGrowthFilter>,
+
+    #[schemars(
+        description = "Minimum number of consecutive profitable years (Ordinary result before taxes > 0) ending in the company's latest financial year",
+        example = "3"
+    )]
+    pub min_profitable_years: Option<u32>,
+
+    #[schemars(
+        description = "Only companies within radius_km of a place. Give either `place` (a Swedish municipality or county, e.g. \"Uppsala\" or \"Skåne län\"; a municipality's seat or a county's capital is used as the centre) or a WGS84 `lat`/`lon`. Adds a distance_km column to each result and enables sort_by \"distance\".",
+        example = "{\"place\": \"Uppsala\", \"radius_km\": 20}"
+    )]
+    pub near: Option<NearFilter>,
+
+    #[schemars(
+        description = "Only companies inside a WGS84 bounding box",
+        example = "{\"min_lat\": 59.2, \"min_lon\": 17.8, \"max_lat\": 59.45, \"max_lon\": 18.3}"
+    )]
+    pub bbox: Option<BoundingBox>,
+
+    #[schemars(
+        description = "\"json\" (default), or \"geojson\" to get the page of companies as a GeoJSON FeatureCollection resource for maps, with the selected fields as feature properties. Companies without coordinates are left out of the collection.",
+        example = "\"geojson\""
+    )]
+    pub format: OutputFormat,
+
+    #[schemars(
+        description = "Search companies as they were at this date (end of day) or UTC timestamp, \"YYYY-MM-DD\" or \"YYYY-MM-DD HH:MM:SS\": names, addresses, NACE codes and financials from the data builds current then",
+        example = "\"2025-06-30\""
+    )]
+    pub as_of: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, schemars::JsonSchema)]
+pub struct NearFilter {
+    pub place: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub radius_km: f64,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, schemars::JsonSchema)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+/// Largest accepted `near.radius_km`, roughly the length of Sweden
+const MAX_RADIUS_KM: f64 = 2000.0;
+
+impl NearFilter {
+    fn center(&self) -> Result<LatLon, McpError> {
+        let center = match (&self.place, self.lat, self.lon) {
+            (Some(place), None, None) => gazetteer::resolve_place(place)
+                .map(|place| place.position())
+                .ok_or_else(|| {
+                    McpError::invalid_params(
+                        format!(
+                            "Unknown place \"{}\". Use a Swedish municipality or county name, or lat/lon",
+                            place
+                        ),
+                        None,
+                    )
+                })?,
+            (None, Some(lat), Some(lon)) => LatLon { lat, lon },
+            _ => {
+                return Err(McpError::invalid_params(
+                    "`near` needs either `place` or both `lat` and `lon`".to_string(),
+                    None,
+                ));
+            }
+        };
+        validate_position(center.lat, center.lon)?;
+        Ok(center)
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, schemars::JsonSchema)]
+#[serde(default)]
+pub struct RevenueGrowthFilter {
+    pub from_year: Option<i64>,
+    pub to_year: Option<i64>,
+    #[schemars(description = "Minimum CAGR in percent, e.g. 20 for 20% a year")]
+    pub min_cagr_percent: Option<f64>,
+    #[schemars(description = "Maximum CAGR in percent")]
+    pub max_cagr_percent: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, schemars::JsonSchema)]
+#[serde(default)]
+pub struct EmployeeGrowthFilter {
+    pub from_year: Option<i64>,
+    pub to_year: Option<i64>,
+    #[schemars(description = "Minimum increase in employees, negative for shrinking companies")]
+    pub min_change: Option<f64>,
+    #[schemars(description = "Maximum increase in employees")]
+    pub max_change: Option<f64>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, schemars::JsonSchema)]
+pub struct MetricFilter {
+    #[schemars(description = "FINANCIAL_METRICS_BASE field name, e.g. \"Operating margin\"")]
+    pub metric: String,
+
+    #[serde(default)]
+    #[schemars(description = "A year (2016-2024), \"latest\", \"any\" or \"all\"")]
+    pub year: MetricYear,
+
+    pub op: ComparisonOp,
+
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+#[serde(untagged)]
+pub enum MetricYear {
+    Year(i64),
+    Mode(YearMode),
+}
+
+impl Default for MetricYear {
+    fn default() -> Self {
+        MetricYear::Mode(YearMode::Latest)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum YearMode {
+    Latest,
+    Any,
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+pub enum ComparisonOp {
+    #[serde(rename = "=")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+}
+
+impl ComparisonOp {
+    fn sql(self) -> &'static str {
+        match self {
+            ComparisonOp::Eq => "=",
+            ComparisonOp::Ne => "<>",
+            ComparisonOp::Gt => ">",
+            ComparisonOp::Ge => ">=",
+            ComparisonOp::Lt => "<",
+            ComparisonOp::Le => "<=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, schemars::JsonSchema)]
+#[serde(untagged)]
+pub enum SortBy {
+    Field(SortField),
+    Metric { metric: String, year: i64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    CompanyName,
+    FoundationYear,
+    Revenue,
+    Employees,
+    RevenueGrowth,
+    Distance,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchField {
+    CompanyId,
+    CompanyName,
+    OrganizationNumber,
+    Country,
+    Currency,
+    CompanyType,
+    CompanyPurpose,
+    EstablishedDate,
+    FoundationYear,
+    RegisteredForPayrollTax,
+    Homepage,
+    PostalAddress,
+    VisitorAddress,
+    NaceCategories,
+    Location,
+    County,
+    CountyCode,
+    Municipality,
+    MunicipalityCode,
+    CountryPart,
+    Revenue,
+    Employees,
+    Lat,
+    Lon,
+    LatestFinancialYear,
+    LatestFinancials,
+    #[serde(rename = "revenue_cagr_3y")]
+    RevenueCagr3y,
+    #[serde(rename = "employee_change_3y")]
+    EmployeeChange3y,
+    ProfitableYearsStreak,
+    FinancialData,
+}
+
+impl SearchField {
+    /// Select list entry for this field, aliased to its request name, with
+    /// revenue converted to `currency` when one is given
+    fn select_sql(self, currency: Option<&str>) -> String {
+        match self {
+            SearchField::CompanyId => "company_id".to_string(),
+            SearchField::CompanyName => "company_name".to_string(),
+            SearchField::OrganizationNumber => "organization_number".to_string(),
+            SearchField::Country => "country".to_string(),
+            SearchField::Currency => "currency".to_string(),
+            SearchField::CompanyType => "company_type".to_string(),
+            SearchField::CompanyPurpose => "company_purpose".to_string(),
+            SearchField::EstablishedDate => "established_date".to_string(),
+            SearchField::FoundationYear => "foundation_year".to_string(),
+            SearchField::RegisteredForPayrollTax => "registered_for_payroll_tax".to_string(),
+            SearchField::Homepage => "homepage".to_string(),
+            SearchField::PostalAddress => "postal_address".to_string(),
+            SearchField::VisitorAddress => "visitor_address".to_string(),
+            SearchField::NaceCategories => "nace_categories".to_string(),
+            SearchField::Location => "location".to_string(),
+            SearchField::County => "county".to_string(),
+            SearchField::CountyCode => "county_code".to_string(),
+            SearchField::Municipality => "municipality".to_string(),
+            SearchField::MunicipalityCode => "municipality_code".to_string(),
+            SearchField::CountryPart => "location.countryPart AS country_part".to_string(),
+            SearchField::Revenue => format!(
+                "{} AS revenue",
+                metrics::latest_metric_in_currency_sql(metrics::REVENUE_METRIC, currency)
+            ),
+            SearchField::Employees => format!(
+                "{} AS employees",
+                metrics::latest_metric_sql(metrics::EMPLOYEES_METRIC)
+            ),
+            SearchField::Lat => "lat".to_string(),
+            SearchField::Lon => "lon".to_string(),
+            SearchField::LatestFinancialYear => "latest_financial_year".to_string(),
+            SearchField::LatestFinancials => "latest_financials".to_string(),
+            SearchField::RevenueCagr3y => "revenue_cagr_3y".to_string(),
+            SearchField::EmployeeChange3y => "employee_change_3y".to_string(),
+            SearchField::ProfitableYearsStreak => "profitable_years_streak".to_string(),
+            SearchField::FinancialData => "financial_data".to_string(),
+        }
+    }
 }
 
+const DEFAULT_SEARCH_FIELDS: &[SearchField] = &[
+    SearchField::CompanyId,
+    SearchField::CompanyName,
+    SearchField::OrganizationNumber,
+    SearchField::Municipality,
+    SearchField::NaceCategories,
+    SearchField::Revenue,
+    SearchField::Currency,
+    SearchField::Employees,
+];
+
+const DEFAULT_SEARCH_LIMIT: u32 = 1000;
+const MAX_SEARCH_LIMIT: u32 = 1000;
+
 #[derive(Clone)]
 pub struct Tool {
     tool_router: ToolRouter<Tool>,
+    snapshots: Arc<Snapshots>,
+    limits: QueryConfig,
 }
 
 #[tool_router]
 impl Tool {
-    pub fn new() -> Self {
+    pub fn new(snapshots: Arc<Snapshots>, limits: QueryConfig) -> Self {
         Self {
             tool_router: Self::tool_router(),
+            snapshots,
+            limits,
         }
     }
 
@@ -72,6 +493,10 @@ impl Tool {
         description = r#"
         Execute SQL queries (duckdb dialect) against the company database.
 
+        Returns the rows as JSON, followed by {"dataset": {"version", "built_at"}} naming
+        the newest data build. With `as_of`, hello_nest reads as it was at that time,
+        from company_history.
+
         # Schema
         -- Define the complete financial metrics structure (used across all years)
         FINANCIAL_METRICS_BASE STRUCT(
@@ -119,13 +544,20 @@ impl Tool {
             "Total provisions for liabilities and charges" DOUBLE,
             "Total receivable" DOUBLE,
             "Total short-term liabilities" DOUBLE,
-            "Trade creditors" DOUBLE
+            "Trade creditors" DOUBLE,
+            -- Derived at ingest from the amounts above
+            "EBITDA" DOUBLE,            -- Result before depreciation
+            "Net debt" DOUBLE,          -- Total long-term + short-term liabilities - Bank deposits cash etc.
+            "Working capital" DOUBLE,   -- Total current assets - Total short-term liabilities
+            "Equity ratio" DOUBLE       -- Total equity / Total assets
         );
 
         'hello_nest' table (
             company_id BIGINT,
             company_name VARCHAR,
             organization_number BIGINT,
+            country VARCHAR,  -- 'SE', 'NO' or 'DK'
+            currency VARCHAR,  -- currency of all monetary financial values: 'SEK', 'NOK' or 'DKK'
             company_type VARCHAR,
             company_purpose VARCHAR,
             established_date DATE,
@@ -145,74 +577,114 @@ impl Tool {
                 countryPart VARCHAR,
                 municipality VARCHAR
             ),
-
-            -- Nested financial data: each year contains the same base metrics with minor type variations
+            lat DOUBLE,  -- WGS84 latitude converted from location.coordinates
+            lon DOUBLE,  -- WGS84 longitude converted from location.coordinates
+            county_code VARCHAR,  -- two-digit county code (länskod) from the gazetteer
+            county VARCHAR,  -- canonical county name, e.g. "Stockholm" for "Stockholms Län"
+            municipality_code VARCHAR,  -- four-digit municipality code (kommunkod)
+            municipality VARCHAR,  -- canonical municipality name
+            latest_financial_year BIGINT,  -- newest year with Total operating revenues, Sales revenues or Total assets reported, NULL if none
+            latest_financials FINANCIAL_METRICS_BASE,  -- financial_data of latest_financial_year, all DOUBLE
+            revenue_cagr_3y DOUBLE,  -- Sales revenues CAGR (fraction) over the 3 years up to latest_financial_year
+            employee_change_3y DOUBLE,  -- change in Employees from accounting over the same 3 years
+            profitable_years_streak BIGINT,  -- consecutive years with Ordinary result before taxes > 0, up to latest_financial_year
+
+            -- Nested financial data: every year is the same FINANCIAL_METRICS_BASE struct, all DOUBLE,
+            -- so years can be compared or unioned directly. A year is NULL when the company has no
+            -- accounts for it; "Minority interests" is only reported from 2019 and NULL before.
             financial_data STRUCT(
-                -- Years 2016-2017: Standard metrics but "Allocation dividends" is INTEGER
-                "2016" STRUCT(
-                    ...FINANCIAL_METRICS_BASE,  -- 44 standard financial metrics
-                    -- Override: "Allocation dividends" INTEGER (instead of DOUBLE)
-                    -- Missing: "Minority interests" (not present in 2016-2018)
-                ),
-                "2017" STRUCT(
-                    ...FINANCIAL_METRICS_BASE,  -- 44 standard financial metrics
-                    -- Override: "Allocation dividends" INTEGER (instead of DOUBLE)
-                    -- Missing: "Minority interests" (not present in 2016-2018)
-                ),
-
-                -- Year 2018: Standard metrics but no "Minority interests" yet
-                "2018" STRUCT(
-                    ...FINANCIAL_METRICS_BASE,  -- 44 standard financial metrics
-                    -- Missing: "Minority interests" (not present in 2016-2018)
-                ),
-
-                -- Years 2019-2020: Standard metrics but "Minority interests" is INTEGER
-                "2019" STRUCT(
-                    ...FINANCIAL_METRICS_BASE,  -- 44 standard financial metrics
-                    -- Override: "Minority interests" INTEGER (instead of DOUBLE)
-                ),
-                "2020" STRUCT(
-                    ...FINANCIAL_METRICS_BASE,  -- 44 standard financial metrics
-                    -- Override: "Minority interests" INTEGER (instead of DOUBLE)
-                ),
-
-                -- Years 2021-2024: Fully standard structure (all DOUBLE types)
-                "2021" STRUCT(
-                    ...FINANCIAL_METRICS_BASE   -- 44 standard financial metrics
-                ),
-                "2022" STRUCT(
-                    ...FINANCIAL_METRICS_BASE   -- 44 standard financial metrics
-                ),
-                "2023" STRUCT(
-                    ...FINANCIAL_METRICS_BASE   -- 44 standard financial metrics
-                ),
-                "2024" STRUCT(
-                    ...FINANCIAL_METRICS_BASE   -- 44 standard financial metrics
-                )
+                "2016" FINANCIAL_METRICS_BASE,
+                "2017" FINANCIAL_METRICS_BASE,
+                "2018" FINANCIAL_METRICS_BASE,
+                "2019" FINANCIAL_METRICS_BASE,
+                "2020" FINANCIAL_METRICS_BASE,
+                "2021" FINANCIAL_METRICS_BASE,
+                "2022" FINANCIAL_METRICS_BASE,
+                "2023" FINANCIAL_METRICS_BASE,
+                "2024" FINANCIAL_METRICS_BASE
             )
         );
 
-        -- Schema Evolution Summary:
-        -- • 2016-2017: 43 metrics (missing "Minority interests", "Allocation dividends" is INTEGER)
-        -- • 2018:      43 metrics (missing "Minority interests", "Allocation dividends" becomes DOUBLE)
-        -- • 2019-2020: 44 metrics (adds "Minority interests" as INTEGER)
-        -- • 2021-2024: 44 metrics (all fields present, all DOUBLE types - fully standardized)
+        -- Gazetteer (positions are the county capital / municipal seat)
+        counties (county_code VARCHAR, county_name VARCHAR, official_name VARCHAR, lat DOUBLE, lon DOUBLE)
+        municipalities (municipality_code VARCHAR, municipality_name VARCHAR, county_code VARCHAR, lat DOUBLE, lon DOUBLE)
+
+        -- Yearly average exchange rates, as units of currency per EUR (SEK, NOK, DKK, EUR)
+        fx_rates (year BIGINT, currency VARCHAR, per_eur DOUBLE)
+
+        -- Reported ratios that differ from their value recomputed from base amounts
+        -- (Operating margin, Equity-to-asset ratio / solvency ratio, Debt ratio);
+        -- scale is reported / computed, about 100 for a percentage
+        ratio_mismatches (company_id BIGINT, year BIGINT, metric VARCHAR, reported DOUBLE, computed DOUBLE, scale DOUBLE)
+
+        -- Companies inserted or updated by incremental `update` runs, newest last;
+        -- changed_columns lists the hello_nest columns that changed, added_years the new financial years
+        change_log (changed_at TIMESTAMP, company_id BIGINT, change VARCHAR, changed_columns VARCHAR[], added_years BIGINT[], source VARCHAR)
+
+        -- Every version of every hello_nest row (type 2 history): all hello_nest columns plus
+        -- the interval it was current in; valid_to is NULL for the current version
+        company_history (...hello_nest columns, valid_from TIMESTAMP, valid_to TIMESTAMP)
+          e.g. SELECT company_name, valid_from, valid_to FROM company_history WHERE company_id = 123 ORDER BY valid_from
+
+        -- Derived views over hello_nest, listed with their version in derived_views (name VARCHAR, version BIGINT, description VARCHAR)
+        hello_nest_parsed (...hello_nest columns, nace_categories VARCHAR[], nace_codes VARCHAR[])
+          e.g. SELECT company_name FROM hello_nest_parsed WHERE list_contains(nace_codes, '62010')
+        company_locations (company_id BIGINT, company_name VARCHAR, country_part VARCHAR, reported_county VARCHAR, reported_municipality VARCHAR,
+                           x_coordinate DOUBLE, y_coordinate DOUBLE, coordinate_system VARCHAR, lat DOUBLE, lon DOUBLE,
+                           county_code VARCHAR, county VARCHAR, municipality_code VARCHAR, municipality VARCHAR)
+        company_latest_financials (company_id BIGINT, company_name VARCHAR, currency VARCHAR, year BIGINT, ...FINANCIAL_METRICS_BASE fields as DOUBLE columns)
+          e.g. SELECT company_name, "Sales revenues" FROM company_latest_financials WHERE year = 2024
+
+        -- One row per create-db or update run, newest is the current build;
+        -- sources (file, sha256, bytes), row_counts and year_coverage are JSON text
+        dataset_metadata (version VARCHAR, kind VARCHAR, built_at TIMESTAMP, builder_version VARCHAR, converter_version VARCHAR, sources VARCHAR, row_counts VARCHAR, year_coverage VARCHAR)
+
+        -- Macros:
+        -- distance_km(lat1, lon1, lat2, lon2) DOUBLE  -- great-circle distance in km between WGS84 points
+        --   e.g. WHERE distance_km(lat, lon, 59.3293, 18.0686) <= 10
+        -- fx_rate(year, from_currency, to_currency) DOUBLE  -- units of to_currency per unit of from_currency
+        --   e.g. SELECT latest_financials['Sales revenues'] * fx_rate(latest_financial_year, currency, 'EUR') FROM hello_nest
         "#,
         annotations(title = "Companies", read_only_hint = true)
     )]
     pub async fn company(
         &self,
-        Parameters(QueryRequest { sql }): Parameters<QueryRequest>,
+        Parameters(QueryRequest { sql, format, as_of }): Parameters<QueryRequest>,
     ) -> Result<CallToolResult, McpError> {
-        let db = DuckDB::new_default().await.map_err(|e| {
+        let db = self.snapshots.connect().await.map_err(|e| {
             McpError::internal_error(format!("Failed to connect to database: {}", e), None)
         })?;
+        read_as_of(&db, as_of.as_deref())?;
+        let sql = limit_rows(&sql, self.limits.max_rows);
+
+        if format == OutputFormat::Geojson {
+            let rows = db.query_all_value(&sql).map_err(|e| {
+                McpError::internal_error(format!("Failed to execute query: {}", e), None)
+            })?;
+            let rows = rows.as_array().map(Vec::as_slice).unwrap_or_default();
+            if let Some(first) = rows.first()
+                && (first.get("lat").is_none() || first.get("lon").is_none())
+            {
+                return Err(McpError::invalid_params(
+                    "GeoJSON output needs `lat` and `lon` columns, e.g. SELECT company_name, lat, lon FROM hello_nest".to_string(),
+                    None,
+                ));
+            }
+            let mut summary = Map::new();
+            summary.insert("dataset".to_string(), dataset_version(&db));
+            let contents = geojson_contents("nest://company-sql/results.geojson", rows, summary)?;
+            return Ok(CallToolResult::success(contents));
+        }
 
         let result = db.query_all_json(&sql).map_err(|e| {
             McpError::internal_error(format!("Failed to execute query: {}", e), None)
         })?;
+        let dataset = serde_json::json!({ "dataset": dataset_version(&db) });
 
-        Ok(CallToolResult::success(vec![Content::text(result)]))
+        Ok(CallToolResult::success(vec![
+            Content::text(result),
+            Content::text(dataset.to_string()),
+        ]))
     }
 
     #[tool(
@@ -220,6 +692,17 @@ impl Tool {
         description = r#"
             Search for companies in the company database.
 
+            Returns {"total_count", "offset", "limit", "next_offset", "currency", "as_of",
+            "dataset", "companies"}. Pass `next_offset` back as `offset` to fetch the next
+            page; it is null on the last page. `currency` echoes the requested conversion
+            currency, or is null when money is in each company's own currency. `as_of`
+            echoes the requested point in time. `dataset` has the "version" and "built_at"
+            of the newest data build.
+
+            Each company only carries the columns listed in `fields` (a compact default set
+            when omitted). Request "financial_data" explicitly to get the nested per-year
+            financials described below.
+
             # Schema
 
             -- Define the complete financial metrics structure (used across all years)
@@ -268,13 +751,20 @@ impl Tool {
                 "Total provisions for liabilities and charges" DOUBLE,
                 "Total receivable" DOUBLE,
                 "Total short-term liabilities" DOUBLE,
-                "Trade creditors" DOUBLE
+                "Trade creditors" DOUBLE,
+                -- Derived at ingest from the amounts above
+                "EBITDA" DOUBLE,            -- Result before depreciation
+                "Net debt" DOUBLE,          -- Total long-term + short-term liabilities - Bank deposits cash etc.
+                "Working capital" DOUBLE,   -- Total current assets - Total short-term liabilities
+                "Equity ratio" DOUBLE       -- Total equity / Total assets
             );
 
             'hello_nest' (
                 company_id BIGINT,
                 company_name VARCHAR,
                 organization_number BIGINT,
+                country VARCHAR,  -- 'SE', 'NO' or 'DK'
+                currency VARCHAR,  -- currency of all monetary financial values: 'SEK', 'NOK' or 'DKK'
                 company_type VARCHAR,
                 company_purpose VARCHAR,
                 established_date DATE,
@@ -294,58 +784,33 @@ impl Tool {
                     countryPart VARCHAR,
                     municipality VARCHAR
                 ),
-
-                -- Nested financial data: each year contains the same base metrics with minor type variations
+                lat DOUBLE,  -- WGS84 latitude converted from location.coordinates
+                lon DOUBLE,  -- WGS84 longitude converted from location.coordinates
+                county_code VARCHAR,  -- two-digit county code (länskod) from the gazetteer
+                county VARCHAR,  -- canonical county name, e.g. "Stockholm" for "Stockholms Län"
+                municipality_code VARCHAR,  -- four-digit municipality code (kommunkod)
+                municipality VARCHAR,  -- canonical municipality name
+                latest_financial_year BIGINT,  -- newest year with Total operating revenues, Sales revenues or Total assets reported, NULL if none
+                latest_financials FINANCIAL_METRICS_BASE,  -- financial_data of latest_financial_year, all DOUBLE
+                revenue_cagr_3y DOUBLE,  -- Sales revenues CAGR (fraction) over the 3 years up to latest_financial_year
+                employee_change_3y DOUBLE,  -- change in Employees from accounting over the same 3 years
+                profitable_years_streak BIGINT,  -- consecutive years with Ordinary result before taxes > 0, up to latest_financial_year
+
+                -- Nested financial data: every year is the same FINANCIAL_METRICS_BASE struct, all DOUBLE,
+                -- so years can be compared or unioned directly. A year is NULL when the company has no
+                -- accounts for it; "Minority interests" is only reported from 2019 and NULL before.
                 financial_data STRUCT(
-                    -- Years 2016-2017: Standard metrics but "Allocation dividends" is INTEGER
-                    "2016" STRUCT(
-                        ...FINANCIAL_METRICS_BASE,  -- 44 standard financial metrics
-                        -- Override: "Allocation dividends" INTEGER (instead of DOUBLE)
-                        -- Missing: "Minority interests" (not present in 2016-2018)
-                    ),
-                    "2017" STRUCT(
-                        ...FINANCIAL_METRICS_BASE,  -- 44 standard financial metrics
-                        -- Override: "Allocation dividends" INTEGER (instead of DOUBLE)
-                        -- Missing: "Minority interests" (not present in 2016-2018)
-                    ),
-
-                    -- Year 2018: Standard metrics but no "Minority interests" yet
-                    "2018" STRUCT(
-                        ...FINANCIAL_METRICS_BASE,  -- 44 standard financial metrics
-                        -- Missing: "Minority interests" (not present in 2016-2018)
-                    ),
-
-                    -- Years 2019-2020: Standard metrics but "Minority interests" is INTEGER
-                    "2019" STRUCT(
-                        ...FINANCIAL_METRICS_BASE,  -- 44 standard financial metrics
-                        -- Override: "Minority interests" INTEGER (instead of DOUBLE)
-                    ),
-                    "2020" STRUCT(
-                        ...FINANCIAL_METRICS_BASE,  -- 44 standard financial metrics
-                        -- Override: "Minority interests" INTEGER (instead of DOUBLE)
-                    ),
-
-                    -- Years 2021-2024: Fully standard structure (all DOUBLE types)
-                    "2021" STRUCT(
-                        ...FINANCIAL_METRICS_BASE   -- 44 standard financial metrics
-                    ),
-                    "2022" STRUCT(
-                        ...FINANCIAL_METRICS_BASE   -- 44 standard financial metrics
-                    ),
-                    "2023" STRUCT(
-                        ...FINANCIAL_METRICS_BASE   -- 44 standard financial metrics
-                    ),
-                    "2024" STRUCT(
-                        ...FINANCIAL_METRICS_BASE   -- 44 standard financial metrics
-                    )
+                    "2016" FINANCIAL_METRICS_BASE,
+                    "2017" FINANCIAL_METRICS_BASE,
+                    "2018" FINANCIAL_METRICS_BASE,
+                    "2019" FINANCIAL_METRICS_BASE,
+                    "2020" FINANCIAL_METRICS_BASE,
+                    "2021" FINANCIAL_METRICS_BASE,
+                    "2022" FINANCIAL_METRICS_BASE,
+                    "2023" FINANCIAL_METRICS_BASE,
+                    "2024" FINANCIAL_METRICS_BASE
                 )
             );
-
-            -- Schema Evolution Summary:
-            -- • 2016-2017: 43 metrics (missing "Minority interests", "Allocation dividends" is INTEGER)
-            -- • 2018:      43 metrics (missing "Minority interests", "Allocation dividends" becomes DOUBLE)
-            -- • 2019-2020: 44 metrics (adds "Minority interests" as INTEGER)
-            -- • 2021-2024: 44 metrics (all fields present, all DOUBLE types - fully standardized)
         "#,
         annotations(title = "Company Search", read_only_hint = true)
     )]
@@ -354,16 +819,108 @@ impl Tool {
         Parameters(search_request): Parameters<SearchRequest>,
     ) -> Result<CallToolResult, McpError> {
         // All filters are now optional - if none provided, return all companies (limited)
-        let db = DuckDB::new_default().await.map_err(|e| {
+        let db = self.snapshots.connect().await.map_err(|e| {
             McpError::internal_error(format!("Failed to connect to database: {}", e), None)
         })?;
+        read_as_of(&db, search_request.as_of.as_deref())?;
 
         let sql = build_company_search_query(&search_request)?;
+        let count_sql = build_company_count_query(&search_request)?;
+        let (limit, offset) = search_page(&search_request);
 
-        let result = db.query_all_json(&sql).map_err(|e| {
+        let total_count = db
+            .query_one(&count_sql, |row| Ok(row.get::<_, i64>(0)?))
+            .map_err(|e| McpError::internal_error(format!("Failed to count matches: {}", e), None))?
+            .unwrap_or(0);
+
+        let companies = db.query_all_value(&sql).map_err(|e| {
             McpError::internal_error(format!("Failed to execute query: {}", e), None)
         })?;
 
+        let next_offset =
+            (i64::from(offset) + i64::from(limit) < total_count).then(|| offset + limit);
+        let currency = target_currency(&search_request)?;
+
+        if search_request.format == OutputFormat::Geojson {
+            let page = serde_json::json!({
+                "total_count": total_count,
+                "offset": offset,
+                "limit": limit,
+                "next_offset": next_offset,
+                "currency": currency,
+                "as_of": search_request.as_of,
+                "dataset": dataset_version(&db),
+            });
+            let rows = companies.as_array().map(Vec::as_slice).unwrap_or_default();
+            let summary = page.as_object().cloned().unwrap_or_default();
+            let contents =
+                geojson_contents("nest://company-search/results.geojson", rows, summary)?;
+            return Ok(CallToolResult::success(contents));
+        }
+
+        let response = serde_json::json!({
+            "total_count": total_count,
+            "offset": offset,
+            "limit": limit,
+            "next_offset": next_offset,
+            "currency": currency,
+            "as_of": search_request.as_of,
+            "dataset": dataset_version(&db),
+            "companies": companies,
+        });
+        let result = serde_json::to_string_pretty(&response).map_err(|e| {
+            McpError::internal_error(format!("Failed to format results: {}", e), None)
+        })?;
+
+        Ok(CallToolResult::success(vec![Content::text(result)]))
+    }
+
+    #[tool(
+        name = "data-quality",
+        description = r#"
+            Scan the company database for data-quality problems, mostly values stored in
+            the wrong unit (percentages vs fractions, SEK vs thousands of SEK).
+
+            Returns {"dataset", "companies", "companies_without_financials", "checks",
+            "coverage"}.
+            Each check has a description, the number of violations and affected companies,
+            violations per metric and year, and a few example rows:
+            - revenue_per_employee: Sales revenues / Employees from accounting outside
+              10 000 - 5 000 000 EUR
+            - ratio_out_of_range: ratios (margins, returns, debt and solvency ratios)
+              outside [-10, 10]
+            - balance_sheet_identity: Total assets differs from Total liabilities and
+              equity by more than 1%; `ratio` 1000 means one side is in thousands
+            - ratio_mismatch: Operating margin, solvency ratio and Debt ratio that differ
+              from their value recomputed from base amounts by more than 10%; `scale`
+              about 100 means a percentage stored as a fraction
+            - missing_years: years without accounts between the first and latest
+              reported financial year
+            `coverage` lists, per metric and year, how many reporting companies have a
+            non-NULL value.
+        "#,
+        annotations(title = "Data Quality", read_only_hint = true)
+    )]
+    pub async fn data_quality(
+        &self,
+        Parameters(DataQualityRequest { examples }): Parameters<DataQualityRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let db = self.snapshots.connect().await.map_err(|e| {
+            McpError::internal_error(format!("Failed to connect to database: {}", e), None)
+        })?;
+
+        let report =
+            quality::report(&db, examples.unwrap_or(quality::DEFAULT_EXAMPLES)).map_err(|e| {
+                McpError::internal_error(format!("Failed to check data quality: {}", e), None)
+            })?;
+        let mut response = serde_json::to_value(&report).map_err(|e| {
+            McpError::internal_error(format!("Failed to format results: {}", e), None)
+        })?;
+        response["dataset"] = dataset_version(&db);
+        let result = serde_json::to_string_pretty(&response).map_err(|e| {
+            McpError::internal_error(format!("Failed to format results: {}", e), None)
+        })?;
+
         Ok(CallToolResult::success(vec![Content::text(result)]))
     }
 }
@@ -373,7 +930,10 @@ impl ServerHandler for Tool {
     fn get_info(&self) -> ServerInfo {
         ServerInfo {
             protocol_version: ProtocolVersion::V_2024_11_05,
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_resources()
+                .build(),
             server_info: Implementation::from_build_env(),
             instructions: Some(
                 "This server provides SQL query tools for company database access.".to_string(),
@@ -393,11 +953,553 @@ impl ServerHandler for Tool {
         }
         Ok(self.get_info())
     }
+
+    async fn list_resources(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, McpError> {
+        let mut resource = RawResource::new(dataset::RESOURCE_URI, "dataset-metadata");
+        resource.description = Some(
+            "Build time, source file hashes, row counts and year coverage of the data the tools answer from".to_string(),
+        );
+        resource.mime_type = Some("application/json".to_string());
+        Ok(ListResourcesResult::with_all_items(vec![
+            resource.no_annotation(),
+        ]))
+    }
+
+    async fn read_resource(
+        &self,
+        ReadResourceRequestParam { uri }: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, McpError> {
+        if uri != dataset::RESOURCE_URI {
+            return Err(McpError::resource_not_found(
+                format!("Unknown resource: {}", uri),
+                None,
+            ));
+        }
+        let metadata = self.snapshots.dataset();
+        Ok(ReadResourceResult {
+            contents: vec![dataset_resource_contents(metadata.as_ref())?],
+        })
+    }
+}
+
+/// Cap a query at `max_rows` rows (0 for no cap). Only queries are wrapped;
+/// PRAGMA, DESCRIBE and the like run as given.
+fn limit_rows(sql: &str, max_rows: u64) -> String {
+    let sql = sql.trim().trim_end_matches(';').trim_end();
+    let first_word = sql
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    if max_rows == 0 || !matches!(first_word.as_str(), "SELECT" | "WITH" | "FROM" | "VALUES") {
+        return sql.to_string();
+    }
+    format!("SELECT * FROM ({}\n) LIMIT {}", sql, max_rows)
+}
+
+/// Shadow hello_nest on `db` with its state at `as_of`, if given
+fn read_as_of(db: &Connection, as_of: Option<&str>) -> Result<(), McpError> {
+    let Some(as_of) = as_of else {
+        return Ok(());
+    };
+    if history::as_of_bound_sql(as_of).is_none() {
+        return Err(McpError::invalid_params(
+            format!(
+                "Invalid as_of \"{}\". Use a date (YYYY-MM-DD) or UTC timestamp (YYYY-MM-DD HH:MM:SS)",
+                as_of
+            ),
+            None,
+        ));
+    }
+    history::shadow_companies_as_of(db, as_of)
+        .map_err(|e| McpError::internal_error(format!("Failed to read history: {}", e), None))
+}
+
+/// The data build `db` reads from, as `{"version", "built_at"}`, or null for a
+/// database built before metadata was recorded
+fn dataset_version(db: &Connection) -> Value {
+    db.snapshot()
+        .dataset
+        .as_ref()
+        .map(DatasetMetadata::summary)
+        .and_then(|summary| serde_json::to_value(summary).ok())
+        .unwrap_or(Value::Null)
+}
+
+fn dataset_resource_contents(
+    metadata: Option<&DatasetMetadata>,
+) -> Result<ResourceContents, McpError> {
+    let text = serde_json::to_string_pretty(&metadata).map_err(|e| {
+        McpError::internal_error(format!("Failed to format dataset metadata: {}", e), None)
+    })?;
+    Ok(ResourceContents::TextResourceContents {
+        uri: dataset::RESOURCE_URI.to_string(),
+        mime_type: Some("application/json".to_string()),
+        text,
+    })
+}
+
+/// Result rows as an embedded GeoJSON resource, preceded by a JSON summary
+/// with the feature counts
+fn geojson_contents(
+    uri: &str,
+    rows: &[Value],
+    mut summary: Map<String, Value>,
+) -> Result<Vec<Content>, McpError> {
+    let (collection, skipped) = geo::feature_collection(rows);
+    summary.insert("features".to_string(), (rows.len() - skipped).into());
+    summary.insert("skipped_without_coordinates".to_string(), skipped.into());
+    summary.insert("resource".to_string(), uri.into());
+
+    let summary = serde_json::to_string_pretty(&summary)
+        .map_err(|e| McpError::internal_error(format!("Failed to format results: {}", e), None))?;
+    let collection = serde_json::to_string(&collection)
+        .map_err(|e| McpError::internal_error(format!("Failed to format GeoJSON: {}", e), None))?;
+
+    Ok(vec![
+        Content::text(summary),
+        Content::resource(ResourceContents::TextResourceContents {
+            uri: uri.to_string(),
+            mime_type: Some(geo::GEOJSON_MIME_TYPE.to_string()),
+            text: collection,
+        }),
+    ])
 }
 
 fn build_company_search_query(search_request: &SearchRequest) -> Result<String, McpError> {
-    let mut sql = "SELECT * FROM hello_nest WHERE 1=1".to_string();
+    let select_list = build_select_list(search_request)?;
+    let where_clause = build_where_clause(search_request)?;
+    let order_clause = build_order_clause(search_request)?;
+    let (limit, offset) = search_page(search_request);
+
+    Ok(format!(
+        "SELECT {} FROM hello_nest {} ORDER BY {} LIMIT {} OFFSET {}",
+        select_list, where_clause, order_clause, limit, offset
+    ))
+}
+
+fn build_select_list(search_request: &SearchRequest) -> Result<String, McpError> {
+    let requested = match &search_request.fields {
+        Some(fields) if !fields.is_empty() => fields.as_slice(),
+        _ => DEFAULT_SEARCH_FIELDS,
+    };
+
+    let mut fields: Vec<SearchField> = Vec::new();
+    for field in requested {
+        if !fields.contains(field) {
+            fields.push(*field);
+        }
+    }
+    if search_request.format == OutputFormat::Geojson {
+        for field in [SearchField::Lat, SearchField::Lon] {
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+    }
+
+    let currency = target_currency(search_request)?;
+    let mut select_list: Vec<String> = fields
+        .iter()
+        .map(|field| field.select_sql(currency))
+        .collect();
+    if let Some(near) = &search_request.near {
+        select_list.push(format!(
+            "{} AS distance_km",
+            geo::distance_km_sql(near.center()?)
+        ));
+    }
+    Ok(select_list.join(", "))
+}
+
+fn build_company_count_query(search_request: &SearchRequest) -> Result<String, McpError> {
+    let where_clause = build_where_clause(search_request)?;
+    Ok(format!("SELECT COUNT(*) FROM hello_nest {}", where_clause))
+}
+
+/// Effective (limit, offset) for a search request, with the limit capped.
+fn search_page(search_request: &SearchRequest) -> (u32, u32) {
+    let limit = search_request
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    (limit, search_request.offset.unwrap_or(0))
+}
+
+/// Canonical code of the requested `currency`, if any
+fn target_currency(search_request: &SearchRequest) -> Result<Option<&'static str>, McpError> {
+    search_request
+        .currency
+        .as_deref()
+        .map(|currency| {
+            fx::parse_currency(currency).ok_or_else(|| {
+                McpError::invalid_params(
+                    format!(
+                        "Unsupported currency \"{}\". Use one of {}",
+                        currency,
+                        fx::CURRENCIES.join(", ")
+                    ),
+                    None,
+                )
+            })
+        })
+        .transpose()
+}
+
+fn build_order_clause(search_request: &SearchRequest) -> Result<String, McpError> {
+    let currency = target_currency(search_request)?;
+    let sort_by = search_request
+        .sort_by
+        .clone()
+        .unwrap_or(SortBy::Field(SortField::CompanyName));
+
+    let (sort_expr, default_order) = match sort_by {
+        SortBy::Field(SortField::CompanyName) => ("company_name".to_string(), SortOrder::Asc),
+        SortBy::Field(SortField::FoundationYear) => {
+            ("foundation_year".to_string(), SortOrder::Desc)
+        }
+        SortBy::Field(SortField::Revenue) => (
+            metrics::latest_metric_in_currency_sql(metrics::REVENUE_METRIC, currency),
+            SortOrder::Desc,
+        ),
+        SortBy::Field(SortField::Employees) => (
+            metrics::latest_metric_sql(metrics::EMPLOYEES_METRIC),
+            SortOrder::Desc,
+        ),
+        SortBy::Field(SortField::RevenueGrowth) => ("revenue_cagr_3y".to_string(), SortOrder::Desc),
+        SortBy::Field(SortField::Distance) => match &search_request.near {
+            Some(near) => (geo::distance_km_sql(near.center()?), SortOrder::Asc),
+            None => {
+                return Err(McpError::invalid_params(
+                    "Sorting by distance requires a `near` filter".to_string(),
+                    None,
+                ));
+            }
+        },
+        SortBy::Metric { metric, year } => {
+            validate_metric(&metric, Some(year))?;
+            (
+                metrics::metric_in_currency_sql(&metric, year, currency),
+                SortOrder::Desc,
+            )
+        }
+    };
+
+    let direction = match search_request.sort_order.unwrap_or(default_order) {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+
+    // company_id breaks ties so that paging with offset is stable
+    Ok(format!(
+        "{} {} NULLS LAST, company_id",
+        sort_expr, direction
+    ))
+}
+
+/// Reject metric names outside `FINANCIAL_METRICS_BASE` (they end up inside
+/// SQL string literals) and years the metric has no data for.
+fn validate_metric(metric: &str, year: Option<i64>) -> Result<(), McpError> {
+    if !metrics::is_known_metric(metric) {
+        return Err(McpError::invalid_params(
+            format!("Unknown financial metric: {}", metric),
+            None,
+        ));
+    }
+    if let Some(year) = year
+        && !metrics::is_reported_in(metric, year)
+    {
+        return Err(McpError::invalid_params(
+            format!("No \"{}\" data for year {}", metric, year),
+            None,
+        ));
+    }
+    Ok(())
+}
+
+/// Condition for one metric filter, with monetary values compared in
+/// `currency` when one is given
+fn build_metric_filter_condition(
+    filter: &MetricFilter,
+    currency: Option<&str>,
+) -> Result<String, McpError> {
+    let year = match filter.year {
+        MetricYear::Year(year) => Some(year),
+        MetricYear::Mode(_) => None,
+    };
+    validate_metric(&filter.metric, year)?;
+    if !filter.value.is_finite() {
+        return Err(McpError::invalid_params(
+            format!(
+                "Filter value for \"{}\" must be a finite number",
+                filter.metric
+            ),
+            None,
+        ));
+    }
+
+    let predicate = |expr: &str| format!("{} {} {}", expr, filter.op.sql(), filter.value);
+    Ok(match filter.year {
+        MetricYear::Year(year) => predicate(&metrics::metric_in_currency_sql(
+            &filter.metric,
+            year,
+            currency,
+        )),
+        MetricYear::Mode(YearMode::Latest) => predicate(&metrics::latest_metric_in_currency_sql(
+            &filter.metric,
+            currency,
+        )),
+        MetricYear::Mode(YearMode::Any) => {
+            metrics::any_year_sql(&filter.metric, "value", &predicate("value"), currency)
+        }
+        MetricYear::Mode(YearMode::All) => {
+            metrics::all_years_sql(&filter.metric, "value", &predicate("value"), currency)
+        }
+    })
+}
+
+/// `metric BETWEEN min AND max` for the years picked by `year`, defaulting to
+/// the latest financial year like metric filters. `column` names the per-year value inside the any/all subqueries.
+/// Monetary metrics are compared in `currency` when one is given.
+fn build_range_condition(
+    metric: &str,
+    column: &str,
+    year: Option<MetricYear>,
+    (min, max): (f64, f64),
+    currency: Option<&str>,
+) -> Result<String, McpError> {
+    let between = |expr: &str| format!("{} BETWEEN {} AND {}", expr, min, max);
+    Ok(match year.unwrap_or_default() {
+        MetricYear::Year(year) => {
+            validate_metric(metric, Some(year))?;
+            between(&metrics::metric_in_currency_sql(metric, year, currency))
+        }
+        MetricYear::Mode(YearMode::Latest) => {
+            between(&metrics::latest_metric_in_currency_sql(metric, currency))
+        }
+        // Use STRUCT access for better performance than JSON functions
+        MetricYear::Mode(YearMode::Any) => {
+            metrics::any_year_sql(metric, column, &between(column), currency)
+        }
+        MetricYear::Mode(YearMode::All) => {
+            metrics::all_years_sql(metric, column, &between(column), currency)
+        }
+    })
+}
+
+/// Resolve optional growth years: both or neither must be given, and the
+/// metric must exist in both years.
+fn growth_years(
+    metric: &str,
+    from_year: Option<i64>,
+    to_year: Option<i64>,
+) -> Result<Option<(i64, i64)>, McpError> {
+    match (from_year, to_year) {
+        (None, None) => Ok(None),
+        (Some(from_year), Some(to_year)) => {
+            if from_year >= to_year {
+                return Err(McpError::invalid_params(
+                    "Growth from_year must be before to_year".to_string(),
+                    None,
+                ));
+            }
+            validate_metric(metric, Some(from_year))?;
+            validate_metric(metric, Some(to_year))?;
+            Ok(Some((from_year, to_year)))
+        }
+        _ => Err(McpError::invalid_params(
+            "Growth filters need both from_year and to_year, or neither".to_string(),
+            None,
+        )),
+    }
+}
+
+/// `expr >= min AND expr <= max` for whichever bounds are set
+fn bounds_condition(expr: &str, min: Option<f64>, max: Option<f64>) -> Option<String> {
+    let mut bounds = Vec::new();
+    if let Some(min) = min {
+        bounds.push(format!("{} >= {}", expr, min));
+    }
+    if let Some(max) = max {
+        bounds.push(format!("{} <= {}", expr, max));
+    }
+    (!bounds.is_empty()).then(|| format!("({})", bounds.join(" AND ")))
+}
+
+fn build_revenue_growth_condition(
+    filter: &RevenueGrowthFilter,
+) -> Result<Option<String>, McpError> {
+    let cagr = match growth_years(metrics::REVENUE_METRIC, filter.from_year, filter.to_year)? {
+        Some((from_year, to_year)) => metrics::cagr_sql(
+            &metrics::metric_sql(metrics::REVENUE_METRIC, from_year),
+            &metrics::metric_sql(metrics::REVENUE_METRIC, to_year),
+            to_year - from_year,
+        ),
+        None => "revenue_cagr_3y".to_string(),
+    };
+    // Filters are given in percent, the CAGR is a fraction
+    Ok(bounds_condition(
+        &cagr,
+        filter.min_cagr_percent.map(|percent| percent / 100.0),
+        filter.max_cagr_percent.map(|percent| percent / 100.0),
+    ))
+}
+
+fn build_employee_growth_condition(
+    filter: &EmployeeGrowthFilter,
+) -> Result<Option<String>, McpError> {
+    let change = match growth_years(metrics::EMPLOYEES_METRIC, filter.from_year, filter.to_year)? {
+        Some((from_year, to_year)) => format!(
+            "({} - {})",
+            metrics::metric_sql(metrics::EMPLOYEES_METRIC, to_year),
+            metrics::metric_sql(metrics::EMPLOYEES_METRIC, from_year)
+        ),
+        None => "employee_change_3y".to_string(),
+    };
+    Ok(bounds_condition(
+        &change,
+        filter.min_change,
+        filter.max_change,
+    ))
+}
+
+/// Non-empty, trimmed values of a list filter
+fn list_values(values: &Option<Vec<String>>) -> Vec<&str> {
+    values
+        .iter()
+        .flatten()
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+fn build_county_condition(counties: &[&str]) -> Result<String, McpError> {
+    let codes = counties
+        .iter()
+        .map(|name| {
+            gazetteer::find_county(name)
+                .map(|county| format!("'{}'", county.code))
+                .ok_or_else(|| {
+                    let known: Vec<&str> = gazetteer::counties().iter().map(|c| c.name).collect();
+                    McpError::invalid_params(
+                        format!(
+                            "Unknown county \"{}\". Known counties: {}",
+                            name,
+                            known.join(", ")
+                        ),
+                        None,
+                    )
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(format!("county_code IN ({})", codes.join(", ")))
+}
+
+fn build_municipality_condition(municipalities: &[&str]) -> Result<String, McpError> {
+    let codes = municipalities
+        .iter()
+        .map(|name| {
+            gazetteer::find_municipality(name)
+                .map(|municipality| format!("'{}'", municipality.code))
+                .ok_or_else(|| {
+                    McpError::invalid_params(
+                        format!("Unknown Swedish municipality \"{}\"", name),
+                        None,
+                    )
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(format!("municipality_code IN ({})", codes.join(", ")))
+}
+
+fn build_country_part_condition(country_parts: &[&str]) -> Result<String, McpError> {
+    let mut part_conditions = Vec::new();
+    for part in country_parts {
+        // Basic SQL injection protection
+        if part.contains("'") || part.contains(";") || part.contains("--") {
+            return Err(McpError::invalid_params(
+                "Invalid characters in country part".to_string(),
+                None,
+            ));
+        }
+        part_conditions.push(format!(
+            "contains(lower(strip_accents(location.countryPart)), lower(strip_accents('{}')))",
+            part
+        ));
+    }
+    Ok(format!("({})", part_conditions.join(" OR ")))
+}
+
+fn build_country_condition(countries: &[&str]) -> Result<String, McpError> {
+    let codes = countries
+        .iter()
+        .map(|name| {
+            Country::parse(name)
+                .map(|country| format!("'{}'", country.code()))
+                .ok_or_else(|| {
+                    McpError::invalid_params(
+                        format!("Unknown country \"{}\". Use SE, NO or DK", name),
+                        None,
+                    )
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(format!("country IN ({})", codes.join(", ")))
+}
+
+fn validate_position(lat: f64, lon: f64) -> Result<(), McpError> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(McpError::invalid_params(
+            format!("Invalid WGS84 position: lat {}, lon {}", lat, lon),
+            None,
+        ));
+    }
+    Ok(())
+}
+
+fn build_near_condition(near: &NearFilter) -> Result<String, McpError> {
+    let center = near.center()?;
+    if !(near.radius_km > 0.0 && near.radius_km <= MAX_RADIUS_KM) {
+        return Err(McpError::invalid_params(
+            format!("radius_km must be between 0 and {}", MAX_RADIUS_KM),
+            None,
+        ));
+    }
+    // The latitude band is a cheap pre-filter before the exact distance
+    let lat_margin = near.radius_km / geo::KM_PER_DEGREE_LATITUDE;
+    Ok(format!(
+        "lat BETWEEN {} AND {} AND {} <= {}",
+        center.lat - lat_margin,
+        center.lat + lat_margin,
+        geo::distance_km_sql(center),
+        near.radius_km
+    ))
+}
+
+fn build_bbox_condition(bbox: &BoundingBox) -> Result<String, McpError> {
+    validate_position(bbox.min_lat, bbox.min_lon)?;
+    validate_position(bbox.max_lat, bbox.max_lon)?;
+    if bbox.min_lat > bbox.max_lat || bbox.min_lon > bbox.max_lon {
+        return Err(McpError::invalid_params(
+            "Bounding box minimums cannot be greater than maximums".to_string(),
+            None,
+        ));
+    }
+    Ok(format!(
+        "lat BETWEEN {} AND {} AND lon BETWEEN {} AND {}",
+        bbox.min_lat, bbox.max_lat, bbox.min_lon, bbox.max_lon
+    ))
+}
+
+fn build_where_clause(search_request: &SearchRequest) -> Result<String, McpError> {
+    let mut sql = "WHERE 1=1".to_string();
     let mut conditions = Vec::new();
+    let currency = target_currency(search_request)?;
 
     if let Some(company_name) = &search_request.company_name {
         let trimmed_name = company_name.trim();
@@ -435,29 +1537,28 @@ fn build_company_search_query(search_request: &SearchRequest) -> Result<String,
         ));
     }
 
-    if let Some(nace_categories) = &search_request.nace_categories {
-        if !nace_categories.is_empty() {
-            let mut category_conditions = Vec::new();
-            for category in nace_categories {
-                let trimmed_category = category.trim();
-                if !trimmed_category.is_empty() {
-                    // Basic SQL injection protection
-                    if trimmed_category.contains("'")
-                        || trimmed_category.contains(";")
-                        || trimmed_category.contains("--")
-                    {
-                        return Err(McpError::invalid_params(
-                            "Invalid characters in NACE categories".to_string(),
-                            None,
-                        ));
-                    }
-                    category_conditions
-                        .push(format!("nace_categories ILIKE '%{}%'", trimmed_category));
+    if let Some(nace_categories) = &search_request.nace_categories
+        && !nace_categories.is_empty()
+    {
+        let mut category_conditions = Vec::new();
+        for category in nace_categories {
+            let trimmed_category = category.trim();
+            if !trimmed_category.is_empty() {
+                // Basic SQL injection protection
+                if trimmed_category.contains("'")
+                    || trimmed_category.contains(";")
+                    || trimmed_category.contains("--")
+                {
+                    return Err(McpError::invalid_params(
+                        "Invalid characters in NACE categories".to_string(),
+                        None,
+                    ));
                 }
+                category_conditions.push(format!("nace_categories ILIKE '%{}%'", trimmed_category));
             }
-            if !category_conditions.is_empty() {
-                conditions.push(format!("({})", category_conditions.join(" OR ")));
-            }
+        }
+        if !category_conditions.is_empty() {
+            conditions.push(format!("({})", category_conditions.join(" OR ")));
         }
     }
     if let Some(company_purpose) = &search_request.company_purpose {
@@ -490,23 +1591,13 @@ fn build_company_search_query(search_request: &SearchRequest) -> Result<String,
                 None,
             ));
         }
-        // Search in financial_data for Sales revenues across all years
-        // Use STRUCT access for better performance than JSON functions
-        conditions.push(format!(
-            "EXISTS (SELECT 1 FROM (VALUES
-                (financial_data['2016']['Sales revenues']),
-                (financial_data['2017']['Sales revenues']),
-                (financial_data['2018']['Sales revenues']),
-                (financial_data['2019']['Sales revenues']),
-                (financial_data['2020']['Sales revenues']),
-                (financial_data['2021']['Sales revenues']),
-                (financial_data['2022']['Sales revenues']),
-                (financial_data['2023']['Sales revenues']),
-                (financial_data['2024']['Sales revenues'])
-            ) AS revenue_data(revenue)
-            WHERE revenue IS NOT NULL AND revenue BETWEEN {} AND {})",
-            min_revenue, max_revenue
-        ));
+        conditions.push(build_range_condition(
+            metrics::REVENUE_METRIC,
+            "revenue",
+            search_request.revenue_year,
+            (min_revenue, max_revenue),
+            currency,
+        )?);
     }
 
     if let Some((min_employees, max_employees)) = search_request.employee_range {
@@ -522,32 +1613,73 @@ fn build_company_search_query(search_request: &SearchRequest) -> Result<String,
                 None,
             ));
         }
-        // Search in financial_data for Employees from accounting across all years
-        // Use STRUCT access for better performance than JSON functions
+        conditions.push(build_range_condition(
+            metrics::EMPLOYEES_METRIC,
+            "employees",
+            search_request.employee_year,
+            (min_employees, max_employees),
+            currency,
+        )?);
+    }
+
+    if let Some(metric_filters) = &search_request.metric_filters {
+        for filter in metric_filters {
+            conditions.push(build_metric_filter_condition(filter, currency)?);
+        }
+    }
+
+    if let Some(revenue_growth) = &search_request.revenue_growth
+        && let Some(condition) = build_revenue_growth_condition(revenue_growth)?
+    {
+        conditions.push(condition);
+    }
+
+    if let Some(employee_growth) = &search_request.employee_growth
+        && let Some(condition) = build_employee_growth_condition(employee_growth)?
+    {
+        conditions.push(condition);
+    }
+
+    if let Some(min_profitable_years) = search_request.min_profitable_years {
         conditions.push(format!(
-            "EXISTS (SELECT 1 FROM (VALUES
-                (financial_data['2016']['Employees from accounting']),
-                (financial_data['2017']['Employees from accounting']),
-                (financial_data['2018']['Employees from accounting']),
-                (financial_data['2019']['Employees from accounting']),
-                (financial_data['2020']['Employees from accounting']),
-                (financial_data['2021']['Employees from accounting']),
-                (financial_data['2022']['Employees from accounting']),
-                (financial_data['2023']['Employees from accounting']),
-                (financial_data['2024']['Employees from accounting'])
-            ) AS employee_data(employees)
-            WHERE employees IS NOT NULL AND employees BETWEEN {} AND {})",
-            min_employees, max_employees
+            "profitable_years_streak >= {}",
+            min_profitable_years
         ));
     }
 
+    let counties = list_values(&search_request.county);
+    if !counties.is_empty() {
+        conditions.push(build_county_condition(&counties)?);
+    }
+
+    let municipalities = list_values(&search_request.municipality);
+    if !municipalities.is_empty() {
+        conditions.push(build_municipality_condition(&municipalities)?);
+    }
+
+    let countries = list_values(&search_request.country);
+    if !countries.is_empty() {
+        conditions.push(build_country_condition(&countries)?);
+    }
+
+    let country_parts = list_values(&search_request.country_part);
+    if !country_parts.is_empty() {
+        conditions.push(build_country_part_condition(&country_parts)?);
+    }
+
+    if let Some(near) = &search_request.near {
+        conditions.push(build_near_condition(near)?);
+    }
+
+    if let Some(bbox) = &search_request.bbox {
+        conditions.push(build_bbox_condition(bbox)?);
+    }
+
     if !conditions.is_empty() {
         sql.push_str(" AND ");
         sql.push_str(&conditions.join(" AND "));
     }
 
-    sql.push_str(" ORDER BY company_name LIMIT 1000");
-
     Ok(sql)
 }
 
@@ -555,6 +1687,23 @@ fn build_company_search_query(search_request: &SearchRequest) -> Result<String,
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_limit_rows() {
+        assert_eq!(
+            limit_rows("SELECT company_name FROM hello_nest;", 100),
+            "SELECT * FROM (SELECT company_name FROM hello_nest\n) LIMIT 100"
+        );
+        assert_eq!(
+            limit_rows("select 1 -- trailing comment", 5),
+            "SELECT * FROM (select 1 -- trailing comment\n) LIMIT 5"
+        );
+        assert_eq!(
+            limit_rows("DESCRIBE hello_nest", 100),
+            "DESCRIBE hello_nest"
+        );
+        assert_eq!(limit_rows("SELECT 1", 0), "SELECT 1");
+    }
+
     #[test]
     fn test_build_company_search_query_basic() {
         let search_request = SearchRequest {
@@ -564,13 +1713,16 @@ mod tests {
             company_purpose: None,
             revenue_range: None,
             employee_range: None,
+            ..Default::default()
         };
 
         let query = build_company_search_query(&search_request).unwrap();
 
         assert!(query.contains("company_name ILIKE '%Test Company%'"));
         assert!(query.contains("foundation_year BETWEEN 2020 AND 2023"));
-        assert!(query.contains("ORDER BY company_name LIMIT 1000"));
+        assert!(
+            query.contains("ORDER BY company_name ASC NULLS LAST, company_id LIMIT 1000 OFFSET 0")
+        );
     }
 
     #[test]
@@ -582,6 +1734,7 @@ mod tests {
             company_purpose: None,
             revenue_range: None,
             employee_range: None,
+            ..Default::default()
         };
 
         let query = build_company_search_query(&search_request).unwrap();
@@ -600,7 +1753,9 @@ mod tests {
             nace_categories: None,
             company_purpose: None,
             revenue_range: Some((1000000.0, 5000000.0)),
+            revenue_year: Some(MetricYear::Mode(YearMode::Any)),
             employee_range: None,
+            ..Default::default()
         };
 
         let query = build_company_search_query(&search_request).unwrap();
@@ -620,6 +1775,8 @@ mod tests {
             company_purpose: None,
             revenue_range: None,
             employee_range: Some((10.0, 100.0)),
+            employee_year: Some(MetricYear::Mode(YearMode::Any)),
+            ..Default::default()
         };
 
         let query = build_company_search_query(&search_request).unwrap();
@@ -639,6 +1796,7 @@ mod tests {
             company_purpose: None,
             revenue_range: None,
             employee_range: None,
+            ..Default::default()
         };
 
         let result = build_company_search_query(&search_request);
@@ -654,6 +1812,7 @@ mod tests {
             company_purpose: None,
             revenue_range: None,
             employee_range: None,
+            ..Default::default()
         };
 
         let result = build_company_search_query(&search_request);
@@ -669,13 +1828,16 @@ mod tests {
             company_purpose: None,
             revenue_range: None,
             employee_range: None,
+            ..Default::default()
         };
 
         let query = build_company_search_query(&search_request).unwrap();
 
         // Should return all companies with basic ordering
-        assert!(query.contains("SELECT * FROM hello_nest WHERE 1=1"));
-        assert!(query.contains("ORDER BY company_name LIMIT 1000"));
+        assert!(query.contains("FROM hello_nest WHERE 1=1 ORDER BY"));
+        assert!(
+            query.contains("ORDER BY company_name ASC NULLS LAST, company_id LIMIT 1000 OFFSET 0")
+        );
         assert!(!query.contains(" AND ")); // No additional conditions
     }
 
@@ -688,7 +1850,10 @@ mod tests {
             nace_categories: Some(vec!["62010".to_string()]),
             company_purpose: None,
             revenue_range: Some((1000000.0, 10000000.0)),
+            revenue_year: Some(MetricYear::Mode(YearMode::Any)),
             employee_range: Some((10.0, 100.0)),
+            employee_year: Some(MetricYear::Mode(YearMode::Any)),
+            ..Default::default()
         };
 
         let query = build_company_search_query(&search_request).unwrap();
@@ -703,22 +1868,438 @@ mod tests {
         assert!(query.contains("foundation_year BETWEEN 2020 AND 2024"));
     }
 
-    // Integration tests that require the actual database
+    #[test]
+    fn test_sort_by_latest_revenue_defaults_to_descending() {
+        let search_request = SearchRequest {
+            sort_by: Some(SortBy::Field(SortField::Revenue)),
+            ..Default::default()
+        };
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        assert!(
+            query.contains(
+                "ORDER BY latest_financials['Sales revenues'] DESC NULLS LAST, company_id"
+            )
+        );
+    }
+
+    #[test]
+    fn test_sort_by_metric_for_year() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "sort_by": {"metric": "Operating margin", "year": 2023},
+            "sort_order": "asc"
+        }))
+        .unwrap();
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        assert!(query.contains(
+            "ORDER BY financial_data['2023']['Operating margin'] ASC NULLS LAST, company_id"
+        ));
+    }
+
+    #[test]
+    fn test_sort_by_rejects_unknown_metric_and_year() {
+        let unknown_metric = SearchRequest {
+            sort_by: Some(SortBy::Metric {
+                metric: "Sales revenues'] --".to_string(),
+                year: 2023,
+            }),
+            ..Default::default()
+        };
+        assert!(build_company_search_query(&unknown_metric).is_err());
+
+        let unknown_year = SearchRequest {
+            sort_by: Some(SortBy::Metric {
+                metric: "Sales revenues".to_string(),
+                year: 2030,
+            }),
+            ..Default::default()
+        };
+        assert!(build_company_search_query(&unknown_year).is_err());
+    }
+
+    #[test]
+    fn test_limit_is_capped_and_offset_applied() {
+        let search_request = SearchRequest {
+            company_name: Some("AB".to_string()),
+            limit: Some(50_000),
+            offset: Some(2000),
+            ..Default::default()
+        };
+
+        let query = build_company_search_query(&search_request).unwrap();
+        assert!(query.ends_with("LIMIT 1000 OFFSET 2000"));
+
+        let count_query = build_company_count_query(&search_request).unwrap();
+        assert_eq!(
+            count_query,
+            "SELECT COUNT(*) FROM hello_nest WHERE 1=1 AND company_name ILIKE '%AB%'"
+        );
+    }
+
+    #[test]
+    fn test_default_fields_are_compact() {
+        let query = build_company_search_query(&SearchRequest::default()).unwrap();
+
+        assert!(query.starts_with(
+            "SELECT company_id, company_name, organization_number, municipality, nace_categories, latest_financials['Sales revenues'] AS revenue, currency, latest_financials['Employees from accounting'] AS employees FROM hello_nest"
+        ));
+        assert!(!query.contains("SELECT *"));
+        assert!(!query.contains(" financial_data,"));
+    }
+
+    #[test]
+    fn test_requested_fields_are_projected_once() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "fields": ["company_name", "county", "financial_data", "company_name", "revenue_cagr_3y"]
+        }))
+        .unwrap();
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        assert!(query.starts_with(
+            "SELECT company_name, county, financial_data, revenue_cagr_3y FROM hello_nest"
+        ));
+    }
+
+    #[test]
+    fn test_metric_filters_for_each_year_mode() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "metric_filters": [
+                {"metric": "Operating margin", "year": 2023, "op": ">=", "value": 0.1},
+                {"metric": "Total assets", "op": ">", "value": 1000000},
+                {"metric": "Return on equity", "year": "any", "op": "<", "value": 0},
+                {"metric": "Minority interests", "year": "all", "op": "=", "value": 0}
+            ]
+        }))
+        .unwrap();
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        assert!(query.contains("financial_data['2023']['Operating margin'] >= 0.1"));
+        assert!(query.contains("latest_financials['Total assets'] > 1000000"));
+        assert!(query.contains(
+            "EXISTS (SELECT 1 FROM (VALUES (financial_data['2016']['Return on equity'])"
+        ));
+        assert!(query.contains("WHERE value IS NOT NULL AND value < 0)"));
+        assert!(query.contains("(SELECT bool_and(value = 0) FROM (VALUES (financial_data['2016']['Minority interests'])"));
+    }
+
+    #[test]
+    fn test_revenue_and_employee_range_year_modes() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "revenue_range": [1000000, 10000000],
+            "revenue_year": "latest",
+            "employee_range": [10, 50],
+            "employee_year": 2022
+        }))
+        .unwrap();
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        assert!(query.contains("latest_financials['Sales revenues'] BETWEEN 1000000 AND 10000000"));
+        assert!(!query.contains("financial_data['2016']['Sales revenues']"));
+        assert!(
+            query.contains("financial_data['2022']['Employees from accounting'] BETWEEN 10 AND 50")
+        );
+
+        // Without a year the range applies to the latest financial year, so
+        // an old revenue in range does not match
+        let default_year: SearchRequest = serde_json::from_value(serde_json::json!({
+            "revenue_range": [1000000, 10000000],
+            "employee_range": [10, 50]
+        }))
+        .unwrap();
+        let query = build_company_search_query(&default_year).unwrap();
+        assert!(query.contains("latest_financials['Sales revenues'] BETWEEN 1000000 AND 10000000"));
+        assert!(query.contains("latest_financials['Employees from accounting'] BETWEEN 10 AND 50"));
+        assert!(!query.contains("financial_data['2016']"));
+
+        let all_years: SearchRequest = serde_json::from_value(serde_json::json!({
+            "revenue_range": [1000000, 10000000],
+            "revenue_year": "all"
+        }))
+        .unwrap();
+        let query = build_company_search_query(&all_years).unwrap();
+        assert!(
+            query.contains("(SELECT bool_and(revenue BETWEEN 1000000 AND 10000000) FROM (VALUES")
+        );
+    }
+
+    #[test]
+    fn test_growth_filters() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "revenue_growth": {"from_year": 2020, "to_year": 2024, "min_cagr_percent": 20},
+            "employee_growth": {"min_change": 5},
+            "min_profitable_years": 3
+        }))
+        .unwrap();
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        assert!(query.contains(
+            "(CASE WHEN financial_data['2020']['Sales revenues'] > 0 AND financial_data['2024']['Sales revenues'] > 0 THEN power(financial_data['2024']['Sales revenues'] / financial_data['2020']['Sales revenues'], 1.0 / 4) - 1 END >= 0.2)"
+        ));
+        assert!(query.contains("(employee_change_3y >= 5)"));
+        assert!(query.contains("profitable_years_streak >= 3"));
+    }
+
+    #[test]
+    fn test_growth_filters_need_ordered_year_pair() {
+        let one_year: SearchRequest = serde_json::from_value(serde_json::json!({
+            "revenue_growth": {"from_year": 2020, "min_cagr_percent": 20}
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&one_year).is_err());
+
+        let reversed: SearchRequest = serde_json::from_value(serde_json::json!({
+            "employee_growth": {"from_year": 2024, "to_year": 2020, "min_change": 1}
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&reversed).is_err());
+    }
+
+    #[test]
+    fn test_geojson_format_selects_coordinates() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "fields": ["company_name", "lat"],
+            "format": "geojson"
+        }))
+        .unwrap();
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        assert!(query.starts_with("SELECT company_name, lat, lon FROM hello_nest"));
+    }
+
+    #[test]
+    fn test_geojson_contents() {
+        let rows = vec![serde_json::json!({"company_name": "A", "lat": 59.3, "lon": 18.0})];
+        let mut summary = Map::new();
+        summary.insert("total_count".to_string(), 1.into());
+
+        let contents =
+            geojson_contents("nest://company-search/results.geojson", &rows, summary).unwrap();
+
+        let summary: Value = serde_json::from_str(&contents[0].as_text().unwrap().text).unwrap();
+        assert_eq!(summary["total_count"], 1);
+        assert_eq!(summary["features"], 1);
+        assert_eq!(summary["skipped_without_coordinates"], 0);
+
+        match &contents[1].as_resource().unwrap().resource {
+            ResourceContents::TextResourceContents {
+                uri,
+                mime_type,
+                text,
+            } => {
+                assert_eq!(uri, "nest://company-search/results.geojson");
+                assert_eq!(mime_type.as_deref(), Some("application/geo+json"));
+                let collection: Value = serde_json::from_str(text).unwrap();
+                assert_eq!(
+                    collection["features"][0]["geometry"]["coordinates"],
+                    serde_json::json!([18.0, 59.3])
+                );
+            }
+            other => panic!("Expected a text resource, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_location_list_filters() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "county": ["stockholms lan", "Skåne", " "],
+            "municipality": ["GOTEBORG", "Mölndal"],
+            "country_part": ["Vastsverige"]
+        }))
+        .unwrap();
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        assert!(query.contains("county_code IN ('01', '12')"));
+        assert!(query.contains("municipality_code IN ('1480', '1481')"));
+        assert!(query.contains(
+            "(contains(lower(strip_accents(location.countryPart)), lower(strip_accents('Vastsverige'))))"
+        ));
+
+        let unknown_county: SearchRequest = serde_json::from_value(serde_json::json!({
+            "county": ["Stockholm County"]
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&unknown_county).is_err());
+
+        let injected: SearchRequest = serde_json::from_value(serde_json::json!({
+            "country_part": ["x') OR 1=1 --"]
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&injected).is_err());
+    }
+
+    #[test]
+    fn test_country_filter() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "country": ["Norge", "dk"],
+            "fields": ["company_name", "country"]
+        }))
+        .unwrap();
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        assert!(query.starts_with("SELECT company_name, country FROM hello_nest"));
+        assert!(query.contains("country IN ('NO', 'DK')"));
+
+        let unknown: SearchRequest = serde_json::from_value(serde_json::json!({
+            "country": ["Finland"]
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_currency_converts_monetary_values_only() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "currency": "eur",
+            "fields": ["company_name", "revenue", "employees"],
+            "revenue_range": [1000000, 5000000],
+            "revenue_year": 2023,
+            "employee_range": [10, 50],
+            "employee_year": "latest",
+            "metric_filters": [
+                {"metric": "Total assets", "year": "any", "op": ">", "value": 100000},
+                {"metric": "Operating margin", "year": 2023, "op": ">=", "value": 0.1}
+            ],
+            "sort_by": "revenue"
+        }))
+        .unwrap();
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        let revenue = "(latest_financials['Sales revenues'] * fx_rate(latest_financial_year, currency, 'EUR'))";
+        assert!(query.starts_with(&format!(
+            "SELECT company_name, {} AS revenue, latest_financials['Employees from accounting'] AS employees",
+            revenue
+        )));
+        assert!(query.contains(
+            "(financial_data['2023']['Sales revenues'] * fx_rate(2023, currency, 'EUR')) BETWEEN 1000000 AND 5000000"
+        ));
+        assert!(query.contains("latest_financials['Employees from accounting'] BETWEEN 10 AND 50"));
+        assert!(
+            query.contains(
+                "(financial_data['2016']['Total assets'] * fx_rate(2016, currency, 'EUR'))"
+            )
+        );
+        assert!(query.contains("financial_data['2023']['Operating margin'] >= 0.1"));
+        assert!(query.contains(&format!("ORDER BY {} DESC NULLS LAST", revenue)));
+
+        let unsupported: SearchRequest = serde_json::from_value(serde_json::json!({
+            "currency": "USD"
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&unsupported).is_err());
+    }
+
+    #[test]
+    fn test_near_filter_and_distance_sort() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "near": {"lat": 57.7, "lon": 11.97, "radius_km": 20},
+            "sort_by": "distance",
+            "fields": ["company_name"]
+        }))
+        .unwrap();
+
+        let query = build_company_search_query(&search_request).unwrap();
+
+        assert!(query.starts_with(
+            "SELECT company_name, distance_km(lat, lon, 57.7, 11.97) AS distance_km FROM hello_nest"
+        ));
+        assert!(query.contains("distance_km(lat, lon, 57.7, 11.97) <= 20"));
+        assert!(query.contains("ORDER BY distance_km(lat, lon, 57.7, 11.97) ASC NULLS LAST"));
+    }
+
+    #[test]
+    fn test_near_place_resolves_through_gazetteer() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "near": {"place": "uppsala kommun", "radius_km": 10}
+        }))
+        .unwrap();
+        let query = build_company_search_query(&search_request).unwrap();
+        assert!(query.contains("distance_km(lat, lon, 59.8586, 17.6389) <= 10"));
+
+        let unknown_place: SearchRequest = serde_json::from_value(serde_json::json!({
+            "near": {"place": "Atlantis", "radius_km": 10}
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&unknown_place).is_err());
+
+        let place_and_position: SearchRequest = serde_json::from_value(serde_json::json!({
+            "near": {"place": "Uppsala", "lat": 59.0, "lon": 17.0, "radius_km": 10}
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&place_and_position).is_err());
+    }
+
+    #[test]
+    fn test_bbox_filter_and_geo_validation() {
+        let search_request: SearchRequest = serde_json::from_value(serde_json::json!({
+            "bbox": {"min_lat": 59.2, "min_lon": 17.8, "max_lat": 59.45, "max_lon": 18.3}
+        }))
+        .unwrap();
+        let query = build_company_search_query(&search_request).unwrap();
+        assert!(query.contains("lat BETWEEN 59.2 AND 59.45 AND lon BETWEEN 17.8 AND 18.3"));
+
+        let distance_without_near = SearchRequest {
+            sort_by: Some(SortBy::Field(SortField::Distance)),
+            ..Default::default()
+        };
+        assert!(build_company_search_query(&distance_without_near).is_err());
+
+        let bad_radius: SearchRequest = serde_json::from_value(serde_json::json!({
+            "near": {"lat": 57.7, "lon": 11.97, "radius_km": -1}
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&bad_radius).is_err());
+    }
+
+    #[test]
+    fn test_metric_filters_reject_unknown_metric_and_missing_year() {
+        let unknown_metric: SearchRequest = serde_json::from_value(serde_json::json!({
+            "metric_filters": [{"metric": "Sales revenues' OR 1=1 --", "op": ">", "value": 0}]
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&unknown_metric).is_err());
+
+        let missing_year: SearchRequest = serde_json::from_value(serde_json::json!({
+            "metric_filters": [{"metric": "Sales revenues", "year": 2015, "op": ">", "value": 0}]
+        }))
+        .unwrap();
+        assert!(build_company_search_query(&missing_year).is_err());
+
+        // Every year has every metric, NULL where the source had none
+        let minority_interests: SearchRequest = serde_json::from_value(serde_json::json!({
+            "metric_filters": [{"metric": "Minority interests", "year": 2017, "op": ">", "value": 0}]
+        }))
+        .unwrap();
+        let query = build_company_search_query(&minority_interests).unwrap();
+        assert!(query.contains("financial_data['2017']['Minority interests'] > 0"));
+    }
+
+    // Integration tests that require the actual database; build it first with
+    // `nest-mcp create-db`
     #[tokio::test]
     #[ignore] // Run with: cargo test -- --ignored
     async fn integration_test_company_sql_schema_validation() {
-        use crate::duckdb::{DuckDB, DuckDbConfig};
         use rmcp::handler::server::tool::Parameters;
 
-        let mut config = DuckDbConfig::default();
-        config.access_mode = duck::AccessMode::ReadOnly;
-        let _db = DuckDB::new(config).await.expect("Database connection");
-
-        let tool = Tool::new();
+        let config = crate::config::Config::default();
+        let snapshots = Snapshots::open(&config).await.expect("Database connection");
+        let tool = Tool::new(Arc::new(snapshots), config.query);
 
         // Test DATE type for established_date
         let query_request = Parameters(QueryRequest {
             sql: "SELECT company_name, established_date FROM hello_nest WHERE established_date > DATE '2020-01-01' LIMIT 1".to_string(),
+            format: OutputFormat::Json,
+            as_of: None,
         });
         let result = tool.company(query_request).await;
         assert!(result.is_ok(), "DATE query should work");
@@ -726,6 +2307,8 @@ mod tests {
         // Test VARCHAR type for nace_categories
         let query_request = Parameters(QueryRequest {
             sql: "SELECT company_name, nace_categories FROM hello_nest WHERE nace_categories IS NOT NULL LIMIT 1".to_string(),
+            format: OutputFormat::Json,
+            as_of: None,
         });
         let result = tool.company(query_request).await;
         assert!(result.is_ok(), "VARCHAR query should work");
@@ -733,6 +2316,8 @@ mod tests {
         // Test STRUCT type for location
         let query_request = Parameters(QueryRequest {
             sql: "SELECT company_name, location.county, location.coordinates.XCoordinate FROM hello_nest WHERE location IS NOT NULL LIMIT 1".to_string(),
+            format: OutputFormat::Json,
+            as_of: None,
         });
         let result = tool.company(query_request).await;
         assert!(result.is_ok(), "STRUCT query should work");
@@ -741,14 +2326,11 @@ mod tests {
     #[tokio::test]
     #[ignore] // Run with: cargo test -- --ignored
     async fn integration_test_company_search_with_real_data() {
-        use crate::duckdb::{DuckDB, DuckDbConfig};
         use rmcp::handler::server::tool::Parameters;
 
-        let mut config = DuckDbConfig::default();
-        config.access_mode = duck::AccessMode::ReadOnly;
-        let _db = DuckDB::new(config).await.expect("Database connection");
-
-        let tool = Tool::new();
+        let config = crate::config::Config::default();
+        let snapshots = Snapshots::open(&config).await.expect("Database connection");
+        let tool = Tool::new(Arc::new(snapshots), config.query);
 
         // Test search by common Swedish company suffix
         let search_request = Parameters(SearchRequest {
@@ -758,6 +2340,7 @@ mod tests {
             company_purpose: None,
             revenue_range: None,
             employee_range: None,
+            ..Default::default()
         });
         let result = tool.company_search(search_request).await;
         assert!(result.is_ok(), "Company name search should work");
@@ -770,6 +2353,7 @@ mod tests {
             company_purpose: None,
             revenue_range: None,
             employee_range: None,
+            ..Default::default()
         });
         let result = tool.company_search(search_request).await;
         assert!(result.is_ok(), "Foundation year search should work");
@@ -782,6 +2366,7 @@ mod tests {
             company_purpose: None,
             revenue_range: None,
             employee_range: None,
+            ..Default::default()
         });
         let result = tool.company_search(search_request).await;
         assert!(result.is_ok(), "NACE category search should work");
@@ -794,6 +2379,7 @@ mod tests {
             company_purpose: None,
             revenue_range: Some((100000.0, 50000000.0)),
             employee_range: None,
+            ..Default::default()
         });
         let result = tool.company_search(search_request).await;
         assert!(result.is_ok(), "Revenue range search should work");
@@ -802,14 +2388,11 @@ mod tests {
     #[tokio::test]
     #[ignore] // Run with: cargo test -- --ignored
     async fn integration_test_financial_data_structure() {
-        use crate::duckdb::DuckDB;
         use rmcp::handler::server::tool::Parameters;
 
-        let mut config = crate::duckdb::DuckDbConfig::default();
-        config.access_mode = duck::AccessMode::ReadOnly;
-        let _db = DuckDB::new(config).await.expect("Database connection");
-
-        let tool = Tool::new();
+        let config = crate::config::Config::default();
+        let snapshots = Snapshots::open(&config).await.expect("Database connection");
+        let tool = Tool::new(Arc::new(snapshots), config.query);
 
         // Test accessing different years of financial data
         let years = vec!["2020", "2021", "2022", "2023", "2024"];
@@ -823,6 +2406,8 @@ mod tests {
                        LIMIT 1"#,
                     year, year, year
                 ),
+                format: OutputFormat::Json,
+                as_of: None,
             });
             let result = tool.company(query_request).await;
             assert!(
@@ -843,6 +2428,8 @@ mod tests {
                        AND financial_data."2024" IS NOT NULL
                      LIMIT 5"#
                 .to_string(),
+            format: OutputFormat::Json,
+            as_of: None,
         });
         let result = tool.company(query_request).await;
         assert!(
@@ -854,18 +2441,17 @@ mod tests {
     #[tokio::test]
     #[ignore] // Run with: cargo test -- --ignored
     async fn integration_test_complex_location_queries() {
-        use crate::duckdb::DuckDB;
         use rmcp::handler::server::tool::Parameters;
 
-        let mut config = crate::duckdb::DuckDbConfig::default();
-        config.access_mode = duck::AccessMode::ReadOnly;
-        let _db = DuckDB::new(config).await.expect("Database connection");
-
-        let tool = Tool::new();
+        let config = crate::config::Config::default();
+        let snapshots = Snapshots::open(&config).await.expect("Database connection");
+        let tool = Tool::new(Arc::new(snapshots), config.query);
 
         // Test location filtering by county
         let query_request = Parameters(QueryRequest {
             sql: "SELECT company_name, location.county FROM hello_nest WHERE location.county = 'Stockholm' LIMIT 3".to_string(),
+            format: OutputFormat::Json,
+            as_of: None,
         });
         let result = tool.company(query_request).await;
         assert!(result.is_ok(), "County filtering should work");
@@ -873,6 +2459,8 @@ mod tests {
         // Test coordinate access (companies with GPS coordinates)
         let query_request = Parameters(QueryRequest {
             sql: "SELECT company_name, location.coordinates.XCoordinate, location.coordinates.YCoordinate FROM hello_nest WHERE location.coordinates.XCoordinate IS NOT NULL LIMIT 3".to_string(),
+            format: OutputFormat::Json,
+            as_of: None,
         });
         let result = tool.company(query_request).await;
         assert!(result.is_ok(), "Coordinate access should work");
@@ -880,6 +2468,8 @@ mod tests {
         // Test municipality grouping
         let query_request = Parameters(QueryRequest {
             sql: "SELECT location.municipality, COUNT(*) as company_count FROM hello_nest WHERE location.municipality IS NOT NULL GROUP BY location.municipality ORDER BY company_count DESC LIMIT 5".to_string(),
+            format: OutputFormat::Json,
+            as_of: None,
         });
         let result = tool.company(query_request).await;
         assert!(result.is_ok(), "Municipality grouping should work");
@@ -890,11 +2480,15 @@ mod tests {
     async fn integration_test_error_handling() {
         use rmcp::handler::server::tool::Parameters;
 
-        let tool = Tool::new();
+        let config = crate::config::Config::default();
+        let snapshots = Snapshots::open(&config).await.expect("Database connection");
+        let tool = Tool::new(Arc::new(snapshots), config.query);
 
         // Test malformed SQL
         let query_request = Parameters(QueryRequest {
             sql: "SELECT * FROM nonexistent_table".to_string(),
+            format: OutputFormat::Json,
+            as_of: None,
         });
         let result = tool.company(query_request).await;
         assert!(result.is_err(), "Malformed SQL should fail");
@@ -907,6 +2501,7 @@ mod tests {
             company_purpose: None,
             revenue_range: None,
             employee_range: None,
+            ..Default::default()
         });
         let result = tool.company_search(search_request).await;
         assert!(result.is_err(), "SQL injection should be blocked");
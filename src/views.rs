@@ -0,0 +1,395 @@
+//! Derived views over `hello_nest`.
+//!
+//! `create-db` creates them next to `hello_nest` in both build modes:
+//! `hello_nest_parsed` with the NACE categories as arrays, `company_locations`
+//! with the location struct flattened into columns, and
+//! `company_latest_financials` with one column per metric of the latest
+//! financial year. Each view has a version, bumped whenever its definition
+//! changes, and a description that is also its `COMMENT`; both are listed in
+//! `derived_views`, so a file built by an older version can be told apart.
+//!
+//! Every view comes with checks, queries counting rows that break what the
+//! view promises, which `validate-views` runs through [`validate`].
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{duckdb::DuckDB, metrics};
+
+const DERIVED_VIEWS_TABLE_SQL: &str = r#"
+    CREATE OR REPLACE TABLE derived_views (
+        name VARCHAR,
+        version BIGINT,
+        description VARCHAR
+    );
+"#;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivedView {
+    pub name: &'static str,
+    /// Bumped whenever `sql` changes meaning
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: String,
+    pub checks: Vec<ViewCheck>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ViewCheck {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Query returning the number of offending rows; zero passes
+    pub violations_sql: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValidationReport {
+    pub passed: bool,
+    pub views: Vec<ViewReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ViewReport {
+    pub view: &'static str,
+    pub version: i64,
+    /// Version recorded in `derived_views`, `None` if the file has no such view
+    pub recorded_version: Option<i64>,
+    pub checks: Vec<CheckResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub check: &'static str,
+    pub description: &'static str,
+    pub violations: i64,
+}
+
+/// `column`, a JSON array of NACE categories such as
+/// `["43320 Byggnadssnickeriarbeten"]`, as `VARCHAR[]`. A value that is not
+/// a JSON array becomes a single category.
+pub fn nace_array_sql(column: &str) -> String {
+    format!(
+        "CASE WHEN json_valid({column}) AND json_type({column}) = 'ARRAY' \
+         THEN from_json({column}, '[\"VARCHAR\"]') \
+         WHEN {column} IS NOT NULL THEN [{column}] END"
+    )
+}
+
+/// The leading digits of every category in `categories` (a `VARCHAR[]`), e.g.
+/// `43320` for "43320 Byggnadssnickeriarbeten"; categories without a code
+/// are left out
+pub fn nace_codes_sql(categories: &str) -> String {
+    format!(
+        "list_filter(list_transform({categories}, c -> regexp_extract(c, '^[0-9]+')), c -> c <> '')"
+    )
+}
+
+/// Difference between the row counts of `view` and of the `hello_nest` rows
+/// matching `filter`
+fn row_count_mismatch_sql(view: &str, filter: &str) -> String {
+    format!(
+        "SELECT abs((SELECT count(*) FROM {view}) - (SELECT count(*) FROM hello_nest WHERE {filter}))"
+    )
+}
+
+pub fn hello_nest_parsed() -> DerivedView {
+    let categories = nace_array_sql("nace_categories");
+    DerivedView {
+        name: "hello_nest_parsed",
+        version: 1,
+        description: "hello_nest with nace_categories parsed into a VARCHAR[] and their \
+                      leading codes in nace_codes",
+        sql: format!(
+            "SELECT * REPLACE ({categories} AS nace_categories), \
+             {} AS nace_codes FROM hello_nest",
+            nace_codes_sql(&categories)
+        ),
+        checks: vec![
+            ViewCheck {
+                name: "row_count",
+                description: "One row per hello_nest company",
+                violations_sql: row_count_mismatch_sql("hello_nest_parsed", "true"),
+            },
+            ViewCheck {
+                name: "nace_parsed",
+                description: "Companies with NACE categories have at least one after parsing",
+                violations_sql: format!(
+                    "SELECT count(*) FROM hello_nest \
+                     WHERE nace_categories IS NOT NULL AND coalesce(len({categories}), 0) = 0"
+                ),
+            },
+            ViewCheck {
+                name: "nace_codes",
+                description: "NACE codes are two to five digits",
+                violations_sql: "SELECT count(*) FROM (SELECT unnest(nace_codes) AS code FROM hello_nest_parsed) \
+                                 WHERE NOT regexp_full_match(code, '[0-9]{2,5}')"
+                    .to_string(),
+            },
+        ],
+    }
+}
+
+pub fn company_locations() -> DerivedView {
+    DerivedView {
+        name: "company_locations",
+        version: 1,
+        description: "The location struct of every hello_nest company flattened into columns, \
+                      with the reported names next to the gazetteer ones",
+        sql: "SELECT company_id, company_name, \
+              location['countryPart'] AS country_part, \
+              location['county'] AS reported_county, \
+              location['municipality'] AS reported_municipality, \
+              location['coordinates']['XCoordinate'] AS x_coordinate, \
+              location['coordinates']['YCoordinate'] AS y_coordinate, \
+              location['coordinates']['coordinateSystem'] AS coordinate_system, \
+              lat, lon, county_code, county, municipality_code, municipality \
+              FROM hello_nest"
+            .to_string(),
+        checks: vec![
+            ViewCheck {
+                name: "row_count",
+                description: "One row per hello_nest company",
+                violations_sql: row_count_mismatch_sql("company_locations", "true"),
+            },
+            ViewCheck {
+                name: "wgs84_range",
+                description: "Positions are both set or both NULL and within WGS84 bounds",
+                violations_sql: "SELECT count(*) FROM company_locations \
+                                 WHERE (lat IS NULL) <> (lon IS NULL) \
+                                 OR abs(lat) > 90 OR abs(lon) > 180"
+                    .to_string(),
+            },
+            ViewCheck {
+                name: "gazetteer_county",
+                description: "Companies with a municipality code are in the county it belongs to",
+                violations_sql: "SELECT count(*) FROM company_locations \
+                                 WHERE municipality_code IS NOT NULL \
+                                 AND county_code IS DISTINCT FROM left(municipality_code, 2)"
+                    .to_string(),
+            },
+        ],
+    }
+}
+
+pub fn company_latest_financials() -> DerivedView {
+    let columns: Vec<String> = metrics::all_metrics()
+        .map(|metric| format!("{} AS \"{}\"", metrics::latest_metric_sql(metric), metric))
+        .collect();
+    let years: Vec<String> = metrics::FINANCIAL_YEARS
+        .iter()
+        .map(i64::to_string)
+        .collect();
+    DerivedView {
+        name: "company_latest_financials",
+        version: 1,
+        description: "One row per company with financials: the latest financial year and every \
+                      reported and derived metric of it as a DOUBLE column",
+        sql: format!(
+            "SELECT company_id, company_name, currency, latest_financial_year AS year, {} \
+             FROM hello_nest WHERE latest_financial_year IS NOT NULL",
+            columns.join(", ")
+        ),
+        checks: vec![
+            ViewCheck {
+                name: "row_count",
+                description: "One row per hello_nest company with a latest financial year",
+                violations_sql: row_count_mismatch_sql(
+                    "company_latest_financials",
+                    "latest_financial_year IS NOT NULL",
+                ),
+            },
+            ViewCheck {
+                name: "known_year",
+                description: "The year is one of the financial_data years",
+                violations_sql: format!(
+                    "SELECT count(*) FROM company_latest_financials WHERE year NOT IN ({})",
+                    years.join(", ")
+                ),
+            },
+        ],
+    }
+}
+
+pub fn views() -> Vec<DerivedView> {
+    vec![
+        hello_nest_parsed(),
+        company_locations(),
+        company_latest_financials(),
+    ]
+}
+
+/// Create (or replace) every derived view with its comment and record them
+/// in `derived_views`
+pub fn create(db: &DuckDB) -> Result<()> {
+    db.execute(DERIVED_VIEWS_TABLE_SQL)?;
+    for view in views() {
+        db.execute(&format!(
+            "CREATE OR REPLACE VIEW {} AS {}",
+            view.name, view.sql
+        ))
+        .with_context(|| format!("Failed to create {} view", view.name))?;
+        let description = view.description.replace('\'', "''");
+        db.execute(&format!(
+            "COMMENT ON VIEW {} IS '{}'",
+            view.name, description
+        ))?;
+        db.execute(&format!(
+            "INSERT INTO derived_views VALUES ('{}', {}, '{}')",
+            view.name, view.version, description
+        ))?;
+    }
+    Ok(())
+}
+
+fn recorded_version(db: &DuckDB, view: &str) -> Result<Option<i64>> {
+    let recorded = db.query_one(
+        "SELECT count(*) FROM information_schema.tables \
+         WHERE table_catalog = current_database() AND table_name = 'derived_views'",
+        |row| Ok(row.get::<_, i64>(0)?),
+    )?;
+    if recorded.unwrap_or(0) == 0 {
+        return Ok(None);
+    }
+    db.query_one(
+        &format!(
+            "SELECT max(version) FROM derived_views WHERE name = '{}'",
+            view
+        ),
+        |row| Ok(row.get::<_, Option<i64>>(0)?),
+    )
+    .map(Option::flatten)
+}
+
+/// Run every view's checks. Views missing from the file or recorded with
+/// another version fail without running their checks.
+pub fn validate(db: &DuckDB) -> Result<ValidationReport> {
+    let mut passed = true;
+    let mut reports = Vec::new();
+    for view in views() {
+        let recorded_version = recorded_version(db, view.name)?;
+        let mut checks = Vec::new();
+        if recorded_version == Some(view.version) {
+            for check in view.checks {
+                let violations = db
+                    .query_one(&check.violations_sql, |row| Ok(row.get::<_, i64>(0)?))
+                    .with_context(|| format!("Failed to check {}.{}", view.name, check.name))?
+                    .unwrap_or_default();
+                passed &= violations == 0;
+                checks.push(CheckResult {
+                    check: check.name,
+                    description: check.description,
+                    violations,
+                });
+            }
+        } else {
+            passed = false;
+        }
+        reports.push(ViewReport {
+            view: view.name,
+            version: view.version,
+            recorded_version,
+            checks,
+        });
+    }
+    Ok(ValidationReport {
+        passed,
+        views: reports,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duckdb::test_support::*;
+
+    #[test]
+    fn test_view_names_are_unique() {
+        let mut names: Vec<&str> = views().iter().map(|view| view.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), views().len());
+        assert!(views().iter().all(|view| !view.checks.is_empty()));
+    }
+
+    #[test]
+    fn test_latest_financials_has_every_metric() {
+        let sql = company_latest_financials().sql;
+        assert_eq!(
+            sql.matches("latest_financials['").count(),
+            metrics::all_metrics().count()
+        );
+        assert!(sql.contains("latest_financials['Sales revenues'] AS \"Sales revenues\""));
+        assert!(sql.contains("latest_financials['EBITDA'] AS \"EBITDA\""));
+    }
+
+    #[tokio::test]
+    async fn test_derived_views() -> Result<()> {
+        let db = create_test_db("derived_views")?;
+        let revenue = metrics::REVENUE_METRIC;
+
+        db.execute(&format!(
+            r#"CREATE TABLE hello_nest AS
+               SELECT *, 'SEK' AS currency,
+                   CASE company_id
+                       WHEN 1 THEN '["62010 Dataprogrammering", "62020 Datakonsultverksamhet"]'
+                       WHEN 2 THEN '43320 Byggnadssnickeriarbeten'
+                       WHEN 3 THEN '70100 Verksamheter som utövas av huvudkontor'
+                   END AS nace_categories,
+                   {{'county': 'Stockholms Län', 'countryPart': 'Östra Sverige', 'municipality': 'Solna',
+                     'coordinates': {{'XCoordinate': 18.0, 'YCoordinate': 59.36, 'coordinateSystem': 'EPSG:4326'}}}} AS location,
+                   59.36 AS lat, 18.0 AS lon, '01' AS county_code, 'Stockholm' AS county,
+                   '0184' AS municipality_code, 'Solna' AS municipality
+               FROM ({})"#,
+            companies_sql(&[
+                (1, "Alpha AB", &[(2024, revenue, 100.0)]),
+                (2, "Beta AB", &[]),
+                (3, "Gamma AB", &[(2023, revenue, 50.0), (2024, EMPTY_YEAR, 0.0)]),
+            ])
+        ))?;
+        create(&db)?;
+
+        let nace_codes = db.query_all(
+            "SELECT array_to_string(nace_codes, ',') FROM hello_nest_parsed ORDER BY company_id",
+            |row| Ok(row.get::<_, String>(0)?),
+        )?;
+        assert_eq!(nace_codes, vec!["62010,62020", "43320", "70100"]);
+        let place = db.query_one(
+            "SELECT country_part || '/' || reported_county || '/' || coordinate_system FROM company_locations WHERE company_id = 1",
+            |row| Ok(row.get::<_, String>(0)?),
+        )?;
+        assert_eq!(
+            place.as_deref(),
+            Some("Östra Sverige/Stockholms Län/EPSG:4326")
+        );
+        let latest = db.query_all(
+            r#"SELECT company_id, year, "Sales revenues" FROM company_latest_financials ORDER BY company_id"#,
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, f64>(2)?,
+                ))
+            },
+        )?;
+        // Gamma's 2024 struct exists, but has no accounts
+        assert_eq!(latest, vec![(1, 2024, 100.0), (3, 2023, 50.0)]);
+        let comment = db.query_one(
+            "SELECT comment FROM duckdb_views() WHERE view_name = 'company_locations'",
+            |row| Ok(row.get::<_, String>(0)?),
+        )?;
+        assert_eq!(comment.as_deref(), Some(company_locations().description));
+
+        let report = validate(&db)?;
+        assert!(report.passed, "{:?}", report);
+
+        // A file built with an older definition fails without running checks
+        db.execute("UPDATE derived_views SET version = 0 WHERE name = 'hello_nest_parsed'")?;
+        let report = validate(&db)?;
+        assert!(!report.passed);
+        assert_eq!(report.views[0].recorded_version, Some(0));
+        assert!(report.views[0].checks.is_empty());
+
+        cleanup_test_db("derived_views");
+        Ok(())
+    }
+}